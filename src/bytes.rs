@@ -1,5 +1,5 @@
 use std::ffi::c_void;
-use std::ptr;
+#[cfg(nightly)] use std::ptr;
 
 #[cfg(nightly)] 
 #[inline(never)]
@@ -35,3 +35,32 @@ pub fn prune(buffer: &mut [u8])
 	std::ptr::write_bytes(buffer.as_mut_ptr(), 0, buffer.len());
     }
 }
+
+/// A buffer that is `prune()`d when dropped.
+#[derive(Debug, Clone, Default)]
+pub struct PruneOnDrop<T: AsMut<[u8]>>(pub T);
+
+impl<T: AsMut<[u8]>> std::ops::Deref for PruneOnDrop<T>
+{
+    type Target = T;
+    #[inline] fn deref(&self) -> &Self::Target
+    {
+	&self.0
+    }
+}
+
+impl<T: AsMut<[u8]>> std::ops::DerefMut for PruneOnDrop<T>
+{
+    #[inline] fn deref_mut(&mut self) -> &mut Self::Target
+    {
+	&mut self.0
+    }
+}
+
+impl<T: AsMut<[u8]>> Drop for PruneOnDrop<T>
+{
+    fn drop(&mut self)
+    {
+	prune(self.0.as_mut());
+    }
+}
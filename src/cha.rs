@@ -3,14 +3,30 @@ use openssl::{
     symm::{
 	Cipher, Crypter, Mode,
     },
+    cipher::{self, CipherRef},
+    cipher_ctx::CipherCtx,
     error::ErrorStack,
 };
-use crate::key::{Key, IV};
+use crate::key::{Key, IV, Tag};
+use crate::error::AuthError;
+use std::fmt;
+
+pub mod par;
+pub use par::{par_transform, encrypt_file_parallel, decrypt_file_parallel};
+#[cfg(all(unix, feature="ffi"))] pub mod mmap;
 
 /// Size of the key used for the cipher
 pub const KEY_SIZE: usize = 32;
 /// Size of the IV used for the cipher
 pub const IV_SIZE: usize = 12;
+/// Size of the Poly1305 authentication tag produced by the cipher
+pub const TAG_SIZE: usize = 16;
+/// Size of a ChaCha20 keystream block
+pub const BLOCK_SIZE: usize = 64;
+/// Offsets into a ciphertext passed to `crypter_at()` must be less than this.
+///
+/// The ChaCha20 block counter is 32 bits, and block 0 is used for the Poly1305 key.
+pub const MAX_OFFSET: u64 = u32::MAX as u64 * BLOCK_SIZE as u64;
 
 static NEW_CIPHER: fn() -> Cipher = Cipher::chacha20_poly1305;
 
@@ -33,8 +49,370 @@ static NEW_CIPHER: fn() -> Cipher = Cipher::chacha20_poly1305;
     )
 }
 
+/// Create a decrypter with additional authenticated data `aad` already fed into it.
+#[inline] pub fn decrypter_with_aad(key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Crypter, ErrorStack>
+{
+    let mut crypter = decrypter(key, iv)?;
+    crypter.aad_update(aad.as_ref())?;
+    Ok(crypter)
+}
+/// Create an encrypter with additional authenticated data `aad` already fed into it.
+#[inline] pub fn encrypter_with_aad(key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Crypter, ErrorStack>
+{
+    let mut crypter = encrypter(key, iv)?;
+    crypter.aad_update(aad.as_ref())?;
+    Ok(crypter)
+}
+
+/// The chacha20 counter and nonce for the block containing byte `offset` of a chacha20_poly1305 ciphertext.
+///
+/// # Panics
+/// If `offset` is not less than `MAX_OFFSET`.
+fn counter_iv(iv: &IV, offset: u64) -> [u8; 4 + IV_SIZE]
+{
+    assert!(offset < MAX_OFFSET, "offset out of range for chacha20 block counter");
+
+    // chacha20_poly1305 starts encrypting at block 1.
+    let block = (offset / BLOCK_SIZE as u64) as u32 + 1;
+    let mut output = [0u8; 4 + IV_SIZE];
+    output[..4].copy_from_slice(&block.to_le_bytes()[..]);
+    output[4..].copy_from_slice(iv.as_ref());
+    output
+}
+
+/// Create a crypter that applies the chacha20_poly1305 keystream from byte `offset` of the ciphertext onwards.
+///
+/// ChaCha20 en/decryption are the same operation, so this can be used for either.
+/// It is a plain ChaCha20 crypter: it does not compute or verify the Poly1305 tag.
+///
+/// # Panics
+/// If `offset` is not less than `MAX_OFFSET`.
+pub fn crypter_at(key: impl AsRef<Key>, iv: impl AsRef<IV>, offset: u64) -> Result<Crypter, ErrorStack>
+{
+    let mut crypter = Crypter::new(
+	Cipher::chacha20(),
+	Mode::Encrypt,
+	key.as_ref().as_ref(),
+	Some(&counter_iv(iv.as_ref(), offset)[..])
+    )?;
+
+    // Discard the keystream before `offset` in its block.
+    let skip = (offset % BLOCK_SIZE as u64) as usize;
+    let mut scratch = [0u8; BLOCK_SIZE];
+    crypter.update(&[0u8; BLOCK_SIZE][..skip], &mut scratch[..])?;
+    crate::bytes::prune(&mut scratch[..]);
+    Ok(crypter)
+}
+
+/// Open `dst` to write the transform of `input` into, creating it if it does not exist and truncating it if it is a regular file.
+///
+/// It is only opened for reading as well if `read` is set, so write-only destinations can be used when it is not.
+///
+/// # Errors
+/// If `dst` is the same file as `input`, an `InvalidInput` error is returned before anything is truncated.
+#[cfg(unix)]
+fn open_output(input: &std::fs::File, dst: &std::path::Path, read: bool) -> std::io::Result<std::fs::File>
+{
+    use std::os::unix::fs::MetadataExt;
+    let output = std::fs::OpenOptions::new().read(read).write(true).create(true).truncate(false).open(dst)?;
+    let (from, to) = (input.metadata()?, output.metadata()?);
+    if from.dev() == to.dev() && from.ino() == to.ino() {
+	return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "input and output are the same file"));
+    }
+    if to.file_type().is_file() {
+	output.set_len(0)?;
+    }
+    Ok(output)
+}
+
+/// A chacha20_poly1305 transform that works in place
+///
+/// Unlike a `Crypter`, which always writes its output to a separate buffer, a `Transformer` overwrites its input with the output, so no temporary buffer is needed.
+/// It keeps the cipher's state across calls, so data can be passed to `apply_in_place()` in pieces of any size.
+/// ```
+/// # use chacha20stream::cha::Transformer;
+/// # let (key, iv) = chacha20stream::keygen();
+/// let mut packet = *b"Hello world!";
+///
+/// let mut enc = Transformer::encrypter(&key, &iv).unwrap();
+/// enc.apply_in_place(&mut packet[..5]).unwrap();
+/// enc.apply_in_place(&mut packet[5..]).unwrap();
+/// let tag = enc.finish().unwrap();
+///
+/// let mut dec = Transformer::decrypter(&key, &iv).unwrap();
+/// dec.apply_in_place(&mut packet[..]).unwrap();
+/// dec.finish_verify(&tag).expect("Packet was tampered with");
+/// assert_eq!(&packet, b"Hello world!");
+/// ```
+pub struct Transformer
+{
+    ctx: CipherCtx,
+    finished: bool,
+}
+
+impl fmt::Debug for Transformer
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "Transformer")
+    }
+}
+
+impl Transformer
+{
+    fn new(cipher: &CipherRef, mode: Mode, key: &[u8], iv: &[u8]) -> Result<Self, ErrorStack>
+    {
+	let mut ctx = CipherCtx::new()?;
+	match mode {
+	    Mode::Encrypt => ctx.encrypt_init(Some(cipher), Some(key), Some(iv))?,
+	    Mode::Decrypt => ctx.decrypt_init(Some(cipher), Some(key), Some(iv))?,
+	}
+	Ok(Self { ctx, finished: false })
+    }
+
+    /// Create an encrypting transformer. The counterpart to `encrypter()`.
+    #[inline] pub fn encrypter(key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, ErrorStack>
+    {
+	Self::new(cipher::Cipher::chacha20_poly1305(), Mode::Encrypt, key.as_ref().as_ref(), iv.as_ref().as_ref())
+    }
+
+    /// Create a decrypting transformer. The counterpart to `decrypter()`.
+    #[inline] pub fn decrypter(key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, ErrorStack>
+    {
+	Self::new(cipher::Cipher::chacha20_poly1305(), Mode::Decrypt, key.as_ref().as_ref(), iv.as_ref().as_ref())
+    }
+
+    /// Create an encrypting transformer with additional authenticated data `aad` already fed into it.
+    #[inline] pub fn encrypter_with_aad(key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, ErrorStack>
+    {
+	let mut this = Self::encrypter(key, iv)?;
+	this.aad_update(aad)?;
+	Ok(this)
+    }
+
+    /// Create a decrypting transformer with additional authenticated data `aad` already fed into it.
+    #[inline] pub fn decrypter_with_aad(key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, ErrorStack>
+    {
+	let mut this = Self::decrypter(key, iv)?;
+	this.aad_update(aad)?;
+	Ok(this)
+    }
+
+    /// Create a transformer that applies the chacha20_poly1305 keystream from byte `offset` of the ciphertext onwards. The counterpart to `crypter_at()`.
+    ///
+    /// Like `crypter_at()`, it does not compute or verify the Poly1305 tag.
+    ///
+    /// # Panics
+    /// If `offset` is not less than `MAX_OFFSET`.
+    pub fn at(key: impl AsRef<Key>, iv: impl AsRef<IV>, offset: u64) -> Result<Self, ErrorStack>
+    {
+	let mut this = Self::new(cipher::Cipher::chacha20(), Mode::Encrypt, key.as_ref().as_ref(), &counter_iv(iv.as_ref(), offset)[..])?;
+
+	// Discard the keystream before `offset` in its block.
+	let skip = (offset % BLOCK_SIZE as u64) as usize;
+	let mut scratch = [0u8; BLOCK_SIZE];
+	this.apply(&mut scratch[..skip])?;
+	crate::bytes::prune(&mut scratch[..]);
+	Ok(this)
+    }
+
+    /// Feed additional authenticated data into the Poly1305 tag.
+    ///
+    /// This must be done before any data is transformed.
+    #[inline] pub fn aad_update(&mut self, aad: impl AsRef<[u8]>) -> Result<(), ErrorStack>
+    {
+	self.ctx.cipher_update(aad.as_ref(), None)?;
+	Ok(())
+    }
+
+    /// En/decrypt `buf` in place, continuing from where the last call left off.
+    ///
+    /// # Errors
+    /// If the transformer has been finished, `AuthError::Finished` is returned.
+    pub fn apply_in_place(&mut self, buf: &mut [u8]) -> Result<(), AuthError>
+    {
+	if self.finished {
+	    return Err(AuthError::Finished);
+	}
+	Ok(self.apply(buf)?)
+    }
+
+    /// En/decrypt `buf` in place, without checking if the transformer has been finished.
+    fn apply(&mut self, buf: &mut [u8]) -> Result<(), ErrorStack>
+    {
+	if buf.is_empty() {
+	    return Ok(());
+	}
+	let len = buf.len();
+	let n = self.ctx.cipher_update_inplace(buf, len)?;
+	debug_assert_eq!(n, len, "stream cipher output differs in size from its input");
+	Ok(())
+    }
+
+    /// Finalise an encrypting transformer, returning the Poly1305 authentication tag of all data transformed by it.
+    ///
+    /// # Errors
+    /// Finalising resets the cipher's state, so after the transformer is finished, this, `finish_verify()`, and `apply_in_place()` return `AuthError::Finished`.
+    pub fn finish(&mut self) -> Result<Tag, AuthError>
+    {
+	if std::mem::replace(&mut self.finished, true) {
+	    return Err(AuthError::Finished);
+	}
+	let mut tag = Tag::default();
+	self.ctx.cipher_final(&mut [])?;
+	self.ctx.tag(tag.as_mut())?;
+	Ok(tag)
+    }
+
+    /// Finalise a decrypting transformer, verifying the data transformed by it against the Poly1305 authentication tag `tag`.
+    ///
+    /// # Errors
+    /// If the data was not authenticated by `tag`, `AuthError::Mismatch` is returned.
+    /// If the transformer has already been finished, `AuthError::Finished` is returned.
+    pub fn finish_verify(&mut self, tag: &Tag) -> Result<(), AuthError>
+    {
+	if std::mem::replace(&mut self.finished, true) {
+	    return Err(AuthError::Finished);
+	}
+	self.ctx.set_tag(tag.as_ref())?;
+	self.ctx.cipher_final(&mut []).map_err(|_| AuthError::Mismatch)?;
+	Ok(())
+    }
+}
+
 /// Generate a random key and IV for the chacha20_poly1305 cipher
 #[inline(always)] pub fn keygen() -> (Key, IV)
 {
     (Key::new(), IV::new())
 }
+
+/// Checks shared by the file transforms in `par` and `mmap`.
+#[cfg(test)]
+mod file_tests
+{
+    use super::*;
+    use std::{
+	io::{self, Write},
+	path::Path,
+    };
+
+    /// Encrypt `input` with a `Sink`, for comparison.
+    pub fn sequential(key: &Key, iv: &IV, input: &[u8]) -> Vec<u8>
+    {
+	let mut sink = crate::Sink::encrypt(Vec::new(), key, iv).expect("sink::enc");
+	sink.write_all(input).unwrap();
+	sink.into_inner()
+    }
+
+    /// Check `encrypt` and `decrypt` transform files of each of `lens` bytes the same as a `Sink`, truncating any existing output.
+    pub fn round_trip<E, D>(lens: &[usize], encrypt: E, decrypt: D)
+    where E: Fn(&Path, &Path, &Key, &IV) -> io::Result<()>,
+	  D: Fn(&Path, &Path, &Key, &IV) -> io::Result<()>
+    {
+	let (key, iv) = keygen();
+	let dir = tempfile::tempdir().unwrap();
+	let (plain, enc, dec) = (dir.path().join("plain"), dir.path().join("enc"), dir.path().join("dec"));
+
+	for &len in lens {
+	    let input: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+	    std::fs::write(&plain, &input[..]).unwrap();
+	    std::fs::write(&enc, vec![0xff; len + 100]).unwrap();
+
+	    encrypt(&plain, &enc, &key, &iv).expect("encrypt");
+	    assert_eq!(std::fs::read(&enc).unwrap(), sequential(&key, &iv, &input[..]), "len {}", len);
+
+	    decrypt(&enc, &dec, &key, &iv).expect("decrypt");
+	    assert_eq!(std::fs::read(&dec).unwrap(), input, "len {}", len);
+	}
+    }
+
+    /// Check `encrypt` refuses to write a file into itself, by the same path or through a hard link, and leaves it untouched.
+    pub fn same_file<E>(encrypt: E)
+    where E: Fn(&Path, &Path, &Key, &IV) -> io::Result<()>
+    {
+	let (key, iv) = keygen();
+	let dir = tempfile::tempdir().unwrap();
+	let (plain, link) = (dir.path().join("plain"), dir.path().join("link"));
+	std::fs::write(&plain, b"Hello world!").unwrap();
+	std::fs::hard_link(&plain, &link).unwrap();
+
+	for dst in &[&plain, &link] {
+	    let err = encrypt(&plain, dst, &key, &iv).expect_err("transformed a file into itself");
+	    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+	}
+	assert_eq!(std::fs::read(&plain).unwrap(), b"Hello world!");
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn transformer()
+    {
+	let (key, iv) = keygen();
+	let input: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+
+	let mut expected = vec![0u8; input.len()];
+	let mut crypter = encrypter(&key, &iv).unwrap();
+	crypter.update(&input[..], &mut expected[..]).unwrap();
+	let mut tag = Tag::default();
+	crypter.finalize(&mut []).unwrap();
+	crypter.get_tag(tag.as_mut()).unwrap();
+
+	// Pieces of any size produce the same output as a `Crypter`
+	let mut output = input.clone();
+	let mut enc = Transformer::encrypter(&key, &iv).unwrap();
+	for chunk in output.chunks_mut(37) {
+	    enc.apply_in_place(chunk).unwrap();
+	}
+	assert_eq!(output, expected);
+	assert_eq!(enc.finish().unwrap(), tag);
+
+	// Finishing resets the keystream, so a finished transformer can't be used again
+	assert!(matches!(enc.apply_in_place(&mut output[..]), Err(AuthError::Finished)));
+	assert!(matches!(enc.finish(), Err(AuthError::Finished)));
+	assert_eq!(output, expected);
+
+	let mut dec = Transformer::decrypter(&key, &iv).unwrap();
+	dec.apply_in_place(&mut output[..]).unwrap();
+	dec.finish_verify(&tag).expect("verify");
+	assert_eq!(output, input);
+
+	let mut dec = Transformer::decrypter(&key, &iv).unwrap();
+	dec.apply_in_place(&mut expected[..]).unwrap();
+	assert!(dec.finish_verify(&Tag::default()).unwrap_err().is_mismatch());
+	assert!(matches!(dec.finish_verify(&tag), Err(AuthError::Finished)));
+
+	for &offset in &[0usize, 1, 63, 64, 65, 999] {
+	    let mut output = input[offset..].to_vec();
+	    Transformer::at(&key, &iv, offset as u64).unwrap().apply_in_place(&mut output[..]).unwrap();
+	    let mut expected = input.clone();
+	    Transformer::encrypter(&key, &iv).unwrap().apply_in_place(&mut expected[..]).unwrap();
+	    assert_eq!(&output[..], &expected[offset..]);
+	}
+    }
+
+    #[test]
+    fn transformer_aad()
+    {
+	let (key, iv) = keygen();
+	let mut data = *b"Hello world!";
+
+	let mut enc = Transformer::encrypter_with_aad(&key, &iv, b"header").unwrap();
+	enc.apply_in_place(&mut data[..]).unwrap();
+	let tag = enc.finish().unwrap();
+
+	let mut dec = Transformer::decrypter_with_aad(&key, &iv, b"header").unwrap();
+	let mut copy = data;
+	dec.apply_in_place(&mut copy[..]).unwrap();
+	dec.finish_verify(&tag).expect("verify");
+
+	let mut dec = Transformer::decrypter_with_aad(&key, &iv, b"footer").unwrap();
+	dec.apply_in_place(&mut data[..]).unwrap();
+	assert!(dec.finish_verify(&tag).unwrap_err().is_mismatch());
+    }
+}
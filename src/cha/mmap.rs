@@ -0,0 +1,210 @@
+//! Memory-mapped file transforms
+//!
+//! Regular files are mapped into memory with `mmap()` and transformed straight from the input mapping into the output mapping, without copying through a buffer.
+//! Anything else (pipes, character devices, etc.) cannot be mapped, and is streamed through a `Sink` instead.
+//!
+//! The output is the same as copying the input through a `Sink`, without the Poly1305 tag.
+//!
+//! # Note
+//! If the input file is truncated by another process while it is mapped, accessing the missing pages raises `SIGBUS`.
+use super::*;
+use crate::Sink;
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+    ptr,
+};
+
+/// How much of a mapped file is transformed in each call to the cipher
+pub const STRIDE: usize = 16 * 1024 * 1024;
+
+/// A mapping of a whole file, which is unmapped when dropped.
+struct Mapping
+{
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping
+{
+    /// Map the first `len` bytes of `file` with protection `prot`.
+    ///
+    /// # Panics
+    /// If `len` is 0.
+    fn new(file: &File, len: usize, prot: libc::c_int) -> io::Result<Self>
+    {
+	assert!(len > 0, "cannot map an empty file");
+	let ptr = unsafe { libc::mmap(ptr::null_mut(), len, prot, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+	if ptr == libc::MAP_FAILED {
+	    return Err(io::Error::last_os_error());
+	}
+	Ok(Self { ptr, len })
+    }
+
+    /// Hint that the mapping will be read from start to end.
+    ///
+    /// This is only advice, so failure is ignored.
+    #[inline] fn sequential(&self)
+    {
+	unsafe { libc::madvise(self.ptr, self.len, libc::MADV_SEQUENTIAL) };
+    }
+
+    #[inline] fn as_slice(&self) -> &[u8]
+    {
+	unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    #[inline] fn as_mut_slice(&mut self) -> &mut [u8]
+    {
+	unsafe { std::slice::from_raw_parts_mut(self.ptr as *mut u8, self.len) }
+    }
+
+    /// Write the mapping back to its file, waiting until it is done.
+    fn sync(&self) -> io::Result<()>
+    {
+	match unsafe { libc::msync(self.ptr, self.len, libc::MS_SYNC) } {
+	    0 => Ok(()),
+	    _ => Err(io::Error::last_os_error()),
+	}
+    }
+}
+
+impl Drop for Mapping
+{
+    fn drop(&mut self)
+    {
+	unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Stream `input` through a `Sink` into `output`, for files that cannot be mapped.
+fn transform_stream(mut input: File, output: File, sink: impl FnOnce(File) -> Result<Sink<File>, ErrorStack>) -> io::Result<()>
+{
+    let mut sink = sink(output)?;
+    io::copy(&mut input, &mut sink)?;
+    sink.flush()?;
+    let output = sink.into_inner();
+    match output.sync_all() {
+	// Pipes and devices cannot be synced.
+	Err(e) if e.kind() == io::ErrorKind::InvalidInput => Ok(()),
+	other => other,
+    }
+}
+
+/// En/decrypt the file at `src` into a new file at `dst`.
+fn transform_file(src: &Path, dst: &Path, key: &Key, iv: &IV, sink: impl FnOnce(File) -> Result<Sink<File>, ErrorStack>) -> io::Result<()>
+{
+    let input = File::open(src)?;
+    // Mapping the output needs read access too.
+    let output = open_output(&input, dst, true)?;
+    if !input.metadata()?.file_type().is_file() || !output.metadata()?.file_type().is_file() {
+	return transform_stream(input, output, sink);
+    }
+
+    let len = input.metadata()?.len();
+    if len > MAX_OFFSET {
+	return Err(io::Error::new(io::ErrorKind::InvalidInput, "file too large for chacha20 block counter"));
+    }
+    output.set_len(len)?;
+    if len == 0 {
+	// Empty files cannot be mapped, and there is nothing to transform.
+	return output.sync_all();
+    }
+    let len = usize::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to map"))?;
+
+    let from = Mapping::new(&input, len, libc::PROT_READ)?;
+    let mut to = Mapping::new(&output, len, libc::PROT_READ | libc::PROT_WRITE)?;
+    from.sequential();
+
+    let mut crypter = crypter_at(key, iv, 0)?;
+    for (input, output) in from.as_slice().chunks(STRIDE).zip(to.as_mut_slice().chunks_mut(STRIDE)) {
+	crypter.update(input, output)?;
+    }
+    to.sync()
+}
+
+/// Encrypt the file at `src` into a new file at `dst`, through memory mappings.
+///
+/// `dst` is created, or truncated if it exists. Its contents are the same as copying `src` through a `Sink` created with `Sink::encrypt()`. The Poly1305 tag is not computed.
+/// If either file is not a regular file, it is streamed through a `Sink` instead.
+/// ```
+/// # use chacha20stream::{Sink, cha};
+/// # use std::io::Write;
+/// # let (key, iv) = chacha20stream::keygen();
+/// # let dir = tempfile::tempdir().unwrap();
+/// # let (src, dst) = (dir.path().join("plain"), dir.path().join("enc"));
+/// std::fs::write(&src, b"Hello world!").unwrap();
+/// cha::mmap::encrypt_file(&src, &dst, &key, &iv).unwrap();
+///
+/// let mut sink = Sink::encrypt(Vec::new(), &key, &iv).unwrap();
+/// sink.write_all(b"Hello world!").unwrap();
+/// assert_eq!(std::fs::read(&dst).unwrap(), sink.into_inner());
+/// ```
+///
+/// # Errors
+/// If either file cannot be opened or mapped, reading or writing fails, `src` is longer than `MAX_OFFSET`, or `src` and `dst` are the same file (which is left untouched.)
+pub fn encrypt_file(src: impl AsRef<Path>, dst: impl AsRef<Path>, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> io::Result<()>
+{
+    let (key, iv) = (key.as_ref(), iv.as_ref());
+    transform_file(src.as_ref(), dst.as_ref(), key, iv, |output| Sink::encrypt(output, key, iv))
+}
+
+/// Decrypt the file at `src`, encrypted with `encrypt_file()` or a `Sink` (without an appended tag), into a new file at `dst`, through memory mappings.
+///
+/// If either file is not a regular file, it is streamed through a `Sink` instead.
+/// The data is not authenticated.
+///
+/// # Errors
+/// If either file cannot be opened or mapped, reading or writing fails, `src` is longer than `MAX_OFFSET`, or `src` and `dst` are the same file (which is left untouched.)
+pub fn decrypt_file(src: impl AsRef<Path>, dst: impl AsRef<Path>, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> io::Result<()>
+{
+    let (key, iv) = (key.as_ref(), iv.as_ref());
+    transform_file(src.as_ref(), dst.as_ref(), key, iv, |output| Sink::decrypt(output, key, iv))
+}
+
+#[cfg(test)]
+#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+mod tests
+{
+    use super::*;
+    use super::super::file_tests::{self, sequential};
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn files()
+    {
+	file_tests::round_trip(&[0, 1, 4097, STRIDE + 12345], |src, dst, key, iv| encrypt_file(src, dst, key, iv), |src, dst, key, iv| decrypt_file(src, dst, key, iv));
+    }
+
+    #[test]
+    fn fallback()
+    {
+	let (key, iv) = keygen();
+	let dir = tempfile::tempdir().unwrap();
+	let (fifo, enc) = (dir.path().join("fifo"), dir.path().join("enc"));
+	let path = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+	assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0, "mkfifo");
+
+	let input: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+	let writer = {
+	    let (fifo, input) = (fifo.clone(), input.clone());
+	    std::thread::spawn(move || std::fs::write(fifo, input).unwrap())
+	};
+	encrypt_file(&fifo, &enc, &key, &iv).expect("encrypt");
+	writer.join().unwrap();
+	assert_eq!(std::fs::read(&enc).unwrap(), sequential(&key, &iv, &input[..]));
+
+	// Output to a device
+	decrypt_file(&enc, "/dev/null", &key, &iv).expect("decrypt");
+    }
+
+    #[test]
+    fn same_file()
+    {
+	file_tests::same_file(|src, dst, key, iv| encrypt_file(src, dst, key, iv));
+    }
+}
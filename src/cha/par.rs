@@ -0,0 +1,246 @@
+//! Multi-threaded transforms
+//!
+//! The ChaCha20 keystream for any block can be computed from the block counter alone, so separate regions of the input can be transformed on separate threads.
+//! The output is the same as a `Sink` or `Source` would produce, without the Poly1305 tag, which can only be computed sequentially.
+use super::*;
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    thread,
+};
+
+/// The smallest region given to each thread
+///
+/// Inputs shorter than this are transformed on the calling thread.
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How much of a file each thread transforms at a time in `encrypt_file_parallel()`
+pub const FILE_STRIDE: usize = 1024 * 1024;
+
+/// The number of threads to use for `threads`, where 0 means one per available CPU.
+fn thread_count(threads: usize) -> usize
+{
+    match threads {
+	0 => thread::available_parallelism().map_or(1, |n| n.get()),
+	n => n,
+    }
+}
+
+/// Split `len` bytes into a region for each of `threads` threads, aligned to `BLOCK_SIZE`, returning the region size.
+fn chunk_size(len: usize, threads: usize) -> usize
+{
+    let chunk = len.div_ceil(threads).next_multiple_of(BLOCK_SIZE);
+    std::cmp::max(chunk, MIN_CHUNK_SIZE)
+}
+
+/// Call `f` with each of `chunks`, and the offset into the ciphertext it starts at, each on its own thread.
+///
+/// The last chunk is done on the calling thread.
+fn for_each_chunk<T, F>(offset: u64, chunk: usize, chunks: impl Iterator<Item = T>, f: F) -> Result<(), ErrorStack>
+where T: Send,
+      F: Fn(u64, T) -> Result<(), ErrorStack> + Sync
+{
+    let mut chunks = chunks.enumerate().map(|(i, c)| (offset + (i * chunk) as u64, c)).peekable();
+    let f = &f;
+    thread::scope(|s| {
+	let mut workers = Vec::new();
+	while let Some((offset, c)) = chunks.next() {
+	    if chunks.peek().is_none() {
+		f(offset, c)?;
+	    } else {
+		workers.push(s.spawn(move || f(offset, c)));
+	    }
+	}
+	workers.into_iter().try_for_each(|worker| worker.join().expect("transform thread panicked"))
+    })
+}
+
+/// Check that `len` bytes from byte `offset` of the ciphertext are all within `MAX_OFFSET`.
+fn check_range(offset: u64, len: usize) -> io::Result<()>
+{
+    match offset.checked_add(len as u64) {
+	Some(end) if end <= MAX_OFFSET => Ok(()),
+	_ => Err(io::Error::new(io::ErrorKind::InvalidInput, "input too large for chacha20 block counter")),
+    }
+}
+
+/// Apply the chacha20_poly1305 keystream to `input`, from byte `offset` of the ciphertext, writing to `output`.
+fn transform_at(key: &Key, iv: &IV, offset: u64, input: &[u8], output: &mut [u8], threads: usize) -> io::Result<()>
+{
+    if output.len() < input.len() {
+	return Err(io::Error::new(io::ErrorKind::InvalidInput, "output buffer too small for input"));
+    }
+    check_range(offset, input.len())?;
+    if input.is_empty() {
+	return Ok(());
+    }
+
+    let chunk = chunk_size(input.len(), thread_count(threads));
+    for_each_chunk(offset, chunk, input.chunks(chunk).zip(output.chunks_mut(chunk)), |offset, (input, output)| {
+	crypter_at(key, iv, offset)?.update(input, output)?;
+	Ok(())
+    })?;
+    Ok(())
+}
+
+/// Apply the chacha20_poly1305 keystream to `buf` in place, from byte `offset` of the ciphertext.
+fn apply_at(key: &Key, iv: &IV, offset: u64, buf: &mut [u8], threads: usize) -> io::Result<()>
+{
+    check_range(offset, buf.len())?;
+    if buf.is_empty() {
+	return Ok(());
+    }
+
+    let chunk = chunk_size(buf.len(), thread_count(threads));
+    for_each_chunk(offset, chunk, buf.chunks_mut(chunk), |offset, buf| {
+	Transformer::at(key, iv, offset)?.apply(buf)
+    })?;
+    Ok(())
+}
+
+/// En/decrypt `input` into `output` on up to `threads` threads (0 for one per available CPU.)
+///
+/// The output is the same as writing `input` to a `Sink` created with this key and IV. The Poly1305 tag is not computed.
+/// ```
+/// # use chacha20stream::{Sink, cha};
+/// # use std::io::Write;
+/// # let (key, iv) = chacha20stream::keygen();
+/// let input = vec![0x42u8; 1024 * 1024];
+/// let mut output = vec![0u8; input.len()];
+/// cha::par_transform(&key, &iv, &input[..], &mut output[..], 4).unwrap();
+///
+/// let mut sink = Sink::encrypt(Vec::new(), &key, &iv).unwrap();
+/// sink.write_all(&input[..]).unwrap();
+/// assert_eq!(sink.into_inner(), output);
+/// ```
+///
+/// # Errors
+/// If `output` is shorter than `input`, or `input` is longer than `MAX_OFFSET`, an `InvalidInput` error is returned and nothing is transformed.
+pub fn par_transform(key: impl AsRef<Key>, iv: impl AsRef<IV>, input: &[u8], output: &mut [u8], threads: usize) -> io::Result<()>
+{
+    transform_at(key.as_ref(), iv.as_ref(), 0, input, output, threads)
+}
+
+/// En/decrypt the file at `src` into a new file at `dst` on up to `threads` threads (0 for one per available CPU.)
+fn transform_file(src: &Path, dst: &Path, key: &Key, iv: &IV, threads: usize) -> io::Result<()>
+{
+    let threads = thread_count(threads);
+    let mut input = File::open(src)?;
+    #[cfg(unix)] let mut output = open_output(&input, dst, false)?;
+    #[cfg(not(unix))] let mut output = File::create(dst)?;
+
+    let mut buffer = crate::bytes::PruneOnDrop(vec![0u8; threads * FILE_STRIDE]);
+    let mut offset = 0u64;
+    loop {
+	// Fill the buffer, so each batch is split across every thread.
+	let mut len = 0;
+	while len < buffer.len() {
+	    match input.read(&mut buffer[len..]) {
+		Ok(0) => break,
+		Ok(n) => len += n,
+		Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+		Err(e) => return Err(e),
+	    }
+	}
+	if len == 0 {
+	    break;
+	}
+	apply_at(key, iv, offset, &mut buffer[..len], threads)?;
+	output.write_all(&buffer[..len])?;
+	offset += len as u64;
+    }
+    output.sync_all()
+}
+
+/// Encrypt the file at `src` into a new file at `dst`, on up to `threads` threads (0 for one per available CPU.)
+///
+/// `dst` is created, or truncated if it exists. Its contents are the same as copying `src` through a `Sink` created with `Sink::encrypt()`. The Poly1305 tag is not computed.
+///
+/// # Errors
+/// If either file cannot be opened, reading or writing fails, `src` is longer than `MAX_OFFSET`, or (on Unix) `src` and `dst` are the same file, which is left untouched.
+pub fn encrypt_file_parallel(src: impl AsRef<Path>, dst: impl AsRef<Path>, key: impl AsRef<Key>, iv: impl AsRef<IV>, threads: usize) -> io::Result<()>
+{
+    transform_file(src.as_ref(), dst.as_ref(), key.as_ref(), iv.as_ref(), threads)
+}
+
+/// Decrypt the file at `src`, encrypted with `encrypt_file_parallel()` or a `Sink` (without an appended tag), into a new file at `dst`, on up to `threads` threads (0 for one per available CPU.)
+///
+/// The data is not authenticated.
+///
+/// # Errors
+/// If either file cannot be opened, reading or writing fails, `src` is longer than `MAX_OFFSET`, or (on Unix) `src` and `dst` are the same file, which is left untouched.
+pub fn decrypt_file_parallel(src: impl AsRef<Path>, dst: impl AsRef<Path>, key: impl AsRef<Key>, iv: impl AsRef<IV>, threads: usize) -> io::Result<()>
+{
+    transform_file(src.as_ref(), dst.as_ref(), key.as_ref(), iv.as_ref(), threads)
+}
+
+#[cfg(test)]
+#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+mod tests
+{
+    use super::*;
+    use super::super::file_tests::{self, sequential};
+
+    #[test]
+    fn same_as_sink()
+    {
+	let (key, iv) = keygen();
+	for &len in &[0usize, 1, 63, 64, 65, MIN_CHUNK_SIZE - 1, MIN_CHUNK_SIZE + 1, 3 * MIN_CHUNK_SIZE + 100] {
+	    let input: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+	    let expected = sequential(&key, &iv, &input[..]);
+	    for &threads in &[0usize, 1, 2, 3, 8] {
+		let mut output = vec![0u8; len + 3];
+		par_transform(&key, &iv, &input[..], &mut output[..], threads).expect("par_transform");
+		assert_eq!(&output[..len], &expected[..], "len {}, threads {}", len, threads);
+
+		let mut buf = input.clone();
+		apply_at(&key, &iv, 0, &mut buf[..], threads).expect("apply_at");
+		assert_eq!(buf, expected);
+	    }
+	}
+    }
+
+    #[test]
+    fn offset()
+    {
+	let (key, iv) = keygen();
+	let input = vec![7u8; 2 * MIN_CHUNK_SIZE + 1000];
+	let expected = sequential(&key, &iv, &input[..]);
+
+	let mut output = vec![0u8; input.len() - 77];
+	transform_at(&key, &iv, 77, &input[77..], &mut output[..], 4).expect("transform_at");
+	assert_eq!(&output[..], &expected[77..]);
+    }
+
+    #[test]
+    fn out_of_range()
+    {
+	let (key, iv) = keygen();
+	let input = [0u8; 100];
+	let mut output = [0u8; 100];
+
+	let err = par_transform(&key, &iv, &input[..], &mut output[..99], 2).expect_err("output too small");
+	assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+	for &offset in &[MAX_OFFSET - 99, u64::MAX - 10] {
+	    let err = transform_at(&key, &iv, offset, &input[..], &mut output[..], 2).expect_err("past MAX_OFFSET");
+	    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+	    let err = apply_at(&key, &iv, offset, &mut output[..], 2).expect_err("past MAX_OFFSET");
+	    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+	}
+	transform_at(&key, &iv, MAX_OFFSET - 100, &input[..], &mut output[..], 2).expect("ends at MAX_OFFSET");
+    }
+
+    #[test]
+    fn files()
+    {
+	file_tests::round_trip(&[FILE_STRIDE * 2 + 12345], |src, dst, key, iv| encrypt_file_parallel(src, dst, key, iv, 1), |src, dst, key, iv| decrypt_file_parallel(src, dst, key, iv, 3));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn same_file()
+    {
+	file_tests::same_file(|src, dst, key, iv| encrypt_file_parallel(src, dst, key, iv, 2));
+    }
+}
@@ -0,0 +1,296 @@
+//! Errors for authenticated operations
+use std::{fmt, error, io};
+use openssl::error::ErrorStack;
+
+/// An error returned when computing or verifying the authentication tag of a stream fails.
+#[derive(Debug)]
+pub enum AuthError
+{
+    /// The Poly1305 tag did not match the data. The data may have been tampered with, or the wrong key/IV was used.
+    Mismatch,
+    /// The cipher was already finished. Finishing resets its keystream, so it cannot be used again.
+    Finished,
+    /// Transformed data has not been written to the inner stream yet. Flush the stream before finishing it.
+    Pending,
+    /// Internal SSL error
+    Ssl(ErrorStack),
+}
+
+impl AuthError
+{
+    /// Is this error an authentication failure (as opposed to an internal error)?
+    #[inline] pub fn is_mismatch(&self) -> bool
+    {
+	matches!(self, Self::Mismatch)
+    }
+}
+
+impl error::Error for AuthError
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)>
+    {
+	match self {
+	    Self::Ssl(ssl) => Some(ssl),
+	    _ => None,
+	}
+    }
+}
+
+impl fmt::Display for AuthError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Mismatch => write!(f, "authentication tag mismatch"),
+	    Self::Finished => write!(f, "cipher already finished"),
+	    Self::Pending => write!(f, "data pending write to inner stream"),
+	    Self::Ssl(_) => write!(f, "internal SSL error"),
+	}
+    }
+}
+
+impl From<ErrorStack> for AuthError
+{
+    #[inline] fn from(from: ErrorStack) -> Self
+    {
+	Self::Ssl(from)
+    }
+}
+
+impl From<AuthError> for io::Error
+{
+    fn from(from: AuthError) -> Self
+    {
+	match from {
+	    AuthError::Mismatch => io::Error::new(io::ErrorKind::InvalidData, from),
+	    AuthError::Finished | AuthError::Pending => io::Error::other(from),
+	    AuthError::Ssl(ssl) => ssl.into(),
+	}
+    }
+}
+
+/// An error in a framed stream (see `frame`.)
+#[derive(Debug)]
+pub enum FrameError
+{
+    /// The stream ended before its final segment.
+    Truncated,
+    /// The segment at this index failed authentication.
+    ///
+    /// This happens if the segment was modified, reordered, duplicated, or had its final-segment flag changed, if data was appended after the final segment, or if the wrong key/IV was used.
+    Mismatch(u32),
+    /// The inner stream produced more data after the final segment had been read.
+    TrailingData,
+    /// The stream has too many segments for the segment counter.
+    CounterOverflow,
+    /// The chunk size is out of range.
+    InvalidChunkSize(usize),
+    /// The stream was written to after it was finished.
+    Finished,
+    /// Internal SSL error
+    Ssl(ErrorStack),
+}
+
+impl error::Error for FrameError
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)>
+    {
+	match self {
+	    Self::Ssl(ssl) => Some(ssl),
+	    _ => None,
+	}
+    }
+}
+
+impl fmt::Display for FrameError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Truncated => write!(f, "stream truncated before final segment"),
+	    Self::Mismatch(n) => write!(f, "authentication tag mismatch in segment {}", n),
+	    Self::TrailingData => write!(f, "trailing data after final segment"),
+	    Self::CounterOverflow => write!(f, "segment counter overflow"),
+	    Self::InvalidChunkSize(sz) => write!(f, "invalid chunk size {}", sz),
+	    Self::Finished => write!(f, "stream already finished"),
+	    Self::Ssl(_) => write!(f, "internal SSL error"),
+	}
+    }
+}
+
+impl From<ErrorStack> for FrameError
+{
+    #[inline] fn from(from: ErrorStack) -> Self
+    {
+	Self::Ssl(from)
+    }
+}
+
+impl From<FrameError> for io::Error
+{
+    fn from(from: FrameError) -> Self
+    {
+	let kind = match from {
+	    FrameError::Truncated => io::ErrorKind::UnexpectedEof,
+	    FrameError::Mismatch(_) | FrameError::TrailingData | FrameError::InvalidChunkSize(_) => io::ErrorKind::InvalidData,
+	    _ => io::ErrorKind::Other,
+	};
+	io::Error::new(kind, from)
+    }
+}
+
+/// An error parsing a container header (see `header`.)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HeaderError
+{
+    /// The stream ended inside the header.
+    Truncated,
+    /// The stream does not start with the header's magic bytes.
+    BadMagic,
+    /// The header's format version is not supported.
+    UnsupportedVersion(u8),
+    /// The header's algorithm is not known.
+    UnknownAlgorithm(u8),
+    /// The header has unknown flags set.
+    UnknownFlags(u8),
+    /// The header's chunk size is out of range.
+    InvalidChunkSize(u32),
+}
+
+impl error::Error for HeaderError{}
+
+impl fmt::Display for HeaderError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Truncated => write!(f, "stream truncated inside header"),
+	    Self::BadMagic => write!(f, "invalid header magic"),
+	    Self::UnsupportedVersion(v) => write!(f, "unsupported header version {}", v),
+	    Self::UnknownAlgorithm(a) => write!(f, "unknown algorithm id {}", a),
+	    Self::UnknownFlags(fl) => write!(f, "unknown header flags {:#04x}", fl),
+	    Self::InvalidChunkSize(sz) => write!(f, "invalid chunk size {}", sz),
+	}
+    }
+}
+
+impl From<HeaderError> for io::Error
+{
+    fn from(from: HeaderError) -> Self
+    {
+	let kind = match from {
+	    HeaderError::Truncated => io::ErrorKind::UnexpectedEof,
+	    _ => io::ErrorKind::InvalidData,
+	};
+	io::Error::new(kind, from)
+    }
+}
+
+/// An error deriving a key from a passphrase (see `key::kdf`.)
+#[derive(Debug)]
+pub enum KdfError
+{
+    /// The cost parameters are below the minimum allowed.
+    WeakParams,
+    /// The cost parameters are out of range.
+    InvalidParams,
+    /// The salt is shorter than `MIN_SALT_SIZE`.
+    ShortSalt,
+    /// Internal SSL error
+    Ssl(ErrorStack),
+}
+
+impl error::Error for KdfError
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)>
+    {
+	match self {
+	    Self::Ssl(ssl) => Some(ssl),
+	    _ => None,
+	}
+    }
+}
+
+impl fmt::Display for KdfError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::WeakParams => write!(f, "key derivation cost parameters are too low"),
+	    Self::InvalidParams => write!(f, "key derivation cost parameters are out of range"),
+	    Self::ShortSalt => write!(f, "salt is too short"),
+	    Self::Ssl(_) => write!(f, "internal SSL error"),
+	}
+    }
+}
+
+impl From<ErrorStack> for KdfError
+{
+    #[inline] fn from(from: ErrorStack) -> Self
+    {
+	Self::Ssl(from)
+    }
+}
+
+/// An error producing IVs from an `IvSequence` (see `key::sequence`.)
+#[derive(Debug)]
+pub enum SequenceError
+{
+    /// Every counter value has been used. A new prefix or a new key is needed.
+    Exhausted,
+    /// The persisted sequence file was created with a different prefix.
+    PrefixMismatch,
+    /// The persisted sequence file is malformed.
+    Corrupt,
+    /// The persisted sequence file is in use by another sequence.
+    Locked,
+    /// Reading or writing the persisted sequence file failed.
+    Io(io::Error),
+}
+
+impl error::Error for SequenceError
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)>
+    {
+	match self {
+	    Self::Io(io) => Some(io),
+	    _ => None,
+	}
+    }
+}
+
+impl fmt::Display for SequenceError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	match self {
+	    Self::Exhausted => write!(f, "IV sequence counter exhausted"),
+	    Self::PrefixMismatch => write!(f, "IV sequence file has a different prefix"),
+	    Self::Corrupt => write!(f, "IV sequence file is corrupt"),
+	    Self::Locked => write!(f, "IV sequence file is locked by another sequence"),
+	    Self::Io(_) => write!(f, "IV sequence file i/o error"),
+	}
+    }
+}
+
+impl From<io::Error> for SequenceError
+{
+    #[inline] fn from(from: io::Error) -> Self
+    {
+	Self::Io(from)
+    }
+}
+
+impl From<SequenceError> for io::Error
+{
+    fn from(from: SequenceError) -> Self
+    {
+	let kind = match from {
+	    SequenceError::Io(io) => return io,
+	    SequenceError::Corrupt | SequenceError::PrefixMismatch => io::ErrorKind::InvalidData,
+	    SequenceError::Locked => io::ErrorKind::WouldBlock,
+	    SequenceError::Exhausted => io::ErrorKind::Other,
+	};
+	io::Error::new(kind, from)
+    }
+}
@@ -2,6 +2,8 @@
 //!
 //! Low level bindings to create in-memory translators for the cipher stream API.
 //! Intended to be linked to the C wrapper object `wrapper.c`.
+#![allow(clippy::missing_safety_doc)]
+
 use super::*;
 use std::ptr;
 use std::ffi::c_void;
@@ -54,10 +56,10 @@ pub use error::*;
 {
     no_unwind!(ref {
 	if !key.is_null() {
-	    *key = Key::new();
+	    key.write(Key::new());
 	}
 	if !iv.is_null() {
-	    *iv = IV::new();
+	    iv.write(IV::new());
 	}
 	
 	CErr::Success
@@ -75,7 +77,7 @@ pub use error::*;
 	} else {
 	    std::slice::from_raw_parts(ptr as *const u8, nbytes)
 	};
-	match sink.sink.write(&slice[..]) {
+	match sink.sink.write(slice) {
 	    Err(_) => return CErr::IO,
 	    Ok(v) => *bytes = v,
 	}
@@ -90,15 +92,17 @@ pub use error::*;
 	if file.is_null() {
 	    return CErr::InvalidFile;
 	}
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
 	let key = if key.is_null() {
 	    Key::new()
 	} else {
-	    *key
+	    (*key).clone()
 	};
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
 	let iv = if iv.is_null() {
 	    IV::new()
 	} else {
-	    *iv
+	    (*iv).clone()
 	};
 	let write = CPassthrough {
 	    backing: file,
@@ -119,10 +123,11 @@ pub use error::*;
 	let meta = nullchk!(ref meta);
 	let output = nullchk!(ref mut output);
 	
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
 	let sink = CSink {
 	    sink: match meta.mode {
-		CMode::Encrypt => Sink::encrypt(meta.clone(), meta.key, meta.iv).map_err(|_| CErr::SslError).unwrap(),
-		CMode::Decrypt => Sink::decrypt(meta.clone(), meta.key, meta.iv).map_err(|_| CErr::SslError).unwrap(),
+		CMode::Encrypt => Sink::encrypt(meta.clone(), &meta.key, &meta.iv).map_err(|_| CErr::SslError).unwrap(),
+		CMode::Decrypt => Sink::decrypt(meta.clone(), &meta.key, &meta.iv).map_err(|_| CErr::SslError).unwrap(),
 	    },
 	    cookie_settings: Default::default(),
 	};
@@ -163,12 +168,17 @@ pub use error::*;
     CErr::Success
 }
 /// Closes and frees the wrapper `sink`, and writes inner metadata struct to `meta`, if `file` is non-null.
+///
+/// If `meta` is null, the key and IV in the sink's metadata are cleared.
 #[no_mangle] pub unsafe extern "C" fn cc20_close_sink(sink: *mut CSink, meta: *mut CPassthrough) -> CErr
 {
     no_unwind!({
 	let sink = interop::take(nullchk!(sink));
+	let mut inner = sink.sink.into_inner();
 	if !meta.is_null() {
-	    *meta = sink.sink.into_inner();
+	    meta.write(inner);
+	} else {
+	    inner.clear_keys();
 	}
 	CErr::Success
     }).unwrap_or(CErr::Panic)
@@ -187,6 +197,16 @@ pub use error::*;
     cookie::create(sink)
 }
 
+impl CPassthrough
+{
+    /// Clear the key and IV from memory
+    #[inline] fn clear_keys(&mut self)
+    {
+	bytes::explicit_prune(self.key.as_mut());
+	bytes::explicit_prune(self.iv.as_mut());
+    }
+}
+
 impl Write for CPassthrough
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
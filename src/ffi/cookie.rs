@@ -118,8 +118,9 @@ extern "C" fn close(cookie: *mut c_void) -> c_int
     };
     let CSink { sink, cookie_settings } = unsafe { interop::take(sink) };
     let mut meta = sink.into_inner();
+    meta.clear_keys();
     if cookie_settings.keep_alive == 0 && !meta.backing.is_null() {
-	unsafe {  (libc::fclose(meta.backing), meta.backing = ptr::null_mut()).0 }
+	unsafe { libc::fclose(meta.backing) }
     } else {
 	0
     }
@@ -0,0 +1,673 @@
+//! Chunked, authenticated framing of a cipher stream
+//!
+//! A framed stream splits its data into fixed-size segments, each of which is sealed with its own nonce and Poly1305 tag (the STREAM construction.)
+//! Unlike a single tag at the end of the stream, each segment's plaintext is authenticated before it is released, and truncation, reordering, duplication, or appending of segments is detected.
+//!
+//! # Format
+//! The stream begins with the chunk size as a big-endian `u32`, followed by the segments.
+//! Each segment is the ciphertext of `chunk size` bytes of plaintext, followed by its `TAG_SIZE` byte tag.
+//! The final segment holds the remaining (less than `chunk size`, possibly 0) bytes of plaintext.
+//!
+//! Segments are sealed with a per-stream segment key, derived from the key and the `IV` with HKDF-SHA256. The nonce of each segment is 7 zero bytes, the 32-bit big-endian segment counter, then a final-segment flag byte.
+//! Since the `IV` only goes into the segment key, streams with different IVs never share a key and nonce, however similar the IVs are.
+//! The header (the chunk size, or the container header when created with `Sink::create()`) is bound to every segment as additional authenticated data.
+//!
+//! # Example
+//! ```
+//! # use chacha20stream::frame;
+//! # use std::io::{Read, Write};
+//! # let (key, iv) = chacha20stream::keygen();
+//! let mut sink = frame::Sink::with_chunk_size(Vec::new(), &key, &iv, 16).expect("Failed to create encryptor");
+//! sink.write_all(b"Hello world! Hello world!").unwrap();
+//! sink.finish().unwrap();
+//!
+//! let encrypted = sink.into_inner();
+//!
+//! let mut output = Vec::new();
+//! frame::Source::decrypt(&encrypted[..], &key, &iv).unwrap().read_to_end(&mut output).expect("Message was tampered with");
+//! assert_eq!(&output[..], b"Hello world! Hello world!");
+//! ```
+use super::*;
+use key::*;
+use bytes::PruneOnDrop;
+use header::Header;
+pub use error::FrameError;
+
+use std::io::{self, Read, Write};
+use openssl::error::ErrorStack;
+use std::fmt;
+
+/// The default size of the plaintext in each segment
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// The largest chunk size allowed
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Size of the chunk size header at the start of the stream
+pub const HEADER_SIZE: usize = 4;
+
+/// Domain separation label for segment keys
+const SEGMENT_KEY_LABEL: &[u8] = b"chacha20stream frame segment key v1\0";
+
+/// Derive the key the segments of the stream with this key and IV are sealed with.
+fn segment_key(key: &Key, iv: &IV) -> Result<Key, ErrorStack>
+{
+    let mut output = Key::default();
+    key::hkdf::hkdf(key.as_ref(), None, &[SEGMENT_KEY_LABEL, iv.as_ref()], output.as_mut())?;
+    Ok(output)
+}
+
+/// Compute the nonce of the segment at `counter`.
+fn segment_iv(counter: u32, last: bool) -> IV
+{
+    let mut bytes = [0u8; IV_SIZE];
+    bytes[IV_SIZE-5..IV_SIZE-1].copy_from_slice(&counter.to_be_bytes()[..]);
+    bytes[IV_SIZE-1] = last as u8;
+    IV::from_bytes(bytes)
+}
+
+/// Check a chunk size is in range
+pub(crate) fn check_chunk_size(sz: u32) -> Result<usize, FrameError>
+{
+    match sz as usize {
+	0 => Err(FrameError::InvalidChunkSize(0)),
+	x if x > MAX_CHUNK_SIZE => Err(FrameError::InvalidChunkSize(x)),
+	x => Ok(x),
+    }
+}
+
+/// Convert a chunk size to its stored representation, checking it is in range
+fn to_chunk_size(sz: usize) -> Result<u32, FrameError>
+{
+    if sz > MAX_CHUNK_SIZE {
+	return Err(FrameError::InvalidChunkSize(sz));
+    }
+    let sz = sz as u32;
+    check_chunk_size(sz)?;
+    Ok(sz)
+}
+
+/// Read into `buf` until it is full or EOF is reached, returning the number of bytes read.
+pub(crate) fn read_full<R: ?Sized + Read>(stream: &mut R, buf: &mut [u8]) -> io::Result<usize>
+{
+    let mut done = 0;
+    while done < buf.len() {
+	match stream.read(&mut buf[done..]) {
+	    Ok(0) => break,
+	    Ok(n) => done += n,
+	    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+	    Err(e) => return Err(e),
+	}
+    }
+    Ok(done)
+}
+
+/// Framed encrypting sink
+///
+/// Plaintext written is buffered until a full chunk is available, which is then sealed as a segment.
+/// A sealed segment is written to the inner stream by the next `write()`, `flush()`, or `finish()`, before any more plaintext is accepted.
+/// `finish()` must be called once all data is written, to write the final segment. A stream that is not finished will be rejected as truncated when read.
+///
+/// # Errors
+/// If writing a sealed segment to the inner stream fails, the segment is kept and written again by the next call. Its plaintext has already been accepted, so it is never sealed twice.
+///
+/// # Note
+/// `flush()` does not write a partial chunk, it only writes any sealed segment and flushes the inner stream.
+/// The buffered plaintext is cleared after each segment is sealed, and when the sink is dropped.
+pub struct Sink<W: ?Sized>
+{
+    key: Key, // segment key
+    chunk: u32,
+    counter: u32,
+    header: Vec<u8>, // written before the first segment, and bound to each segment as AAD
+    header_written: bool,
+    finished: bool, // the final segment has been sealed
+
+    buffer: PruneOnDrop<Vec<u8>>, // plaintext of the current segment
+    output: Vec<u8>, // sealed segment (after the header, if it has not been written yet)
+    written: usize, // bytes of `output` already written to `stream`
+
+    stream: W,
+}
+
+impl<W: ?Sized+ fmt::Debug> fmt::Debug for Sink<W>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "frame::Sink({:?}, (chunk size {}, segment {}))", &self.stream, self.chunk, self.counter)
+    }
+}
+
+impl<W> Sink<W>
+where W: Write
+{
+    /// Create a framed encrypting sink with the default chunk size
+    pub fn encrypt(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, ErrorStack>
+    {
+	let chunk = DEFAULT_CHUNK_SIZE as u32;
+	Self::from_header(stream, key.as_ref(), iv.as_ref(), chunk, chunk.to_be_bytes().to_vec(), false)
+    }
+
+    /// Create a framed encrypting sink with a specific chunk size
+    ///
+    /// # Errors
+    /// If `chunk_size` is 0 or greater than `MAX_CHUNK_SIZE`.
+    pub fn with_chunk_size(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>, chunk_size: usize) -> Result<Self, FrameError>
+    {
+	let chunk = to_chunk_size(chunk_size)?;
+	Ok(Self::from_header(stream, key.as_ref(), iv.as_ref(), chunk, chunk.to_be_bytes().to_vec(), false)?)
+    }
+
+    /// Create a framed encrypting sink with a container `Header` describing it
+    ///
+    /// The header is written to `stream` immediately.
+    ///
+    /// # Errors
+    /// If `chunk_size` is 0 or greater than `MAX_CHUNK_SIZE`, or writing the header fails.
+    pub fn create(mut stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>, chunk_size: usize) -> io::Result<Self>
+    {
+	let chunk = to_chunk_size(chunk_size)?;
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
+	let header = Header::new(iv.as_ref().clone()).chunked(chunk).to_bytes();
+	stream.write_all(&header[..])?;
+	Ok(Self::from_header(stream, key.as_ref(), iv.as_ref(), chunk, header, true)?)
+    }
+
+    #[inline] fn from_header(stream: W, key: &Key, iv: &IV, chunk: u32, header: Vec<u8>, header_written: bool) -> Result<Self, ErrorStack>
+    {
+	Ok(Self {
+	    key: segment_key(key, iv)?,
+	    chunk,
+	    counter: 0,
+	    header, header_written,
+	    finished: false,
+	    buffer: PruneOnDrop(Vec::with_capacity(chunk as usize)),
+	    output: Vec::new(),
+	    written: 0,
+	    stream,
+	})
+    }
+
+    /// Consume into the inner stream
+    ///
+    /// If the sink has not been `finish()`ed, the stream will be incomplete.
+    #[inline] pub fn into_inner(self) -> W
+    {
+	self.stream
+    }
+}
+
+impl<W: ?Sized + Write> Sink<W>
+{
+    /// The inner stream
+    #[inline] pub fn inner(&self) -> &W
+    {
+	&self.stream
+    }
+
+    /// The inner stream
+    #[inline] pub fn inner_mut(&mut self) -> &mut W
+    {
+	&mut self.stream
+    }
+
+    /// The plaintext size of each segment
+    #[inline] pub fn chunk_size(&self) -> usize
+    {
+	self.chunk as usize
+    }
+
+    /// Has the final segment been sealed?
+    ///
+    /// It has been written to the inner stream once `finish()` returns `Ok`.
+    #[inline] pub fn is_finished(&self) -> bool
+    {
+	self.finished
+    }
+
+    /// Write the sealed segment to the inner stream, if there is one that has not been written yet.
+    fn drain(&mut self) -> io::Result<()>
+    {
+	while self.written < self.output.len() {
+	    match self.stream.write(&self.output[self.written..]) {
+		Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write segment to inner stream")),
+		Ok(n) => self.written += n,
+		Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+		Err(e) => return Err(e),
+	    }
+	}
+	Ok(())
+    }
+
+    /// Seal the buffered plaintext as the segment at `counter` into `output`, after the first `start` bytes of it.
+    fn seal_into(&mut self, start: usize, last: bool) -> Result<(), ErrorStack>
+    {
+	let len = self.buffer.len();
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+	let mut crypter = cha::encrypter_with_aad(&self.key, segment_iv(self.counter, last), &self.header)?;
+	self.output.resize(start + len + TAG_SIZE, 0);
+	crypter.update(&self.buffer[..], &mut self.output[start..(start + len)])?;
+	crypter.finalize(&mut [])?;
+	crypter.get_tag(&mut self.output[(start + len)..])?;
+	Ok(())
+    }
+
+    /// Seal the buffered plaintext as a segment, to be written to the inner stream by `drain()`.
+    ///
+    /// The counter is advanced as soon as the segment is sealed, so it is never used for a different segment, even if writing this one fails.
+    fn seal(&mut self, last: bool) -> Result<(), FrameError>
+    {
+	debug_assert_eq!(self.written, self.output.len(), "seal would overwrite a segment not yet written");
+	let next = if last {
+	    self.counter
+	} else {
+	    self.counter.checked_add(1).ok_or(FrameError::CounterOverflow)?
+	};
+
+	self.output.clear();
+	self.written = 0;
+	if !self.header_written {
+	    self.output.extend_from_slice(&self.header[..]);
+	}
+	if let Err(e) = self.seal_into(self.output.len(), last) {
+	    self.output.clear();
+	    return Err(e.into());
+	}
+	self.header_written = true;
+	self.counter = next;
+	self.finished = last;
+
+	bytes::prune(&mut self.buffer[..]);
+	self.buffer.clear();
+	Ok(())
+    }
+
+    /// Seal and write the final segment
+    ///
+    /// The sink cannot be written to afterwards. If writing the final segment fails, calling this again retries it. Once it has succeeded, calling it again has no effect.
+    pub fn finish(&mut self) -> io::Result<()>
+    {
+	self.drain()?;
+	if !self.finished {
+	    self.seal(true)?;
+	}
+	self.drain()
+    }
+}
+
+impl<W: ?Sized + Write> Write for Sink<W>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	if self.finished {
+	    return Err(FrameError::Finished.into());
+	}
+	// The last sealed segment must be written before any more plaintext is accepted.
+	self.drain()?;
+
+	let take = std::cmp::min(self.chunk as usize - self.buffer.len(), buf.len());
+	self.buffer.extend_from_slice(&buf[..take]);
+	if self.buffer.len() == self.chunk as usize {
+	    if let Err(e) = self.seal(false) {
+		// Nothing was sealed, so give back the plaintext taken from `buf`.
+		let start = self.buffer.len() - take;
+		bytes::prune(&mut self.buffer[start..]);
+		self.buffer.truncate(start);
+		return Err(e.into());
+	    }
+	}
+	Ok(take)
+    }
+    #[inline] fn flush(&mut self) -> io::Result<()> {
+	self.drain()?;
+	self.stream.flush()
+    }
+}
+
+/// Framed decrypting source
+///
+/// Each segment is authenticated before any of its plaintext is released.
+/// Reading fails with a `FrameError` (inside the returned `io::Error`) if the stream is truncated, modified, reordered, or has data appended to it.
+pub struct Source<R: ?Sized>
+{
+    key: Key, // segment key
+    chunk: Option<u32>, // read from the stream
+    header: Vec<u8>, // bound to each segment as AAD
+    counter: u32,
+    done: bool,
+
+    raw: Vec<u8>, // sealed segment
+    plain: PruneOnDrop<Vec<u8>>, // opened segment
+    pos: usize,
+
+    stream: R,
+}
+
+impl<R: ?Sized+ fmt::Debug> fmt::Debug for Source<R>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "frame::Source({:?}, (chunk size {:?}, segment {}))", &self.stream, self.chunk, self.counter)
+    }
+}
+
+impl<R> Source<R>
+where R: Read
+{
+    /// Create a framed decrypting source
+    ///
+    /// The chunk size is read from the stream.
+    pub fn decrypt(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, ErrorStack>
+    {
+	Self::from_header(stream, key, iv, None, Vec::new())
+    }
+
+    /// Create a framed decrypting source from an already read container header
+    pub(crate) fn from_header(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>, chunk: Option<u32>, header: Vec<u8>) -> Result<Self, ErrorStack>
+    {
+	Ok(Self {
+	    key: segment_key(key.as_ref(), iv.as_ref())?,
+	    chunk, header,
+	    counter: 0,
+	    done: false,
+	    raw: Vec::new(),
+	    plain: PruneOnDrop(Vec::new()),
+	    pos: 0,
+	    stream,
+	})
+    }
+
+    /// Consume into the inner stream
+    #[inline] pub fn into_inner(self) -> R
+    {
+	self.stream
+    }
+}
+
+impl<R: ?Sized + Read> Source<R>
+{
+    /// The inner stream
+    #[inline] pub fn inner(&self) -> &R
+    {
+	&self.stream
+    }
+
+    /// The inner stream
+    #[inline] pub fn inner_mut(&mut self) -> &mut R
+    {
+	&mut self.stream
+    }
+
+    /// The plaintext size of each segment, if it has been read from the stream yet
+    #[inline] pub fn chunk_size(&self) -> Option<usize>
+    {
+	self.chunk.map(|x| x as usize)
+    }
+
+    /// Read and open the next segment.
+    fn open(&mut self) -> io::Result<()>
+    {
+	let chunk = match self.chunk {
+	    Some(chunk) => chunk,
+	    None => {
+		let mut header = [0u8; HEADER_SIZE];
+		if read_full(&mut self.stream, &mut header[..])? < HEADER_SIZE {
+		    return Err(FrameError::Truncated.into());
+		}
+		let chunk = u32::from_be_bytes(header);
+		check_chunk_size(chunk)?;
+		self.header = header.to_vec();
+		*self.chunk.get_or_insert(chunk)
+	    },
+	};
+
+	self.raw.resize(chunk as usize + TAG_SIZE, 0);
+	let read = read_full(&mut self.stream, &mut self.raw[..])?;
+	if read < TAG_SIZE {
+	    return Err(FrameError::Truncated.into());
+	}
+	let last = read < self.raw.len();
+	let len = read - TAG_SIZE;
+
+	bytes::prune(&mut self.plain[..]);
+	self.plain.resize(len, 0);
+	self.pos = 0;
+
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+	let mut crypter = cha::decrypter_with_aad(&self.key, segment_iv(self.counter, last), &self.header)?;
+	crypter.update(&self.raw[..len], &mut self.plain[..])?;
+	crypter.set_tag(&self.raw[len..read])?;
+	if crypter.finalize(&mut []).is_err() {
+	    bytes::prune(&mut self.plain[..]);
+	    self.plain.clear();
+	    return Err(FrameError::Mismatch(self.counter).into());
+	}
+
+	if last {
+	    if read_full(&mut self.stream, &mut [0u8; 1][..])? != 0 {
+		bytes::prune(&mut self.plain[..]);
+		self.plain.clear();
+		return Err(FrameError::TrailingData.into());
+	    }
+	    self.done = true;
+	} else {
+	    self.counter = self.counter.checked_add(1).ok_or(FrameError::CounterOverflow)?;
+	}
+	Ok(())
+    }
+}
+
+impl<R: ?Sized + Read> Read for Source<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+	while self.pos >= self.plain.len() {
+	    if self.done || buf.is_empty() {
+		return Ok(0);
+	    }
+	    self.open()?;
+	}
+	let n = std::cmp::min(buf.len(), self.plain.len() - self.pos);
+	buf[..n].copy_from_slice(&self.plain[self.pos..(self.pos + n)]);
+	self.pos += n;
+	Ok(n)
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+mod tests
+{
+    use super::*;
+
+    const CHUNK: usize = 16;
+
+    fn encrypt(input: &[u8], key: &Key, iv: &IV) -> Vec<u8>
+    {
+	let mut sink = Sink::with_chunk_size(Vec::new(), key, iv, CHUNK).expect("frame::Sink");
+	sink.write_all(input).expect("frame::Sink::write_all");
+	sink.finish().expect("frame::Sink::finish");
+	sink.into_inner()
+    }
+
+    fn decrypt(input: &[u8], key: &Key, iv: &IV) -> io::Result<Vec<u8>>
+    {
+	let mut output = Vec::new();
+	Source::decrypt(input, key, iv)?.read_to_end(&mut output)?;
+	Ok(output)
+    }
+
+    fn frame_error(err: io::Error) -> FrameError
+    {
+	*err.into_inner().expect("no inner error").downcast::<FrameError>().expect("not a frame error")
+    }
+
+    /// The range of the segment at `n` in an encrypted stream
+    fn segment(n: usize) -> std::ops::Range<usize>
+    {
+	let start = HEADER_SIZE + n * (CHUNK + TAG_SIZE);
+	start..(start + CHUNK + TAG_SIZE)
+    }
+
+    #[test]
+    fn round_trip()
+    {
+	let (key, iv) = cha::keygen();
+	let input: Vec<u8> = (0..200u8).collect();
+
+	for &len in &[0, 1, CHUNK - 1, CHUNK, CHUNK + 1, CHUNK * 3, 200] {
+	    let encrypted = encrypt(&input[..len], &key, &iv);
+	    assert_eq!(encrypted.len(), HEADER_SIZE + (len / CHUNK + 1) * TAG_SIZE + len);
+	    assert_eq!(&encrypted[..HEADER_SIZE], &(CHUNK as u32).to_be_bytes()[..]);
+	    assert_eq!(&decrypt(&encrypted[..], &key, &iv).expect("decrypt")[..], &input[..len]);
+	}
+    }
+
+    #[test]
+    fn truncated()
+    {
+	let (key, iv) = cha::keygen();
+	let encrypted = encrypt(&[0xaau8; CHUNK * 3 + 4][..], &key, &iv);
+
+	// At a segment boundary
+	let err = decrypt(&encrypted[..segment(2).end], &key, &iv).unwrap_err();
+	assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+	assert!(matches!(frame_error(err), FrameError::Truncated));
+
+	// Inside the final segment
+	let err = decrypt(&encrypted[..encrypted.len() - 1], &key, &iv).unwrap_err();
+	assert!(matches!(frame_error(err), FrameError::Mismatch(3)));
+
+	// Inside the header
+	assert!(matches!(frame_error(decrypt(&encrypted[..2], &key, &iv).unwrap_err()), FrameError::Truncated));
+    }
+
+    #[test]
+    fn reordered()
+    {
+	let (key, iv) = cha::keygen();
+	let encrypted = encrypt(&[0xaau8; CHUNK * 3 + 4][..], &key, &iv);
+
+	let mut reordered = encrypted[..segment(0).start].to_vec();
+	reordered.extend_from_slice(&encrypted[segment(1)]);
+	reordered.extend_from_slice(&encrypted[segment(0)]);
+	reordered.extend_from_slice(&encrypted[segment(2).start..]);
+
+	assert!(matches!(frame_error(decrypt(&reordered[..], &key, &iv).unwrap_err()), FrameError::Mismatch(0)));
+    }
+
+    #[test]
+    fn duplicated()
+    {
+	let (key, iv) = cha::keygen();
+	let encrypted = encrypt(&[0xaau8; CHUNK * 3 + 4][..], &key, &iv);
+
+	let mut duplicated = encrypted[..segment(1).end].to_vec();
+	duplicated.extend_from_slice(&encrypted[segment(1).start..]);
+
+	assert!(matches!(frame_error(decrypt(&duplicated[..], &key, &iv).unwrap_err()), FrameError::Mismatch(2)));
+    }
+
+    #[test]
+    fn appended()
+    {
+	let (key, iv) = cha::keygen();
+	let mut encrypted = encrypt(&[0xaau8; CHUNK * 2 + 4][..], &key, &iv);
+	encrypted.push(0);
+
+	// Appended data changes the final segment's boundary
+	let err = decrypt(&encrypted[..], &key, &iv).unwrap_err();
+	assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	assert!(matches!(frame_error(err), FrameError::Mismatch(2)));
+
+	encrypted.extend_from_slice(&[0u8; CHUNK + TAG_SIZE][..]);
+	assert!(matches!(frame_error(decrypt(&encrypted[..], &key, &iv).unwrap_err()), FrameError::Mismatch(2)));
+    }
+
+    #[test]
+    fn related_ivs()
+    {
+	// IVs from a sequence differ only in their last bytes, as do the nonces of a stream's segments
+	let key = Key::new();
+	let (first, second) = (IV::from_counter(*b"seq0", 0), IV::from_counter(*b"seq0", 1));
+	let input = [0u8; CHUNK * 2];
+
+	// The final segment of one stream never shares a keystream with a segment of the other
+	let one = encrypt(&input[..4], &key, &first);
+	let two = encrypt(&input[..], &key, &second);
+	for n in 0..3 {
+	    assert_ne!(&one[segment(0).start..][..4], &two[segment(n).start..][..4]);
+	}
+	assert_ne!(segment_key(&key, &first).unwrap(), segment_key(&key, &second).unwrap());
+	assert!(decrypt(&one[..], &key, &second).is_err());
+    }
+
+    /// A writer that accepts `room` more bytes, then fails with `BrokenPipe`.
+    struct BreakableWriter
+    {
+	inner: Vec<u8>,
+	room: usize,
+    }
+
+    impl Write for BreakableWriter
+    {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	    if self.room == 0 {
+		return Err(io::ErrorKind::BrokenPipe.into());
+	    }
+	    let n = std::cmp::min(buf.len(), self.room);
+	    self.inner.extend_from_slice(&buf[..n]);
+	    self.room -= n;
+	    Ok(n)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn write_errors()
+    {
+	let (key, iv) = cha::keygen();
+	let input: Vec<u8> = (0..(CHUNK * 2 + 4) as u8).collect();
+	let mut sink = Sink::with_chunk_size(BreakableWriter { inner: Vec::new(), room: HEADER_SIZE + 5 }, &key, &iv, CHUNK).expect("frame::Sink");
+
+	// The first chunk is consumed and sealed, and the segment is written before any more is accepted
+	assert_eq!(sink.write(&input[..]).unwrap(), CHUNK);
+	assert_eq!(sink.write(&input[CHUNK..]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+	assert_eq!(sink.flush().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+	assert_eq!(sink.finish().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+	assert!(!sink.is_finished());
+
+	// Room for the rest of the first segment, and the second
+	sink.inner_mut().room = (CHUNK + TAG_SIZE - 5) + (CHUNK + TAG_SIZE);
+	sink.write_all(&input[CHUNK..]).unwrap();
+
+	// The final segment is sealed once, and written when `finish()` is retried
+	assert_eq!(sink.finish().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+	assert!(sink.is_finished());
+	assert!(sink.write(&input[..]).is_err());
+	sink.inner_mut().room = usize::MAX;
+	sink.finish().expect("frame::Sink::finish");
+	sink.finish().expect("frame::Sink::finish");
+
+	let encrypted = sink.into_inner().inner;
+	assert_eq!(encrypted.len(), HEADER_SIZE + 3 * TAG_SIZE + input.len());
+	assert_eq!(&decrypt(&encrypted[..], &key, &iv).expect("decrypt")[..], &input[..]);
+    }
+
+    #[test]
+    fn chunk_size()
+    {
+	let (key, iv) = cha::keygen();
+	let mut encrypted = encrypt(&[0xaau8; CHUNK * 2][..], &key, &iv);
+
+	// Changing the recorded chunk size is detected
+	encrypted[..HEADER_SIZE].copy_from_slice(&(CHUNK as u32 * 2).to_be_bytes()[..]);
+	assert!(matches!(frame_error(decrypt(&encrypted[..], &key, &iv).unwrap_err()), FrameError::Mismatch(_)));
+
+	encrypted[..HEADER_SIZE].copy_from_slice(&0u32.to_be_bytes()[..]);
+	assert!(matches!(frame_error(decrypt(&encrypted[..], &key, &iv).unwrap_err()), FrameError::InvalidChunkSize(0)));
+
+	assert!(Sink::with_chunk_size(Vec::new(), &key, &iv, 0).is_err());
+	assert!(Sink::with_chunk_size(Vec::new(), &key, &iv, MAX_CHUNK_SIZE + 1).is_err());
+	assert!(matches!(Sink::with_chunk_size(Vec::new(), &key, &iv, usize::MAX).unwrap_err(), FrameError::InvalidChunkSize(usize::MAX)));
+    }
+}
@@ -0,0 +1,335 @@
+//! Self-describing container header
+//!
+//! A container starts with a header describing how it was encrypted, so it can be decrypted with only the `Key`.
+//! The header is bound to the ciphertext as additional authenticated data.
+//!
+//! # Format
+//! | Field      | Size | Notes                                      |
+//! |------------|------|--------------------------------------------|
+//! | Magic      | 4    | `CC20`                                     |
+//! | Version    | 1    | `VERSION`                                  |
+//! | Algorithm  | 1    | See `Algorithm`                            |
+//! | Flags      | 1    | Bit 0: the stream is framed (see `frame`)  |
+//! | IV         | 12   |                                            |
+//! | Chunk size | 4    | Big-endian, only present if framed         |
+//!
+//! An unframed container is followed by the ciphertext and its `TAG_SIZE` byte Poly1305 tag.
+//! A framed container is followed by the segments of a framed stream (without the framed stream's own chunk size header.)
+//!
+//! # Example
+//! ```
+//! # use chacha20stream::{Sink, Source, Key, IV};
+//! # use std::io::{Read, Write};
+//! let key = Key::new();
+//! let mut sink = Sink::create(Vec::new(), &key, IV::new()).expect("Failed to create encryptor");
+//! sink.write_all(b"Hello world!").unwrap();
+//! sink.finish_append().unwrap();
+//!
+//! let encrypted = sink.into_inner();
+//!
+//! let mut output = Vec::new();
+//! Source::open(&encrypted[..], key).expect("Invalid header").read_to_end(&mut output).expect("Message was tampered with");
+//! assert_eq!(&output[..], b"Hello world!");
+//! ```
+use super::*;
+use key::*;
+pub use error::HeaderError;
+
+use std::io::{self, Read};
+use std::convert::TryFrom;
+
+/// Magic bytes at the start of a container
+pub const MAGIC: [u8; 4] = *b"CC20";
+/// The current format version
+pub const VERSION: u8 = 1;
+
+/// Size of the header without the chunk size
+pub const HEADER_SIZE: usize = MAGIC.len() + 3 + IV_SIZE;
+/// Size of the header with the chunk size
+pub const HEADER_SIZE_FRAMED: usize = HEADER_SIZE + 4;
+
+const FLAG_FRAMED: u8 = 1;
+
+/// The cipher a container was encrypted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Algorithm
+{
+    /// chacha20_poly1305
+    ChaCha20Poly1305 = 1,
+}
+
+impl TryFrom<u8> for Algorithm
+{
+    type Error = HeaderError;
+
+    fn try_from(from: u8) -> Result<Self, Self::Error>
+    {
+	match from {
+	    1 => Ok(Self::ChaCha20Poly1305),
+	    x => Err(HeaderError::UnknownAlgorithm(x)),
+	}
+    }
+}
+
+impl Default for Algorithm
+{
+    #[inline]
+    fn default() -> Self
+    {
+	Self::ChaCha20Poly1305
+    }
+}
+
+/// A container header
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Header
+{
+    pub algorithm: Algorithm,
+    pub iv: IV,
+    /// The chunk size, if the container is framed
+    pub chunk_size: Option<u32>,
+}
+
+impl Header
+{
+    /// Create a header for an unframed container with this IV
+    #[inline] pub fn new(iv: IV) -> Self
+    {
+	Self {
+	    algorithm: Algorithm::default(),
+	    iv,
+	    chunk_size: None,
+	}
+    }
+
+    /// Make this header describe a framed container with this chunk size
+    #[inline] pub fn chunked(self, chunk_size: u32) -> Self
+    {
+	Self {
+	    chunk_size: Some(chunk_size),
+	    ..self
+	}
+    }
+
+    /// The size of this header when encoded
+    #[inline] pub fn encoded_len(&self) -> usize
+    {
+	if self.chunk_size.is_some() {
+	    HEADER_SIZE_FRAMED
+	} else {
+	    HEADER_SIZE
+	}
+    }
+
+    /// Encode this header
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+	let mut output = Vec::with_capacity(self.encoded_len());
+	output.extend_from_slice(&MAGIC[..]);
+	output.push(VERSION);
+	output.push(self.algorithm as u8);
+	output.push(if self.chunk_size.is_some() { FLAG_FRAMED } else { 0 });
+	output.extend_from_slice(self.iv.as_ref());
+	if let Some(chunk) = self.chunk_size {
+	    output.extend_from_slice(&chunk.to_be_bytes()[..]);
+	}
+	output
+    }
+
+    /// Decode a header from the start of `bytes`, returning it and the number of bytes it took up.
+    pub fn parse(bytes: &[u8]) -> Result<(Self, usize), HeaderError>
+    {
+	if bytes.len() < HEADER_SIZE {
+	    return Err(if MAGIC.starts_with(&bytes[..std::cmp::min(bytes.len(), MAGIC.len())]) {
+		HeaderError::Truncated
+	    } else {
+		HeaderError::BadMagic
+	    });
+	}
+	if bytes[..MAGIC.len()] != MAGIC[..] {
+	    return Err(HeaderError::BadMagic);
+	}
+	let bytes = &bytes[MAGIC.len()..];
+	if bytes[0] != VERSION {
+	    return Err(HeaderError::UnsupportedVersion(bytes[0]));
+	}
+	let algorithm = Algorithm::try_from(bytes[1])?;
+	let flags = bytes[2];
+	if flags & !FLAG_FRAMED != 0 {
+	    return Err(HeaderError::UnknownFlags(flags));
+	}
+	let mut iv = IV::default();
+	iv.as_mut().copy_from_slice(&bytes[3..(3 + IV_SIZE)]);
+
+	let chunk_size = if flags & FLAG_FRAMED != 0 {
+	    let bytes = &bytes[(3 + IV_SIZE)..];
+	    if bytes.len() < 4 {
+		return Err(HeaderError::Truncated);
+	    }
+	    let chunk = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+	    frame::check_chunk_size(chunk).map_err(|_| HeaderError::InvalidChunkSize(chunk))?;
+	    Some(chunk)
+	} else {
+	    None
+	};
+	let this = Self {
+	    algorithm,
+	    iv,
+	    chunk_size,
+	};
+	let len = this.encoded_len();
+	Ok((this, len))
+    }
+
+    /// Read and decode a header from the start of a stream, returning it and its encoded bytes.
+    pub fn read_from<R: ?Sized + Read>(stream: &mut R) -> io::Result<(Self, Vec<u8>)>
+    {
+	let mut bytes = vec![0u8; HEADER_SIZE_FRAMED];
+	let read = frame::read_full(stream, &mut bytes[..HEADER_SIZE])?;
+	let len = match Self::parse(&bytes[..read]) {
+	    Err(HeaderError::Truncated) if read == HEADER_SIZE => {
+		// Framed, read the chunk size
+		if frame::read_full(stream, &mut bytes[HEADER_SIZE..])? < HEADER_SIZE_FRAMED - HEADER_SIZE {
+		    return Err(HeaderError::Truncated.into());
+		}
+		HEADER_SIZE_FRAMED
+	    },
+	    Err(err) => return Err(err.into()),
+	    Ok((_, len)) => len,
+	};
+	bytes.truncate(len);
+	let (this, _) = Self::parse(&bytes[..])?;
+	Ok((this, bytes))
+    }
+}
+
+/// A decrypting reader opened from a container with `Source::open()`.
+#[derive(Debug)]
+pub enum Reader<R>
+{
+    /// An unframed container, with a single tag at the end.
+    Tagged(Source<R>),
+    /// A framed container.
+    Framed(frame::Source<R>),
+}
+
+impl<R: Read> Reader<R>
+{
+    /// Read a container header from `stream` and create a decrypting reader for the rest of it.
+    pub fn open(mut stream: R, key: impl AsRef<Key>) -> io::Result<Self>
+    {
+	let (header, bytes) = Header::read_from(&mut stream)?;
+	Ok(match header.chunk_size {
+	    Some(chunk) => Self::Framed(frame::Source::from_header(stream, key, header.iv, Some(chunk), bytes)?),
+	    None => Self::Tagged(Source::decrypt_with_aad(stream, key, header.iv, bytes)?),
+	})
+    }
+
+    /// Consume into the inner stream
+    pub fn into_inner(self) -> R
+    {
+	match self {
+	    Self::Tagged(source) => source.into_inner(),
+	    Self::Framed(source) => source.into_inner(),
+	}
+    }
+}
+
+impl<R: Read> Read for Reader<R>
+{
+    #[inline] fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+	match self {
+	    Self::Tagged(source) => source.read(buf),
+	    Self::Framed(source) => source.read(buf),
+	}
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+mod tests
+{
+    use super::*;
+    use std::io::Write;
+
+    fn header_error(err: io::Error) -> HeaderError
+    {
+	*err.into_inner().expect("no inner error").downcast::<HeaderError>().expect("not a header error")
+    }
+
+    #[test]
+    #[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
+    fn encode_decode()
+    {
+	let iv = IV::new();
+	for header in [Header::new(iv.clone()), Header::new(iv).chunked(4096)].iter() {
+	    let bytes = header.to_bytes();
+	    assert_eq!(bytes.len(), header.encoded_len());
+	    assert_eq!(Header::parse(&bytes[..]), Ok((header.clone(), header.encoded_len())));
+
+	    let (read, read_bytes) = Header::read_from(&mut &bytes[..]).expect("read_from");
+	    assert_eq!(&read, header);
+	    assert_eq!(read_bytes, bytes);
+	}
+    }
+
+    #[test]
+    fn malformed()
+    {
+	let bytes = Header::new(IV::new()).chunked(4096).to_bytes();
+	let check = |bytes: &[u8], expected: HeaderError| {
+	    assert_eq!(header_error(Header::read_from(&mut &bytes[..]).unwrap_err()), expected);
+	};
+
+	check(&bytes[..2], HeaderError::Truncated);
+	check(&bytes[..HEADER_SIZE + 1], HeaderError::Truncated);
+	check(b"not a header at all", HeaderError::BadMagic);
+
+	let mut bad = bytes.clone();
+	bad[4] = VERSION + 1;
+	check(&bad[..], HeaderError::UnsupportedVersion(VERSION + 1));
+
+	let mut bad = bytes.clone();
+	bad[5] = 0;
+	check(&bad[..], HeaderError::UnknownAlgorithm(0));
+
+	let mut bad = bytes.clone();
+	bad[6] |= 0x80;
+	check(&bad[..], HeaderError::UnknownFlags(0x81));
+
+	let mut bad = bytes.clone();
+	bad[HEADER_SIZE..].copy_from_slice(&0u32.to_be_bytes()[..]);
+	check(&bad[..], HeaderError::InvalidChunkSize(0));
+    }
+
+    #[test]
+    fn open()
+    {
+	const INPUT: &[u8] = b"Hello world!Hello world!Hello world!";
+	let key = Key::new();
+
+	let mut sink = Sink::create(Vec::new(), &key, IV::new()).expect("Sink::create");
+	sink.write_all(INPUT).unwrap();
+	sink.finish_append().unwrap();
+	let tagged = sink.into_inner();
+
+	let mut sink = frame::Sink::create(Vec::new(), &key, IV::new(), 16).expect("frame::Sink::create");
+	sink.write_all(INPUT).unwrap();
+	sink.finish().unwrap();
+	let framed = sink.into_inner();
+
+	for (mut encrypted, is_framed) in [(tagged, false), (framed, true)] {
+	    let mut output = Vec::new();
+	    let mut reader = Source::open(&encrypted[..], &key).expect("Source::open");
+	    assert_eq!(matches!(reader, Reader::Framed(_)), is_framed);
+	    reader.read_to_end(&mut output).expect("read");
+	    assert_eq!(&output[..], INPUT);
+
+	    // A tampered header is rejected
+	    encrypted[MAGIC.len() + 3] ^= 1;
+	    let err = Source::open(&encrypted[..], &key).expect("Source::open").read_to_end(&mut Vec::new()).unwrap_err();
+	    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+    }
+}
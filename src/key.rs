@@ -5,9 +5,17 @@ use std::{fmt, str};
 pub use crate::cha::{
     KEY_SIZE,
     IV_SIZE,
+    TAG_SIZE,
 };
 use crate::ext::*;
 
+pub mod kdf;
+pub use kdf::KdfParams;
+pub mod hkdf;
+pub mod agreement;
+pub mod sequence;
+pub use sequence::IvSequence;
+
 /// A 32 byte key for the chacha20_poly1305 cipher
 ///
 /// # Generation
@@ -22,6 +30,10 @@ use crate::ext::*;
 ///
 /// You can also generate a random key/IV pair with `chacha20stream::keygen()`.
 ///
+/// # Clearing
+/// With the `zeroize` feature enabled, this type is not `Copy`, and is explicitly cleared from memory when dropped.
+/// Copies must be made with `clone()`, and streams can be created from a `&Key` without copying it.
+///
 /// # Encoding
 /// This type implements `std::fmt::Display`, which prints the key as a base64 string.
 /// Additionally, it implements `std::str::FromStr`, which decodes a base64 string into a `Key` instance.
@@ -39,7 +51,8 @@ use crate::ext::*;
 ///
 /// assert_eq!(key_encoded.parse::<Key>().unwrap(), key);
 /// ```
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(not(feature="zeroize"), derive(Copy))]
 #[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(transparent)]
 pub struct Key([u8; KEY_SIZE]);
@@ -58,6 +71,9 @@ pub struct Key([u8; KEY_SIZE]);
 ///
 /// You can also generate a random key/IV pair with `chacha20stream::keygen()`.
 ///
+/// # Clearing
+/// With the `zeroize` feature enabled, this type is not `Copy`, and is explicitly cleared from memory when dropped, in the same way as `Key`.
+///
 /// # Encoding
 /// This type implements `std::fmt::Display`, which prints the IV as a base64 string.
 /// Additionally, it implements `std::str::FromStr`, which decodes a base64 string into a `IV` instance.
@@ -75,11 +91,43 @@ pub struct Key([u8; KEY_SIZE]);
 ///
 /// assert_eq!(iv_encoded.parse::<IV>().unwrap(), iv);
 /// ```
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(not(feature="zeroize"), derive(Copy))]
 #[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(transparent)]
 pub struct IV([u8; IV_SIZE]);
 
+/// A 16 byte Poly1305 authentication tag for the chacha20_poly1305 cipher
+///
+/// A tag is produced when an encrypting stream is finished, and must be supplied when finishing a decrypting stream to verify the data was not tampered with.
+/// To create a tag structure from bytes, you can use `Tag::from_bytes()` if the size of the buffer is exact, or you can write to an empty `Tag` as it implements `Default`.
+///
+/// # Encoding
+/// This type implements `std::fmt::Display`, which prints the tag as a base64 string.
+/// Additionally, it implements `std::str::FromStr`, which decodes a base64 string into a `Tag` instance.
+/// If the input base64 string data decoded is shorter than `TAG_SIZE`, the rest of the tag instance is padded with 0s.
+/// If it is longer, the rest is ignored.
+///
+/// The tag can also be lazily formatted as a hex string, with the method `to_hex_string()`.
+/// ```
+/// # use chacha20stream::{Sink, Tag};
+/// # use std::io::Write;
+/// # let (key, iv) = chacha20stream::keygen();
+/// let mut sink = Sink::encrypt(Vec::new(), key, iv).unwrap();
+/// sink.write_all(b"Hello world!").unwrap();
+/// let tag = sink.finish().unwrap();
+/// let tag_encoded = tag.to_string();
+///
+/// println!("Tag base64: {}", tag_encoded);
+/// println!("Tag hex: {}", tag.to_hex_string());
+///
+/// assert_eq!(tag_encoded.parse::<Tag>().unwrap(), tag);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Default)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(transparent)]
+pub struct Tag([u8; TAG_SIZE]);
+
 impl Key
 {
     /// Construct a `Key` from an exact length (32 bytes) buffer.
@@ -159,6 +207,23 @@ impl IV
     }
 }
 
+impl Tag
+{
+    /// Construct a `Tag` from an exact length (16 bytes) buffer.
+    #[inline] pub fn from_bytes(k: [u8; TAG_SIZE]) -> Self
+    {
+	Self(k)
+    }
+
+    /// Format this tag as a hex string
+    ///
+    /// Returns an opaque type that lazily formats the tag into a hex string when written.
+    pub fn to_hex_string(&self) -> impl fmt::Display + '_
+    {
+	self.0.iter().copied().into_hex()
+    }
+}
+
 impl From<[u8; KEY_SIZE]> for Key
 {
     #[inline] fn from(from: [u8; KEY_SIZE]) -> Self
@@ -175,6 +240,14 @@ impl From<[u8; IV_SIZE]> for IV
     }
 }
 
+impl From<[u8; TAG_SIZE]> for Tag
+{
+    #[inline] fn from(from: [u8; TAG_SIZE]) -> Self
+    {
+	Self(from)
+    }
+}
+
 
 impl AsRef<[u8]> for Key
 {
@@ -191,6 +264,14 @@ impl AsRef<[u8]> for IV
     }
 }
 
+impl AsRef<[u8]> for Tag
+{
+    fn as_ref(&self) -> &[u8]
+    {
+	&self.0[..]
+    }
+}
+
 impl AsMut<[u8]> for Key
 {
     fn as_mut(&mut self) -> &mut [u8]
@@ -207,6 +288,14 @@ impl AsMut<[u8]> for IV
     }
 }
 
+impl AsMut<[u8]> for Tag
+{
+    fn as_mut(&mut self) -> &mut [u8]
+    {
+	&mut self.0[..]
+    }
+}
+
 impl AsRef<Key> for Key
 {
     #[inline] fn as_ref(&self) -> &Key
@@ -222,6 +311,24 @@ impl AsRef<IV> for IV
     }
 }
 
+#[cfg(feature="zeroize")]
+impl Drop for Key
+{
+    fn drop(&mut self)
+    {
+	crate::bytes::explicit_prune(&mut self.0[..]);
+    }
+}
+
+#[cfg(feature="zeroize")]
+impl Drop for IV
+{
+    fn drop(&mut self)
+    {
+	crate::bytes::explicit_prune(&mut self.0[..]);
+    }
+}
+
 impl fmt::Display for Key
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
@@ -238,6 +345,14 @@ impl fmt::Display for IV
     }
 }
 
+impl fmt::Display for Tag
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "{}", base64::encode(&self.0[..]))
+    }
+}
+
 impl str::FromStr for Key
 {
     type Err = base64::DecodeError;
@@ -248,7 +363,7 @@ impl str::FromStr for Key
 
 	let mut this = Self::default();
 	let sz = std::cmp::min(KEY_SIZE, buffer.len());
-	(&mut this.0[..sz]).copy_from_slice(&buffer[..sz]);
+	this.0[..sz].copy_from_slice(&buffer[..sz]);
 	Ok(this)
     }
 }
@@ -263,7 +378,22 @@ impl str::FromStr for IV
 
 	let mut this = Self::default();
 	let sz = std::cmp::min(IV_SIZE, buffer.len());
-	(&mut this.0[..sz]).copy_from_slice(&buffer[..sz]);
+	this.0[..sz].copy_from_slice(&buffer[..sz]);
+	Ok(this)
+    }
+}
+
+impl str::FromStr for Tag
+{
+    type Err = base64::DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	let mut buffer = Vec::with_capacity(TAG_SIZE);
+	base64::decode_config_buf(s.as_bytes(), base64::STANDARD, &mut buffer)?;
+
+	let mut this = Self::default();
+	let sz = std::cmp::min(TAG_SIZE, buffer.len());
+	this.0[..sz].copy_from_slice(&buffer[..sz]);
 	Ok(this)
     }
 }
@@ -271,7 +401,7 @@ impl str::FromStr for IV
 #[cfg(test)]
 mod tests
 {
-    use super::{Key, IV};
+    use super::{Key, IV, Tag};
     #[test]
     fn enc_dec()
     {
@@ -286,4 +416,16 @@ mod tests
 	assert_eq!(key, key2);
 	assert_eq!(iv, iv2);
     }
+
+    #[test]
+    fn tag_enc_dec()
+    {
+	let tag = Tag::from_bytes(*b"0123456789abcdef");
+
+	let tag_str = tag.to_string();
+	let tag2: Tag = tag_str.parse().expect("tag");
+
+	assert_eq!(tag, tag2);
+	assert_eq!(tag.to_hex_string().to_string(), "30313233343536373839616263646566");
+    }
 }
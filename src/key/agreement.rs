@@ -0,0 +1,337 @@
+//! X25519 key agreement
+//!
+//! Two parties can each generate a `StaticSecret`, exchange the `PublicKey`s for them, and agree on the same `Key` with `diffie_hellman()` without sharing any secret.
+//! ```
+//! # use chacha20stream::key::agreement::{StaticSecret, diffie_hellman};
+//! let alice = StaticSecret::new();
+//! let bob = StaticSecret::new();
+//!
+//! // Only the public keys are exchanged
+//! let (alice_public, bob_public) = (alice.public_key(), bob.public_key());
+//!
+//! let alice_key = diffie_hellman(&alice, &bob_public).expect("Invalid public key");
+//! let bob_key = diffie_hellman(&bob, &alice_public).expect("Invalid public key");
+//! assert_eq!(alice_key, bob_key);
+//! ```
+use super::*;
+use openssl::{
+    pkey::{PKey, Id},
+    derive::Deriver,
+    error::ErrorStack,
+};
+
+/// Size of an X25519 secret or public key
+pub const X25519_SIZE: usize = 32;
+
+/// Domain separation label for keys derived from a shared secret
+const AGREEMENT_LABEL: &[u8] = b"chacha20stream x25519 v1\0";
+
+/// An X25519 secret key
+///
+/// The secret is cleared from memory when dropped, and is not shown by its `Debug` implementation. Secrets are compared in constant time.
+///
+/// # Encoding
+/// Like `Key`, this type implements `std::fmt::Display` and `std::str::FromStr` as a base64 string.
+/// ```
+/// # use chacha20stream::key::agreement::StaticSecret;
+/// let secret = StaticSecret::new();
+/// let secret_encoded = secret.to_string();
+///
+/// assert_eq!(secret_encoded.parse::<StaticSecret>().unwrap(), secret);
+/// ```
+#[derive(Clone, Default)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(transparent)]
+pub struct StaticSecret([u8; X25519_SIZE]);
+
+/// An X25519 public key, which can be shared freely
+///
+/// # Encoding
+/// Like `Key`, this type implements `std::fmt::Display` and `std::str::FromStr` as a base64 string.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Default, PartialOrd, Ord)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(transparent)]
+pub struct PublicKey([u8; X25519_SIZE]);
+
+impl StaticSecret
+{
+    /// Construct a `StaticSecret` from an exact length (32 bytes) buffer.
+    #[inline] pub fn from_bytes(k: [u8; X25519_SIZE]) -> Self
+    {
+	Self(k)
+    }
+
+    /// Generate a new random X25519 secret.
+    pub fn new() -> Self
+    {
+	let pkey = PKey::generate_x25519().expect("x25519 fatal");
+	let mut raw = pkey.raw_private_key().expect("x25519 fatal");
+
+	let mut output = Self::default();
+	output.0.copy_from_slice(&raw[..]);
+	crate::bytes::prune(&mut raw[..]);
+	output
+    }
+
+    /// The public key for this secret
+    pub fn public_key(&self) -> PublicKey
+    {
+	let mut output = PublicKey::default();
+	let raw = self.to_pkey().and_then(|pkey| pkey.raw_public_key()).expect("x25519 fatal");
+	output.0.copy_from_slice(&raw[..]);
+	output
+    }
+
+    #[inline] fn to_pkey(&self) -> Result<PKey<openssl::pkey::Private>, ErrorStack>
+    {
+	PKey::private_key_from_raw_bytes(&self.0[..], Id::X25519)
+    }
+}
+
+impl PublicKey
+{
+    /// Construct a `PublicKey` from an exact length (32 bytes) buffer.
+    #[inline] pub fn from_bytes(k: [u8; X25519_SIZE]) -> Self
+    {
+	Self(k)
+    }
+
+    /// Format this public key as a hex string
+    ///
+    /// Returns an opaque type that lazily formats the key into a hex string when written.
+    pub fn to_hex_string(&self) -> impl fmt::Display + '_
+    {
+	self.0.iter().copied().into_hex()
+    }
+}
+
+/// Compute the raw X25519 shared secret between `secret` and `peer`.
+fn x25519(secret: &StaticSecret, peer: &PublicKey) -> Result<[u8; X25519_SIZE], ErrorStack>
+{
+    let secret = secret.to_pkey()?;
+    let peer = PKey::public_key_from_raw_bytes(&peer.0[..], Id::X25519)?;
+
+    let mut deriver = Deriver::new(&secret)?;
+    deriver.set_peer(&peer)?;
+
+    let mut output = [0u8; X25519_SIZE];
+    deriver.derive(&mut output[..])?;
+    Ok(output)
+}
+
+/// Agree on a `Key` from our `secret` and the `peer`'s public key.
+///
+/// Both parties derive the same key, which is the X25519 shared secret passed through HKDF-SHA256 along with both public keys.
+///
+/// # Errors
+/// If `peer` is a low-order point, which would make the shared secret all zeros.
+pub fn diffie_hellman(secret: &StaticSecret, peer: &PublicKey) -> Result<Key, ErrorStack>
+{
+    let mut shared = x25519(secret, peer)?;
+
+    // Order the public keys so both sides agree on the info
+    let ours = secret.public_key();
+    let (first, second) = if ours < *peer { (&ours, peer) } else { (peer, &ours) };
+
+    let mut output = Key::default();
+    let res = hkdf::hkdf(&shared[..], None, &[AGREEMENT_LABEL, &first.0[..], &second.0[..]], output.as_mut());
+    crate::bytes::explicit_prune(&mut shared[..]);
+    res.map(move |_| output)
+}
+
+impl Drop for StaticSecret
+{
+    fn drop(&mut self)
+    {
+	crate::bytes::explicit_prune(&mut self.0[..]);
+    }
+}
+
+impl fmt::Debug for StaticSecret
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "StaticSecret(<redacted>)")
+    }
+}
+
+impl PartialEq for StaticSecret
+{
+    #[inline] fn eq(&self, other: &Self) -> bool
+    {
+	openssl::memcmp::eq(&self.0[..], &other.0[..])
+    }
+}
+impl Eq for StaticSecret{}
+
+impl From<[u8; X25519_SIZE]> for StaticSecret
+{
+    #[inline] fn from(from: [u8; X25519_SIZE]) -> Self
+    {
+	Self(from)
+    }
+}
+
+impl From<[u8; X25519_SIZE]> for PublicKey
+{
+    #[inline] fn from(from: [u8; X25519_SIZE]) -> Self
+    {
+	Self(from)
+    }
+}
+
+impl From<&StaticSecret> for PublicKey
+{
+    #[inline] fn from(from: &StaticSecret) -> Self
+    {
+	from.public_key()
+    }
+}
+
+impl AsRef<[u8]> for StaticSecret
+{
+    fn as_ref(&self) -> &[u8]
+    {
+	&self.0[..]
+    }
+}
+
+impl AsRef<[u8]> for PublicKey
+{
+    fn as_ref(&self) -> &[u8]
+    {
+	&self.0[..]
+    }
+}
+
+impl AsMut<[u8]> for StaticSecret
+{
+    fn as_mut(&mut self) -> &mut [u8]
+    {
+	&mut self.0[..]
+    }
+}
+
+impl AsMut<[u8]> for PublicKey
+{
+    fn as_mut(&mut self) -> &mut [u8]
+    {
+	&mut self.0[..]
+    }
+}
+
+impl fmt::Display for StaticSecret
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "{}", base64::encode(&self.0[..]))
+    }
+}
+
+impl fmt::Display for PublicKey
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "{}", base64::encode(&self.0[..]))
+    }
+}
+
+impl str::FromStr for StaticSecret
+{
+    type Err = base64::DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	let mut buffer = Vec::with_capacity(X25519_SIZE);
+	base64::decode_config_buf(s.as_bytes(), base64::STANDARD, &mut buffer)?;
+
+	let mut this = Self::default();
+	let sz = std::cmp::min(X25519_SIZE, buffer.len());
+	this.0[..sz].copy_from_slice(&buffer[..sz]);
+	crate::bytes::prune(&mut buffer[..]);
+	Ok(this)
+    }
+}
+
+impl str::FromStr for PublicKey
+{
+    type Err = base64::DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	let mut buffer = Vec::with_capacity(X25519_SIZE);
+	base64::decode_config_buf(s.as_bytes(), base64::STANDARD, &mut buffer)?;
+
+	let mut this = Self::default();
+	let sz = std::cmp::min(X25519_SIZE, buffer.len());
+	this.0[..sz].copy_from_slice(&buffer[..sz]);
+	Ok(this)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn unhex(s: &str) -> [u8; X25519_SIZE]
+    {
+	let mut output = [0u8; X25519_SIZE];
+	for (i, byte) in output.iter_mut().enumerate() {
+	    *byte = u8::from_str_radix(&s[i*2..i*2+2], 16).unwrap();
+	}
+	output
+    }
+
+    #[test]
+    fn rfc7748_scalar_mult()
+    {
+	// RFC 7748, section 5.2
+	let secret = StaticSecret::from_bytes(unhex("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"));
+	let peer = PublicKey::from_bytes(unhex("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c"));
+	assert_eq!(x25519(&secret, &peer).expect("x25519").hex().to_string(), "c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552");
+    }
+
+    #[test]
+    fn rfc7748_diffie_hellman()
+    {
+	// RFC 7748, section 6.1
+	let alice = StaticSecret::from_bytes(unhex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a"));
+	let bob = StaticSecret::from_bytes(unhex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb"));
+
+	assert_eq!(alice.public_key().to_hex_string().to_string(), "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+	assert_eq!(bob.public_key().to_hex_string().to_string(), "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+
+	const SHARED: &str = "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742";
+	assert_eq!(x25519(&alice, &bob.public_key()).expect("x25519").hex().to_string(), SHARED);
+	assert_eq!(x25519(&bob, &alice.public_key()).expect("x25519").hex().to_string(), SHARED);
+
+	assert_eq!(diffie_hellman(&alice, &bob.public_key()).unwrap(), diffie_hellman(&bob, &alice.public_key()).unwrap());
+    }
+
+    #[test]
+    fn agreement()
+    {
+	let (alice, bob, eve) = (StaticSecret::new(), StaticSecret::new(), StaticSecret::new());
+
+	let key = diffie_hellman(&alice, &bob.public_key()).unwrap();
+	assert_eq!(key, diffie_hellman(&bob, &alice.public_key()).unwrap());
+	assert_ne!(key, diffie_hellman(&eve, &alice.public_key()).unwrap());
+	assert_ne!(key, diffie_hellman(&eve, &bob.public_key()).unwrap());
+
+	// Low-order points are rejected
+	assert!(diffie_hellman(&alice, &PublicKey::default()).is_err());
+    }
+
+    #[test]
+    fn enc_dec()
+    {
+	let secret = StaticSecret::new();
+	let public = secret.public_key();
+
+	assert_eq!(secret.to_string().parse::<StaticSecret>().expect("secret"), secret);
+	assert_eq!(public.to_string().parse::<PublicKey>().expect("public"), public);
+	assert_eq!(PublicKey::from(&secret), public);
+
+	// The secret is never shown
+	assert_eq!(format!("{:?}", secret), "StaticSecret(<redacted>)");
+    }
+}
@@ -0,0 +1,142 @@
+//! Hierarchical key derivation with HKDF-SHA256
+//!
+//! Keys derived from a master `Key` are domain-separated from IVs derived with the same info, and from keys derived for other purposes.
+//! ```
+//! # use chacha20stream::Key;
+//! let master = Key::new();
+//!
+//! let uploads = master.derive_path(&["tenant-7", "uploads", "2026"]);
+//! assert_eq!(uploads, master.derive(b"tenant-7").derive(b"uploads").derive(b"2026"));
+//! ```
+use super::*;
+use openssl::{
+    pkey::Id,
+    pkey_ctx::PkeyCtx,
+    md::Md,
+    error::ErrorStack,
+};
+
+/// Domain separation label for derived keys
+const KEY_LABEL: &[u8] = b"chacha20stream key v1\0";
+/// Domain separation label for derived IVs
+const IV_LABEL: &[u8] = b"chacha20stream iv v1\0";
+
+/// HKDF-SHA256 (RFC 5869), filling `output` from input keying material `ikm`, with an optional `salt` and `info`.
+pub fn hkdf(ikm: &[u8], salt: Option<&[u8]>, info: &[&[u8]], output: &mut [u8]) -> Result<(), ErrorStack>
+{
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(ikm)?;
+    if let Some(salt) = salt {
+	ctx.set_hkdf_salt(salt)?;
+    }
+    for info in info {
+	ctx.add_hkdf_info(info)?;
+    }
+    ctx.derive(Some(output))?;
+    Ok(())
+}
+
+impl Key
+{
+    /// Derive a sub-key from this key for the purpose described by `info`.
+    ///
+    /// The same key and `info` always derive the same sub-key.
+    pub fn derive(&self, info: impl AsRef<[u8]>) -> Self
+    {
+	let mut output = Self::default();
+	hkdf(self.as_ref(), None, &[KEY_LABEL, info.as_ref()], output.as_mut()).expect("hkdf fatal");
+	output
+    }
+
+    /// Derive a sub-key by deriving from this key with each component of `path` in turn.
+    ///
+    /// An empty `path` returns this key.
+    #[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
+    pub fn derive_path<I>(&self, path: I) -> Self
+    where I: IntoIterator,
+	  I::Item: AsRef<[u8]>
+    {
+	path.into_iter().fold(self.clone(), |key, info| key.derive(info))
+    }
+}
+
+impl IV
+{
+    /// Derive a new IV from this IV for the purpose described by `info`.
+    ///
+    /// The same IV and `info` always derive the same new IV.
+    pub fn derive(&self, info: impl AsRef<[u8]>) -> Self
+    {
+	let mut output = Self::default();
+	hkdf(self.as_ref(), None, &[IV_LABEL, info.as_ref()], output.as_mut()).expect("hkdf fatal");
+	output
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8>
+    {
+	(0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i+2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn rfc5869_case_1()
+    {
+	let ikm = [0x0bu8; 22];
+	let salt = unhex("000102030405060708090a0b0c");
+	let info = unhex("f0f1f2f3f4f5f6f7f8f9");
+	let mut okm = [0u8; 42];
+	hkdf(&ikm[..], Some(&salt[..]), &[&info[..]], &mut okm[..]).expect("hkdf");
+	assert_eq!(okm.hex().to_string(), "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865");
+    }
+
+    #[test]
+    fn rfc5869_case_2()
+    {
+	let ikm: Vec<u8> = (0x00..=0x4f).collect();
+	let salt: Vec<u8> = (0x60..=0xaf).collect();
+	let info: Vec<u8> = (0xb0..=0xff).collect();
+	let mut okm = [0u8; 82];
+	// `info` split across calls is concatenated
+	hkdf(&ikm[..], Some(&salt[..]), &[&info[..10], &info[10..]], &mut okm[..]).expect("hkdf");
+	assert_eq!(okm.hex().to_string(), "b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c59045a99cac7827271cb41c65e590e09da3275600c2f09b8367793a9aca3db71cc30c58179ec3e87c14c01d5c1f3434f1d87");
+    }
+
+    #[test]
+    fn rfc5869_case_3()
+    {
+	let ikm = [0x0bu8; 22];
+	let mut okm = [0u8; 42];
+	hkdf(&ikm[..], None, &[], &mut okm[..]).expect("hkdf");
+	assert_eq!(okm.hex().to_string(), "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8");
+    }
+
+    #[test]
+    fn derive()
+    {
+	let master = Key::new();
+
+	assert_eq!(master.derive("uploads"), master.derive(b"uploads"));
+	assert_ne!(master.derive("uploads"), master.derive("downloads"));
+	assert_ne!(master.derive("uploads"), Key::new().derive("uploads"));
+
+	assert_eq!(master.derive_path(&["tenant-7", "uploads"]), master.derive("tenant-7").derive("uploads"));
+	assert_ne!(master.derive_path(&["tenant-7", "uploads"]), master.derive_path(&["tenant-7uploads"]));
+	assert_eq!(master.derive_path(Vec::<&str>::new()), master);
+
+	// Derived keys are domain separated from plain HKDF output
+	let mut plain = Key::default();
+	hkdf(master.as_ref(), None, &[b"uploads"], plain.as_mut()).expect("hkdf");
+	assert_ne!(master.derive("uploads"), plain);
+
+	let iv = IV::new();
+	assert_eq!(iv.derive("uploads"), iv.derive("uploads"));
+	assert_ne!(iv.derive("uploads"), iv.derive("downloads"));
+    }
+}
@@ -0,0 +1,188 @@
+//! Deriving keys from passphrases
+use super::*;
+use crate::error::KdfError;
+use openssl::{
+    pkcs5,
+    hash::MessageDigest,
+};
+
+/// Size of the salt generated by `Key::generate_from_passphrase()`
+pub const SALT_SIZE: usize = 16;
+/// The shortest salt allowed
+pub const MIN_SALT_SIZE: usize = 16;
+
+/// The lowest scrypt `log_n` allowed
+pub const SCRYPT_MIN_LOG_N: u8 = 14;
+/// The highest scrypt `log_n` allowed
+pub const SCRYPT_MAX_LOG_N: u8 = 30;
+/// The lowest scrypt `r` allowed
+pub const SCRYPT_MIN_R: u32 = 8;
+/// The lowest PBKDF2 iteration count allowed
+pub const PBKDF2_MIN_ITERATIONS: u32 = 100_000;
+
+/// Parameters for deriving a `Key` from a passphrase
+///
+/// The default is scrypt with `log_n = 17, r = 8, p = 1`, which uses 128MiB of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature="serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KdfParams
+{
+    /// scrypt, with cost `N = 2^log_n`
+    Scrypt{log_n: u8, r: u32, p: u32},
+    /// PBKDF2 with HMAC-SHA256
+    Pbkdf2Sha256{iterations: u32},
+}
+
+impl Default for KdfParams
+{
+    #[inline]
+    fn default() -> Self
+    {
+	Self::Scrypt{log_n: 17, r: 8, p: 1}
+    }
+}
+
+/// The memory scrypt needs for these parameters, plus some to spare, or `None` if it does not fit in a `u64`.
+fn scrypt_maxmem(n: u64, r: u64, p: u64) -> Option<u64>
+{
+    n.checked_add(p)?.checked_add(2)?
+	.checked_mul(r)?.checked_mul(128)?
+	.checked_add(1024 * 1024)
+}
+
+impl KdfParams
+{
+    /// Check these parameters are in range, and not dangerously low.
+    pub fn validate(&self) -> Result<(), KdfError>
+    {
+	match *self {
+	    Self::Scrypt{log_n, r, p} => {
+		if log_n < SCRYPT_MIN_LOG_N || r < SCRYPT_MIN_R || p < 1 {
+		    return Err(KdfError::WeakParams);
+		}
+		if log_n > SCRYPT_MAX_LOG_N || (r as u64 * p as u64) >= (1 << 30) || scrypt_maxmem(1 << log_n, r as u64, p as u64).is_none() {
+		    return Err(KdfError::InvalidParams);
+		}
+	    },
+	    Self::Pbkdf2Sha256{iterations} if iterations < PBKDF2_MIN_ITERATIONS => return Err(KdfError::WeakParams),
+	    _ => (),
+	}
+	Ok(())
+    }
+
+    /// Derive `output.len()` bytes from `pass` and `salt` with these parameters, without validating them.
+    ///
+    /// # Errors
+    /// If the memory scrypt needs does not fit in a `u64`, `KdfError::InvalidParams` is returned.
+    pub(crate) fn derive(&self, pass: &[u8], salt: &[u8], output: &mut [u8]) -> Result<(), KdfError>
+    {
+	match *self {
+	    Self::Scrypt{log_n, r, p} => {
+		let n = 1u64.checked_shl(log_n.into()).ok_or(KdfError::InvalidParams)?;
+		let (r, p) = (r as u64, p as u64);
+		let maxmem = scrypt_maxmem(n, r, p).ok_or(KdfError::InvalidParams)?;
+		pkcs5::scrypt(pass, salt, n, r, p, maxmem, output)?;
+	    },
+	    Self::Pbkdf2Sha256{iterations} => pkcs5::pbkdf2_hmac(pass, salt, iterations as usize, MessageDigest::sha256(), output)?,
+	}
+	Ok(())
+    }
+}
+
+impl Key
+{
+    /// Derive a `Key` from a passphrase and salt.
+    ///
+    /// The same passphrase, salt, and parameters always produce the same key.
+    ///
+    /// # Errors
+    /// If `params` fails validation (see `KdfParams::validate()`), or `salt` is shorter than `MIN_SALT_SIZE`.
+    pub fn from_passphrase(pass: impl AsRef<[u8]>, salt: impl AsRef<[u8]>, params: &KdfParams) -> Result<Self, KdfError>
+    {
+	let salt = salt.as_ref();
+	if salt.len() < MIN_SALT_SIZE {
+	    return Err(KdfError::ShortSalt);
+	}
+	params.validate()?;
+
+	let mut output = Self::default();
+	params.derive(pass.as_ref(), salt, output.as_mut())?;
+	Ok(output)
+    }
+
+    /// Derive a `Key` from a passphrase and a new random salt.
+    ///
+    /// Returns the key, and the salt, which must be stored alongside `params` to derive the same key again.
+    /// ```
+    /// # use chacha20stream::{Key, key::KdfParams};
+    /// # let params = KdfParams::Scrypt{log_n: 14, r: 8, p: 1};
+    /// let (key, salt) = Key::generate_from_passphrase("hunter2", &params).expect("Failed to derive key");
+    ///
+    /// assert_eq!(Key::from_passphrase("hunter2", &salt, &params).unwrap(), key);
+    /// ```
+    pub fn generate_from_passphrase(pass: impl AsRef<[u8]>, params: &KdfParams) -> Result<(Self, [u8; SALT_SIZE]), KdfError>
+    {
+	let mut salt = [0u8; SALT_SIZE];
+	getrandom(&mut salt[..]).expect("rng fatal");
+	Ok((Self::from_passphrase(pass, &salt[..], params)?, salt))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const SCRYPT_FAST: KdfParams = KdfParams::Scrypt{log_n: SCRYPT_MIN_LOG_N, r: 8, p: 1};
+
+    #[test]
+    fn scrypt_known_answer()
+    {
+	// RFC 7914, section 12
+	let mut output = [0u8; 32];
+	SCRYPT_FAST.derive(b"pleaseletmein", b"SodiumChloride", &mut output[..]).expect("scrypt");
+	assert_eq!(output.hex().to_string(), "7023bdcb3afd7348461c06cd81fd38ebfda8fbba904f8e3ea9b543f6545da1f2");
+    }
+
+    #[test]
+    fn pbkdf2_known_answer()
+    {
+	let mut output = [0u8; 32];
+	KdfParams::Pbkdf2Sha256{iterations: 4096}.derive(b"password", b"salt", &mut output[..]).expect("pbkdf2");
+	assert_eq!(output.hex().to_string(), "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a");
+    }
+
+    #[test]
+    fn deterministic()
+    {
+	let (key, salt) = Key::generate_from_passphrase("hunter2", &SCRYPT_FAST).expect("derive");
+	assert_eq!(Key::from_passphrase("hunter2", &salt[..], &SCRYPT_FAST).unwrap(), key);
+	assert_ne!(Key::from_passphrase("hunter3", &salt[..], &SCRYPT_FAST).unwrap(), key);
+
+	let (key2, salt2) = Key::generate_from_passphrase("hunter2", &SCRYPT_FAST).expect("derive");
+	assert_ne!(salt, salt2);
+	assert_ne!(key, key2);
+    }
+
+    #[test]
+    fn validation()
+    {
+	let salt = [0u8; SALT_SIZE];
+	let weak = |params: KdfParams| matches!(Key::from_passphrase("hunter2", &salt[..], &params), Err(KdfError::WeakParams));
+
+	assert!(weak(KdfParams::Scrypt{log_n: 10, r: 8, p: 1}));
+	assert!(weak(KdfParams::Scrypt{log_n: 17, r: 1, p: 1}));
+	assert!(weak(KdfParams::Scrypt{log_n: 17, r: 8, p: 0}));
+	assert!(weak(KdfParams::Pbkdf2Sha256{iterations: 1000}));
+
+	assert!(matches!(KdfParams::Scrypt{log_n: 40, r: 8, p: 1}.validate(), Err(KdfError::InvalidParams)));
+	// The memory needed overflows a `u64`.
+	assert!(matches!(KdfParams::Scrypt{log_n: SCRYPT_MAX_LOG_N, r: 1 << 29, p: 1}.validate(), Err(KdfError::InvalidParams)));
+	let mut output = [0u8; 32];
+	assert!(matches!(KdfParams::Scrypt{log_n: 64, r: 8, p: 1}.derive(b"hunter2", &salt[..], &mut output[..]), Err(KdfError::InvalidParams)));
+	assert!(matches!(Key::from_passphrase("hunter2", &salt[..8], &SCRYPT_FAST), Err(KdfError::ShortSalt)));
+
+	assert!(KdfParams::default().validate().is_ok());
+	assert!(KdfParams::Pbkdf2Sha256{iterations: PBKDF2_MIN_ITERATIONS}.validate().is_ok());
+    }
+}
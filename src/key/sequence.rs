@@ -0,0 +1,342 @@
+//! Unique IVs from a counter
+//!
+//! An IV must never be used twice with the same `Key`. Random IVs from `IV::new()` are only unlikely to repeat, which stops being good enough after enough messages.
+//! An `IvSequence` instead produces IVs from a fixed prefix and a counter that only goes up, so each IV is unique for as long as the prefix is only used by one sequence.
+//!
+//! # Layout
+//! The first `PREFIX_SIZE` bytes of each IV are the prefix, and the last 8 are the counter as a big-endian integer.
+//!
+//! # Persistence
+//! A sequence created with `IvSequence::persistent()` keeps its high-water mark in a file.
+//! Counters are reserved in blocks: the end of the block is written to the file and synced *before* any counter in it is used, so a sequence reopened after a crash resumes after every counter that could have been used, and never reissues an IV.
+//! The file is replaced atomically, by writing a temporary file next to it and renaming it over the old one, so a crash part-way through a write leaves the previous reservation intact.
+//! While a sequence is open, it holds an exclusive lock on a `.lock` file next to the sequence file, so two sequences (in this process or another) can't issue from the same file at once.
+//! ```
+//! # use chacha20stream::key::sequence::IvSequence;
+//! # let dir = tempfile::tempdir().unwrap();
+//! # let path = dir.path().join("ivs");
+//! let mut seq = IvSequence::persistent(&path, *b"srv1").expect("Failed to open sequence");
+//! let first = seq.next_iv().unwrap();
+//! drop(seq);
+//!
+//! let mut seq = IvSequence::persistent(&path, *b"srv1").expect("Failed to open sequence");
+//! assert_ne!(seq.next_iv().unwrap(), first);
+//! ```
+use super::*;
+pub use crate::error::SequenceError;
+use std::{
+    fs::{self, File, TryLockError},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Size of the fixed prefix of a sequence's IVs
+pub const PREFIX_SIZE: usize = IV_SIZE - 8;
+
+/// How many counters a persistent sequence reserves at a time by default
+pub const DEFAULT_RESERVATION: u64 = 1024;
+
+/// Size of the persisted sequence file: the prefix, then the high-water mark.
+const FILE_SIZE: usize = PREFIX_SIZE + 8;
+
+impl IV
+{
+    /// Create an `IV` from a prefix and a counter.
+    ///
+    /// See `key::sequence` for the layout.
+    pub fn from_counter(prefix: [u8; PREFIX_SIZE], n: u64) -> Self
+    {
+	let mut output = Self::default();
+	output.0[..PREFIX_SIZE].copy_from_slice(&prefix[..]);
+	output.0[PREFIX_SIZE..].copy_from_slice(&n.to_be_bytes()[..]);
+	output
+    }
+
+    /// XOR a counter into the last 8 bytes of this `IV`, big-endian.
+    ///
+    /// This produces a unique IV per counter from a single random or derived base IV, as TLS 1.3 does for its record nonces.
+    pub fn xor_counter(&self, n: u64) -> Self
+    {
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
+	let mut output = self.clone();
+	for (o, c) in output.0[PREFIX_SIZE..].iter_mut().zip(n.to_be_bytes().iter()) {
+	    *o ^= c;
+	}
+	output
+    }
+}
+
+/// A generator of unique IVs from a prefix and a counter
+///
+/// # Exhaustion
+/// A sequence can produce `u64::MAX` IVs. After that, `next_iv()` returns `SequenceError::Exhausted` rather than ever reusing a counter.
+#[derive(Debug)]
+pub struct IvSequence
+{
+    prefix: [u8; PREFIX_SIZE],
+    next: u64,
+    persist: Option<Persist>,
+}
+
+/// The file a sequence reserves counters in
+#[derive(Debug)]
+struct Persist
+{
+    path: PathBuf,
+    /// Held locked for as long as the sequence is open
+    _lock: File,
+    /// No counter at or above this has been reserved
+    reserved: u64,
+    /// How many counters to reserve at a time
+    block: u64,
+}
+
+impl Persist
+{
+    /// Replace the file with one holding `reserved`, and sync it.
+    fn store(&mut self, prefix: &[u8; PREFIX_SIZE], reserved: u64) -> io::Result<()>
+    {
+	let mut bytes = [0u8; FILE_SIZE];
+	bytes[..PREFIX_SIZE].copy_from_slice(&prefix[..]);
+	bytes[PREFIX_SIZE..].copy_from_slice(&reserved.to_be_bytes()[..]);
+
+	let temp = sibling(&self.path, ".tmp");
+	{
+	    let mut file = File::create(&temp)?;
+	    file.write_all(&bytes[..])?;
+	    file.sync_all()?;
+	}
+	fs::rename(&temp, &self.path)?;
+	sync_parent(&self.path)?;
+	self.reserved = reserved;
+	Ok(())
+    }
+}
+
+/// The path of a file next to `path`, with `suffix` appended to its name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf
+{
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
+
+/// Sync the directory containing `path`, so a file created or renamed in it persists.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()>
+{
+    match path.parent() {
+	Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+	_ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories can't be opened to sync them on this platform.
+#[cfg(not(unix))]
+#[inline(always)] fn sync_parent(_: &Path) -> io::Result<()>
+{
+    Ok(())
+}
+
+impl IvSequence
+{
+    /// Create an in-memory sequence with this prefix, starting at 0.
+    ///
+    /// The sequence is lost when dropped. Creating another with the same prefix for the same key will reissue its IVs.
+    #[inline] pub fn new(prefix: [u8; PREFIX_SIZE]) -> Self
+    {
+	Self::starting_at(prefix, 0)
+    }
+
+    /// Create an in-memory sequence with a random prefix, starting at 0.
+    pub fn random() -> Self
+    {
+	let mut prefix = [0u8; PREFIX_SIZE];
+	getrandom(&mut prefix[..]).expect("rng fatal");
+	Self::new(prefix)
+    }
+
+    /// Create an in-memory sequence with this prefix, starting at counter `n`.
+    #[inline] pub fn starting_at(prefix: [u8; PREFIX_SIZE], n: u64) -> Self
+    {
+	Self {
+	    prefix,
+	    next: n,
+	    persist: None,
+	}
+    }
+
+    /// Open or create a sequence persisted to the file at `path`, reserving `DEFAULT_RESERVATION` counters at a time.
+    ///
+    /// If the file exists, the sequence resumes after the last counter reserved in it. Any counters reserved but not used before then are skipped.
+    ///
+    /// # Errors
+    /// If the file cannot be opened or created, is malformed, was created with a different prefix, or is in use by another sequence.
+    #[inline] pub fn persistent(path: impl AsRef<Path>, prefix: [u8; PREFIX_SIZE]) -> Result<Self, SequenceError>
+    {
+	Self::persistent_with_reservation(path, prefix, DEFAULT_RESERVATION)
+    }
+
+    /// Open or create a sequence persisted to the file at `path`, reserving `block` counters at a time.
+    ///
+    /// A larger `block` syncs the file less often, but skips more counters when the sequence is reopened.
+    ///
+    /// # Panics
+    /// If `block` is 0.
+    pub fn persistent_with_reservation(path: impl AsRef<Path>, prefix: [u8; PREFIX_SIZE], block: u64) -> Result<Self, SequenceError>
+    {
+	assert!(block > 0, "reservation block must not be empty");
+
+	let path = path.as_ref().to_owned();
+	let lock = fs::OpenOptions::new().write(true).create(true).truncate(false).open(sibling(&path, ".lock"))?;
+	match lock.try_lock() {
+	    Ok(()) => (),
+	    Err(TryLockError::WouldBlock) => return Err(SequenceError::Locked),
+	    Err(TryLockError::Error(err)) => return Err(err.into()),
+	}
+
+	let bytes = match fs::read(&path) {
+	    Ok(bytes) => bytes,
+	    Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+	    Err(err) => return Err(err.into()),
+	};
+
+	let next = match bytes.len() {
+	    0 => 0,
+	    FILE_SIZE => {
+		if bytes[..PREFIX_SIZE] != prefix[..] {
+		    return Err(SequenceError::PrefixMismatch);
+		}
+		let mut reserved = [0u8; 8];
+		reserved.copy_from_slice(&bytes[PREFIX_SIZE..]);
+		u64::from_be_bytes(reserved)
+	    },
+	    _ => return Err(SequenceError::Corrupt),
+	};
+
+	let mut persist = Persist {
+	    path,
+	    _lock: lock,
+	    reserved: next,
+	    block,
+	};
+	if bytes.is_empty() {
+	    persist.store(&prefix, next)?;
+	}
+	Ok(Self {
+	    prefix,
+	    next,
+	    persist: Some(persist),
+	})
+    }
+
+    /// The prefix of this sequence's IVs
+    #[inline] pub fn prefix(&self) -> &[u8; PREFIX_SIZE]
+    {
+	&self.prefix
+    }
+
+    /// The counter the next IV will be produced from
+    #[inline] pub fn counter(&self) -> u64
+    {
+	self.next
+    }
+
+    /// Is this sequence persisted to a file?
+    #[inline] pub fn is_persistent(&self) -> bool
+    {
+	self.persist.is_some()
+    }
+
+    /// Produce the next IV in the sequence.
+    ///
+    /// For a persistent sequence, this syncs the file first if the counter has not been reserved yet.
+    ///
+    /// # Errors
+    /// If the sequence is exhausted, or reserving the counter fails. The counter is not used if this fails.
+    pub fn next_iv(&mut self) -> Result<IV, SequenceError>
+    {
+	if self.next == u64::MAX {
+	    return Err(SequenceError::Exhausted);
+	}
+	if let Some(persist) = &mut self.persist {
+	    if self.next >= persist.reserved {
+		let reserved = self.next.saturating_add(persist.block);
+		persist.store(&self.prefix, reserved)?;
+	    }
+	}
+	let iv = IV::from_counter(self.prefix, self.next);
+	self.next += 1;
+	Ok(iv)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn from_counter()
+    {
+	let iv = IV::from_counter(*b"abcd", 0x0102);
+	assert_eq!(iv, IV::from_bytes(*b"abcd\0\0\0\0\0\0\x01\x02"));
+
+	let base = IV::from_bytes(*b"0123456789ab");
+	assert_eq!(base.xor_counter(0), base);
+	assert_eq!(base.xor_counter(1), IV::from_bytes(*b"0123456789a\x63"));
+	assert_eq!(base.xor_counter(5).xor_counter(5), base);
+	assert_eq!(IV::default().xor_counter(7), IV::from_counter([0; PREFIX_SIZE], 7));
+    }
+
+    #[test]
+    fn sequence()
+    {
+	let mut seq = IvSequence::new(*b"seq0");
+	for n in 0..10 {
+	    assert_eq!(seq.next_iv().unwrap(), IV::from_counter(*b"seq0", n));
+	}
+	assert_eq!(seq.counter(), 10);
+	assert!(!seq.is_persistent());
+    }
+
+    #[test]
+    fn exhausted()
+    {
+	let mut seq = IvSequence::starting_at(*b"seq0", u64::MAX - 1);
+	assert_eq!(seq.next_iv().unwrap(), IV::from_counter(*b"seq0", u64::MAX - 1));
+	assert!(matches!(seq.next_iv(), Err(SequenceError::Exhausted)));
+	assert!(matches!(seq.next_iv(), Err(SequenceError::Exhausted)));
+    }
+
+    #[test]
+    fn persistent()
+    {
+	let dir = tempfile::tempdir().unwrap();
+	let path = dir.path().join("ivs");
+
+	let mut issued = Vec::new();
+	for _ in 0..3 {
+	    let mut seq = IvSequence::persistent_with_reservation(&path, *b"seq0", 4).expect("persistent");
+	    for _ in 0..5 {
+		issued.push(seq.next_iv().unwrap());
+	    }
+	}
+	// Reopening skips the rest of the reserved block
+	assert_eq!(issued[5], IV::from_counter(*b"seq0", 8));
+
+	let unique: std::collections::HashSet<_> = issued.iter().collect();
+	assert_eq!(unique.len(), issued.len());
+
+	assert!(matches!(IvSequence::persistent(&path, *b"seq1"), Err(SequenceError::PrefixMismatch)));
+
+	// Only one sequence can have the file open at a time
+	let seq = IvSequence::persistent(&path, *b"seq0").expect("persistent");
+	assert!(matches!(IvSequence::persistent(&path, *b"seq0"), Err(SequenceError::Locked)));
+	drop(seq);
+	assert!(!sibling(&path, ".tmp").exists());
+
+	fs::write(&path, b"garbage").unwrap();
+	assert!(matches!(IvSequence::persistent(&path, *b"seq0"), Err(SequenceError::Corrupt)));
+    }
+}
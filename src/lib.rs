@@ -18,6 +18,20 @@ sink.flush().unwrap(); // `flush` also clears the in-memory buffer if there is l
 
 let output_encrypted = sink.into_inner();
 ```
+Authenticating a message with its Poly1305 tag:
+```
+# use chacha20stream::Sink;
+# use std::io::Write;
+# let (key, iv) = chacha20stream::keygen();
+let mut sink = Sink::encrypt(Vec::new(), &key, &iv).expect("Failed to create encryptor");
+sink.write_all(b"Hello world!").unwrap();
+let tag = sink.finish().expect("Failed to finalise encryptor");
+let encrypted = sink.into_inner();
+
+let mut sink = Sink::decrypt(Vec::new(), &key, &iv).expect("Failed to create decryptor");
+sink.write_all(&encrypted[..]).unwrap();
+sink.finish_verify(&tag).expect("Message was tampered with");
+```
 Decrypting a message:
 ```
 # use chacha20stream::{Sink, Key, IV};
@@ -35,17 +49,25 @@ fn decrypt_message_to<W: Write + ?Sized>(output: &mut W, encrypted: &[u8], key:
 # Features
 * **smallvec** - Use `smallvec` crate to store the in-memory buffer on the stack if it's smalle enough (*default*)
 * **async** - Enable `AsyncSink` with tokio 0.2 `AsyncWrite`
+* **tokio1** - Enable `AsyncSink` and `AsyncSource` with tokio 1.x `AsyncWrite` and `AsyncRead`. Can be enabled alongside `async`, in which case they implement both.
+* **futures-io** - Enable `AsyncSink` and `AsyncSource` with the `futures::io` `AsyncWrite` and `AsyncRead` used by async-std and smol. Can be enabled alongside either tokio feature.
 * **explicit_clear** - Explicitly clear in-memory buffer after operations.
-* **serde** - Enable `Key` and `IV` to be de/serialised with Serde.
+* **zeroize** - `Key` and `IV` are not `Copy`, and are explicitly cleared from memory when dropped.
+* **serde** - Enable `Key`, `IV`, `Tag`, `KdfParams`, and the X25519 key types to be de/serialised with Serde.
+
+# Compatibility
+Versions up to 2.2 finalised the cipher after every write, which restarts the chacha20_poly1305 keystream, so each `write()` was encrypted as if it were the start of the stream.
+Since 3.0, the cipher is only finalised once, by `Sink::finish()`. Streams written by older versions in more than one write cannot be decrypted by this version, and vice versa. Data written in a single call is unchanged.
 */
 
 #![cfg_attr(nightly, feature(asm))] 
 
 #![allow(dead_code)]
+#![allow(clippy::tabs_in_doc_comments)]
 
 //extern crate test;
 
-#[cfg(feature="async")] 
+#[cfg(any(feature="async", feature="tokio1", feature="futures-io"))]
 #[macro_use] extern crate pin_project;
 
 #[macro_use] mod ext; #[allow(unused_imports)] use ext::*;
@@ -58,17 +80,27 @@ mod private
 
 pub mod key;
 pub mod cha;
+pub mod error;
+pub mod frame;
+pub mod header;
 mod stream;
 mod bytes;
 
-#[cfg(feature="async")] mod stream_async;
-#[cfg(feature="async")] pub use stream_async::Sink as AsyncSink;
-#[cfg(feature="async")] pub use stream_async::Source as AsyncSource;
+#[cfg(any(feature="async", feature="tokio1", feature="futures-io"))] mod stream_async;
+#[cfg(any(feature="async", feature="tokio1", feature="futures-io"))] pub use stream_async::Sink as AsyncSink;
+#[cfg(any(feature="async", feature="tokio1", feature="futures-io"))] pub use stream_async::Source as AsyncSource;
+#[cfg(any(feature="async", feature="tokio1", feature="futures-io"))] pub use stream_async::Stream as AsyncStream;
 
 pub use stream::Sink;
 pub use stream::Source;
+pub use stream::Stream;
+pub use stream::source::{
+    BufferKind, UseBufferInternal, UseBufferExternal, UseBufferPool, DefaultBuffer,
+};
+pub use stream::pool::BufferPool;
+pub use stream::decrypt_range;
 pub use key::{
-    Key, IV,
+    Key, IV, Tag,
 };
 
 pub use cha::keygen;
@@ -0,0 +1,198 @@
+//! Syncronous two-way stream componant.
+use super::*;
+
+/// Two-way ChaCha stream.
+/// Encrypts data written to it to the inner stream, and decrypts data read from the inner stream.
+///
+/// Each direction has its own `Crypter`, created from its own key and IV, so the two ends of a connection can each write with the key the other reads with.
+/// ```
+/// # use chacha20stream::Stream;
+/// # use std::io::{Cursor, Read, Write};
+/// # let (ours, theirs) = (chacha20stream::keygen(), chacha20stream::keygen());
+/// let mut a = Stream::new(Cursor::new(Vec::new()), &ours.0, &ours.1, &theirs.0, &theirs.1).expect("Failed to create stream");
+/// a.write_all(b"Hello world!").unwrap();
+/// a.flush().unwrap();
+///
+/// // The other end reads with the key and IV `a` writes with.
+/// let sent = a.into_inner().into_inner();
+/// let mut b = Stream::new(Cursor::new(sent), &theirs.0, &theirs.1, &ours.0, &ours.1).expect("Failed to create stream");
+///
+/// let mut output = [0u8; 12];
+/// b.read_exact(&mut output[..]).unwrap();
+/// assert_eq!(&output[..], b"Hello world!");
+/// ```
+///
+/// # Splitting
+/// A stream can be split into a `Source` and a `Sink` that carry on reading and writing with its crypters, so each direction can be used from a different thread.
+/// `split()` does this for streams that can be shared by cloning (such as `&TcpStream`), and `split_with()` for anything else.
+///
+/// # Note
+/// Writes behave as they do for `Sink`: the write buffer is only cleared by `flush()`, and `prune()` clears both buffers.
+//#[derive(Debug)]
+pub struct Stream<S>
+{
+    sink: Sink<S>, // Owns the inner stream, and handles writes.
+    crypter: Crypter, // Decrypts reads.
+    buffer: BufferVec, // Raw bytes read from the inner stream.
+}
+
+impl<S: fmt::Debug + Read + Write> fmt::Debug for Stream<S>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "Stream({:?}, ({} buffer cap))", self.sink.inner(), self.buffer.capacity())
+    }
+}
+
+impl<S> Stream<S>
+where S: Read + Write
+{
+    /// Create a new two-way Chacha stream wrapper, encrypting writes with `write_key` and `write_iv`, and decrypting reads with `read_key` and `read_iv`.
+    pub fn new(stream: S, write_key: impl AsRef<Key>, write_iv: impl AsRef<IV>, read_key: impl AsRef<Key>, read_iv: impl AsRef<IV>) -> Result<Self, Error>
+    {
+	Ok(Self::from_parts(stream, cha::encrypter(write_key, write_iv)?, cha::decrypter(read_key, read_iv)?))
+    }
+
+    /// Create a stream from an inner stream, the crypter for writes, and the crypter for reads.
+    ///
+    /// The counterpart to `into_parts()`.
+    #[inline] pub fn from_parts(stream: S, encrypter: Crypter, decrypter: Crypter) -> Self
+    {
+	Self {
+	    sink: Sink::from_parts(stream, encrypter),
+	    crypter: decrypter,
+	    buffer: BufferVec::new(),
+	}
+    }
+
+    /// Consume into the inner stream
+    ///
+    /// Any pending written data not yet written to the inner stream is lost. Call `flush()` first to write it.
+    #[inline] pub fn into_inner(self) -> S
+    {
+	self.sink.into_inner()
+    }
+
+    /// Consume into the inner stream, the crypter for writes, and the crypter for reads.
+    ///
+    /// Any pending written data not yet written to the inner stream is lost. Call `flush()` first to write it.
+    #[inline] pub fn into_parts(self) -> (S, Crypter, Crypter)
+    {
+	let (stream, encrypter) = self.sink.into_parts();
+	(stream, encrypter, self.crypter)
+    }
+
+    /// Split into a `Source` that decrypts reads from the inner stream, and a `Sink` that encrypts writes to a clone of it.
+    ///
+    /// Any pending written data is kept by the `Sink`.
+    #[inline] pub fn split(self) -> (Source<S>, Sink<S>)
+    where S: Clone
+    {
+	self.split_with(|stream| (stream.clone(), stream))
+    }
+
+    /// Split into a `Source` that decrypts reads from, and a `Sink` that encrypts writes to, the read and write halves `f` splits the inner stream into.
+    ///
+    /// Any pending written data is kept by the `Sink`.
+    /// ```
+    /// # use chacha20stream::Stream;
+    /// # use std::{io::Write, net::{TcpListener, TcpStream}};
+    /// # let (key, iv) = chacha20stream::keygen();
+    /// # let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    /// # let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    /// let stream = Stream::new(stream, &key, &iv, &key, &iv).expect("Failed to create stream");
+    /// let (source, mut sink) = stream.split_with(|stream| (stream.try_clone().expect("Failed to clone socket"), stream));
+    ///
+    /// std::thread::spawn(move || sink.write_all(b"Hello world!"));
+    /// ```
+    pub fn split_with<R, W, F>(self, f: F) -> (Source<R>, Sink<W>)
+    where F: FnOnce(S) -> (R, W),
+	  R: Read,
+	  W: Write
+    {
+	let mut read = None;
+	let sink = self.sink.map_inner(|stream| {
+	    let (r, w) = f(stream);
+	    read = Some(r);
+	    w
+	});
+	(Source::from_parts(read.expect("split_with() callback was not called"), self.crypter), sink)
+    }
+}
+
+impl<S> Stream<S>
+where S: Read + Write
+{
+    /// The crypter used to encrypt writes
+    #[inline] pub fn encrypter(&self) -> &Crypter
+    {
+	self.sink.crypter()
+    }
+
+    /// The crypter used to encrypt writes
+    #[inline] pub fn encrypter_mut(&mut self) -> &mut Crypter
+    {
+	self.sink.crypter_mut()
+    }
+
+    /// The crypter used to decrypt reads
+    #[inline] pub fn decrypter(&self) -> &Crypter
+    {
+	&self.crypter
+    }
+
+    /// The crypter used to decrypt reads
+    #[inline] pub fn decrypter_mut(&mut self) -> &mut Crypter
+    {
+	&mut self.crypter
+    }
+
+    /// The inner stream
+    #[inline] pub fn inner(&self) -> &S
+    {
+	self.sink.inner()
+    }
+
+    /// The inner stream
+    #[inline] pub fn inner_mut(&mut self) -> &mut S
+    {
+	self.sink.inner_mut()
+    }
+
+    /// Clear the internal buffers while keeping them allocated for further use.
+    ///
+    /// Pending written data that has not been written to the inner stream yet is kept.
+    #[inline]
+    pub fn prune(&mut self)
+    {
+	self.sink.prune();
+	bytes::prune(&mut self.buffer[..]);
+    }
+}
+
+impl<S: Read + Write> Read for Stream<S>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+	if buf.len() > self.buffer.len() {
+	    self.buffer.resize(buf.len(), 0);
+	}
+	let read = self.sink.inner_mut().read(&mut self.buffer[..buf.len()])?;
+	let n = self.crypter.update(&self.buffer[..read], &mut buf[..read])?;
+
+	#[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut self.buffer[..read]);
+	Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for Stream<S>
+{
+    #[inline] fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	self.sink.write(buf)
+    }
+    #[inline] fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+	self.sink.write_vectored(bufs)
+    }
+    #[inline] fn flush(&mut self) -> io::Result<()> {
+	self.sink.flush()
+    }
+}
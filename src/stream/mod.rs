@@ -20,24 +20,30 @@ type BufferVec = smallvec::SmallVec<[u8; BUFFER_SIZE]>;
 type BufferVec = Vec<u8>;
 
 pub type Error = ErrorStack;
+use crate::error::AuthError;
+use crate::header::Header;
 
 pub mod sink;
 pub mod source;
+pub mod duplex;
+pub mod pool;
 
 pub use sink::Sink;
 pub use source::Source;
-
-//TODO: Stream: Wrapper over both Sink and Source?
+pub use source::decrypt_range;
+pub use duplex::Stream;
 
 #[cfg(test)]
+#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
 mod tests
 {
     use super::*;
+    use source::{UseBufferInternal, UseBufferExternal, UseBufferPool};
     use std::io::Cursor;
 
-    const INPUT: &'static str = "Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!";
+    const INPUT: &str = "Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!";
 
-    fn enc_stream(input: impl AsRef<[u8]>, key: Key, iv: IV) -> Sink<Vec<u8>>
+    fn enc_stream(input: impl AsRef<[u8]>, key: &Key, iv: &IV) -> Sink<Vec<u8>>
     {
 	let enc_buffer = Vec::new();
 	let input = input.as_ref();
@@ -54,7 +60,7 @@ mod tests
     }
 
     /// Create a source from a slice of bytes with this key and IV.
-    fn create_source<'a, T: ?Sized + AsRef<[u8]> +'a>(source: &'a T, key: Key, iv: IV, enc: bool) -> Source<Cursor<&'a [u8]>>
+    fn create_source<'a, T: ?Sized + AsRef<[u8]> +'a>(source: &'a T, key: &Key, iv: &IV, enc: bool) -> Source<Cursor<&'a [u8]>>
     {
 	eprintln!("({}) Key: {}, IV: {}, Input: ({}: {})", ["dec", "enc"][enc as usize], key, iv, source.as_ref().len(), source.as_ref().hex());
 	
@@ -72,7 +78,7 @@ mod tests
     {
 	let (key, iv) = cha::keygen();
 
-	eprintln!("Sink ends: {:?}", enc_stream(INPUT.as_bytes(), key, iv));
+	eprintln!("Sink ends: {:?}", enc_stream(INPUT.as_bytes(), &key, &iv));
     }
 
     #[test]
@@ -80,10 +86,10 @@ mod tests
     {
 	let (key, iv) = cha::keygen();
 
-	const INPUT: &'static [u8] = b"Hello world!";
+	const INPUT: &[u8] = b"Hello world!";
 	println!("Input ({} bytes, hex): {}", INPUT.len(), INPUT.hex());
 
-	let mut source = create_source(INPUT, key, iv, true);
+	let mut source = create_source(INPUT, &key, &iv, true);
 
 	let mut output = Vec::with_capacity(INPUT.len());
 	io::copy(&mut source, &mut output).expect("Failed to copy source to output");
@@ -97,10 +103,10 @@ mod tests
     {
 	let (key, iv) = cha::keygen();
 
-	const INPUT: &'static [u8] = b"Hello world!";
+	const INPUT: &[u8] = b"Hello world!";
 	println!("Input ({} bytes, hex): {}", INPUT.len(), INPUT.hex());
 
-	let mut source = create_source(INPUT, key, iv, true);
+	let mut source = create_source(INPUT, &key, &iv, true);
 
 	let mut temp = Vec::with_capacity(INPUT.len());
 	io::copy(&mut source, &mut temp).expect("Failed to copy source to output (encrypt)");
@@ -109,7 +115,7 @@ mod tests
 
 	// decrypt
 
-	let mut source = create_source(&mut temp, key, iv, false);
+	let mut source = create_source(&temp, &key, &iv, false);
 
 	let mut temp = Vec::with_capacity(INPUT.len());
 	io::copy(&mut source, &mut temp).expect("Failed to copy source to output (decrypt)");
@@ -119,6 +125,119 @@ mod tests
 	assert_eq!(INPUT, &temp[..]);
     }
     
+    /// A reader that only returns up to `.1` bytes at a time.
+    struct Trickle<R>(R, usize);
+
+    impl<R: Read> Read for Trickle<R>
+    {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+	    let sz = std::cmp::min(buf.len(), self.1);
+	    self.0.read(&mut buf[..sz])
+	}
+    }
+
+    #[test]
+    fn source_tagged()
+    {
+	let (key, iv) = cha::keygen();
+
+	let mut temp = Vec::new();
+	Source::<_, UseBufferInternal>::encrypt_tagged(Trickle(INPUT.as_bytes(), 5), &key, &iv).expect("source::enc").read_to_end(&mut temp).expect("Failed to read encrypted");
+	assert_eq!(temp.len(), INPUT.len() + TAG_SIZE);
+
+	// The appended tag is the same one `Sink` produces.
+	let mut sink = enc_stream(INPUT.as_bytes(), &key, &iv);
+	let tag = sink.finish().expect("sink::finish");
+	assert_eq!(&temp[..INPUT.len()], &sink.inner()[..]);
+	assert_eq!(&temp[INPUT.len()..], tag.as_ref());
+
+	for &chunk in &[1, 7, TAG_SIZE, 4096] {
+	    let mut output = Vec::new();
+	    Source::<_, UseBufferInternal>::decrypt_tagged(Trickle(&temp[..], chunk), &key, &iv).expect("source::dec")
+		.read_to_end(&mut output).expect("Failed to read decrypted (internal)");
+	    assert_eq!(&output[..], INPUT.as_bytes());
+
+	    let mut output = Vec::new();
+	    Source::<_, UseBufferExternal>::decrypt_tagged(Trickle(&temp[..], chunk), &key, &iv).expect("source::dec")
+		.read_to_end(&mut output).expect("Failed to read decrypted (external)");
+	    assert_eq!(&output[..], INPUT.as_bytes());
+	}
+    }
+
+    #[test]
+    fn source_tagged_mismatch()
+    {
+	let (key, iv) = cha::keygen();
+
+	let mut temp = Vec::new();
+	Source::<_, UseBufferExternal>::encrypt_tagged(INPUT.as_bytes(), &key, &iv).expect("source::enc").read_to_end(&mut temp).expect("Failed to read encrypted");
+
+	let check = |input: &[u8]| {
+	    let mut source: Source<_> = Source::decrypt_tagged(input, &key, &iv).expect("source::dec");
+	    let err = source.read_to_end(&mut Vec::new()).expect_err("Bad input was verified");
+	    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	};
+
+	let mut tampered = temp.clone();
+	tampered[10] ^= 0x01;
+	check(&tampered[..]);
+
+	let mut tampered = temp.clone();
+	*tampered.last_mut().unwrap() ^= 0x01;
+	check(&tampered[..]);
+
+	check(&temp[..temp.len() - 1]);
+	check(&temp[..TAG_SIZE - 1]);
+    }
+
+    #[test]
+    fn aad()
+    {
+	const AAD: &[u8] = b"routing header";
+	let (key, iv) = cha::keygen();
+
+	let mut stream = Sink::encrypt_with_aad(Vec::new(), &key, &iv, AAD).expect("sink::enc");
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	let tag = stream.finish().expect("sink::finish");
+	let input = stream.into_inner();
+
+	// The AAD is not written, and the ciphertext is unchanged by it.
+	assert_eq!(&input[..], &enc_stream(INPUT.as_bytes(), &key, &iv).inner()[..]);
+
+	let mut stream = Sink::decrypt_with_aad(Vec::new(), &key, &iv, AAD).expect("sink::dec");
+	stream.write_all(&input[..]).unwrap();
+	stream.finish_verify(&tag).expect("sink::finish_verify");
+	assert_eq!(&stream.inner()[..], INPUT.as_bytes());
+
+	let mut stream = Sink::decrypt_with_aad(Vec::new(), &key, &iv, b"routing heade\0").expect("sink::dec");
+	stream.write_all(&input[..]).unwrap();
+	assert!(stream.finish_verify(&tag).unwrap_err().is_mismatch());
+
+	let mut stream = Sink::decrypt(Vec::new(), &key, &iv).expect("sink::dec");
+	stream.write_all(&input[..]).unwrap();
+	assert!(stream.finish_verify(&tag).unwrap_err().is_mismatch());
+    }
+
+    #[test]
+    fn source_aad()
+    {
+	const AAD: &[u8] = b"routing header";
+	let (key, iv) = cha::keygen();
+
+	let mut temp = Vec::new();
+	let mut source: Source<_> = Source::encrypt_with_aad(INPUT.as_bytes(), &key, &iv, AAD).expect("source::enc");
+	source.read_to_end(&mut temp).expect("Failed to read encrypted");
+
+	let mut output = Vec::new();
+	let mut source: Source<_> = Source::decrypt_with_aad(&temp[..], &key, &iv, AAD).expect("source::dec");
+	source.read_to_end(&mut output).expect("Failed to read decrypted");
+	assert_eq!(&output[..], INPUT.as_bytes());
+
+	let mut source: Source<_> = Source::decrypt_with_aad(&temp[..], &key, &iv, &AAD[1..]).expect("source::dec");
+	let err = source.read_to_end(&mut Vec::new()).expect_err("Wrong AAD was verified");
+	assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn dec()
     {
@@ -126,11 +245,11 @@ mod tests
 	let (key, iv) = cha::keygen();
 	eprintln!("Input unencrypted: {}", INPUT.hex());
 
-	let input = enc_stream(INPUT.as_bytes(), key.clone(), iv.clone()).into_inner();
+	let input = enc_stream(INPUT.as_bytes(), &key, &iv).into_inner();
 
 	let mut dec_buffer = Vec::new();
 	{
-	    let mut stream = Sink::decrypt(&mut dec_buffer, key, iv).expect("sink::dec");
+	    let mut stream = Sink::decrypt(&mut dec_buffer, &key, &iv).expect("sink::dec");
 
 	    stream.write_all(&input[..]).unwrap();
 	    stream.flush().unwrap();
@@ -140,6 +259,626 @@ mod tests
 	assert_eq!(&dec_buffer[..], INPUT.as_bytes());
     }
     
+    #[test]
+    fn split_writes()
+    {
+	let (key, iv) = cha::keygen();
+	let whole = enc_stream(INPUT.as_bytes(), &key, &iv).into_inner();
+
+	let mut stream = Sink::encrypt(Vec::new(), &key, &iv).expect("sink::enc");
+	for chunk in INPUT.as_bytes().chunks(7) {
+	    stream.write_all(chunk).unwrap();
+	}
+	stream.flush().unwrap();
+
+	assert_eq!(&stream.into_inner()[..], &whole[..]);
+    }
+
+    /// A writer that accepts at most `max` bytes per call, and fails with `WouldBlock` every `fail_every`th call.
+    struct ShortWriter
+    {
+	inner: Vec<u8>,
+	max: usize,
+	fail_every: usize,
+	calls: usize,
+    }
+
+    impl ShortWriter
+    {
+	fn new(max: usize, fail_every: usize) -> Self
+	{
+	    Self { inner: Vec::new(), max, fail_every, calls: 0 }
+	}
+    }
+
+    impl Write for ShortWriter
+    {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	    self.calls += 1;
+	    if self.calls == self.fail_every {
+		self.calls = 0;
+		return Err(io::ErrorKind::WouldBlock.into());
+	    }
+	    let n = std::cmp::min(buf.len(), self.max);
+	    self.inner.extend_from_slice(&buf[..n]);
+	    Ok(n)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn sink_vectored()
+    {
+	use std::io::IoSlice;
+	let (key, iv) = cha::keygen();
+	let whole = enc_stream(INPUT.as_bytes(), &key, &iv).into_inner();
+	let bufs = [IoSlice::new(&INPUT.as_bytes()[..5]), IoSlice::new(&[]), IoSlice::new(&INPUT.as_bytes()[5..40]), IoSlice::new(&INPUT.as_bytes()[40..])];
+
+	// All the slices are forwarded in one inner write
+	let mut stream = Sink::encrypt(ShortWriter::new(usize::MAX, 0), &key, &iv).expect("sink::enc");
+	assert_eq!(stream.write_vectored(&bufs[..]).unwrap(), INPUT.len());
+	assert_eq!(stream.inner().calls, 1);
+	assert_eq!(&stream.inner().inner[..], &whole[..]);
+
+	// Only what the inner stream takes is reported, the rest is pending as usual
+	let mut stream = Sink::encrypt(ShortWriter::new(7, 3), &key, &iv).expect("sink::enc");
+	assert_eq!(stream.write_vectored(&bufs[..]).unwrap(), 7);
+	let mut input = &INPUT.as_bytes()[7..];
+	while !input.is_empty() {
+	    let n = retry(|| stream.write(input)).expect("write");
+	    input = &input[n..];
+	}
+	assert_eq!(&stream.into_inner().inner[..], &whole[..]);
+    }
+
+    #[test]
+    fn sink_max_buffer()
+    {
+	use std::io::IoSlice;
+	let (key, iv) = cha::keygen();
+	let input = INPUT.repeat(20);
+	let whole = enc_stream(input.as_bytes(), &key, &iv).into_inner();
+
+	let mut stream = Sink::encrypt(Vec::new(), &key, &iv).expect("sink::enc").with_max_buffer(64);
+	assert_eq!(stream.write(input.as_bytes()).unwrap(), 64);
+	stream.write_all(&input.as_bytes()[64..]).unwrap();
+	assert!(stream.buffer.len() <= 64);
+	assert_eq!(&stream.inner()[..], &whole[..]);
+
+	// Vectored writes stop partway through a slice at the limit
+	let mut stream = Sink::encrypt(Vec::new(), &key, &iv).expect("sink::enc").with_max_buffer(10);
+	let bufs = [IoSlice::new(&input.as_bytes()[..4]), IoSlice::new(&input.as_bytes()[4..20])];
+	assert_eq!(stream.write_vectored(&bufs[..]).unwrap(), 10);
+	stream.write_all(&input.as_bytes()[10..]).unwrap();
+	assert_eq!(&stream.inner()[..], &whole[..]);
+
+	// A buffer that has already grown is shrunk to the limit
+	let mut stream = Sink::encrypt(ShortWriter::new(100, 0), &key, &iv).expect("sink::enc");
+	stream.write_all(input.as_bytes()).unwrap();
+	stream.set_max_buffer(32);
+	assert!(stream.buffer.len() <= 32);
+	assert_eq!(stream.max_buffer(), Some(32));
+	stream.flush().unwrap();
+	assert_eq!(&stream.inner().inner[..], &whole[..]);
+    }
+
+    #[test]
+    fn sink_buffered()
+    {
+	use std::io::IoSlice;
+	let (key, iv) = cha::keygen();
+	let input = INPUT.repeat(4);
+	let mut whole = enc_stream(input.as_bytes(), &key, &iv);
+	let tag = whole.finish().expect("sink::finish");
+	let whole = whole.into_inner();
+
+	let mut stream = Sink::encrypt(ShortWriter::new(usize::MAX, 0), &key, &iv).expect("sink::enc").buffered(64);
+	let mut rest = input.as_bytes();
+	let mut sizes = (1..=8).cycle();
+	while !rest.is_empty() {
+	    let n = std::cmp::min(sizes.next().unwrap(), rest.len());
+	    assert_eq!(stream.write(&rest[..n]).unwrap(), n);
+	    rest = &rest[n..];
+	}
+	// Only full batches have been written so far
+	assert!(stream.inner().calls <= input.len() / 56);
+	assert!(stream.inner().inner.len() < input.len());
+
+	// Writes as large as the buffer go straight through, after what was buffered before them
+	assert_eq!(stream.write_vectored(&[IoSlice::new(&[]), IoSlice::new(&input.as_bytes()[..64])]).unwrap(), 64);
+	assert_eq!(stream.inner().inner.len(), input.len() + 64);
+	stream.write_all(&input.as_bytes()[64..70]).unwrap();
+
+	// Sealed plaintext is cleared, including the spare capacity
+	stream.flush().unwrap();
+	assert_eq!(stream.plain.len(), 0);
+	assert!(unsafe { std::slice::from_raw_parts(stream.plain.as_ptr(), stream.plain.capacity()) }.iter().all(|&b| b == 0));
+
+	let output = stream.into_inner().inner;
+	assert_eq!(&output[..input.len()], &whole[..]);
+
+	// Buffered plaintext is included in the tag
+	let mut stream = Sink::encrypt(Vec::new(), &key, &iv).expect("sink::enc").buffered(1024);
+	stream.write_all(input.as_bytes()).unwrap();
+	assert!(stream.inner().is_empty());
+	assert_eq!(stream.finish_append().unwrap(), tag);
+	assert_eq!(&stream.inner()[..input.len()], &whole[..]);
+
+	let mut stream = Sink::decrypt(Vec::new(), &key, &iv).expect("sink::dec").buffered(16).with_max_buffer(8);
+	stream.write_all(&whole[..]).unwrap();
+	stream.finish_verify(&tag).expect("sink::finish_verify");
+	stream.flush().unwrap();
+	assert_eq!(&stream.inner()[..], input.as_bytes());
+    }
+
+    /// Retry `op` until it doesn't return `WouldBlock`, as a non-blocking caller would.
+    fn retry<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T>
+    {
+	loop {
+	    match op() {
+		Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+		res => return res,
+	    }
+	}
+    }
+
+    #[test]
+    fn partial_writes()
+    {
+	let (key, iv) = cha::keygen();
+	let whole = enc_stream(INPUT.as_bytes(), &key, &iv).into_inner();
+
+	for &(max, fail_every) in &[(1, 2), (3, 3), (7, 2), (64, 5), (1000, 2)] {
+	    let mut stream = Sink::encrypt(ShortWriter::new(max, fail_every), &key, &iv).expect("sink::enc");
+	    let mut input = INPUT.as_bytes();
+	    while !input.is_empty() {
+		let n = retry(|| stream.write(&input[..std::cmp::min(input.len(), 10)])).expect("write");
+		input = &input[n..];
+	    }
+	    retry(|| stream.flush()).expect("flush");
+	    let tag = stream.finish().expect("sink::finish");
+
+	    let output = stream.into_inner().inner;
+	    assert_eq!(&output[..], &whole[..], "max: {}, fail_every: {}", max, fail_every);
+
+	    let mut stream = Sink::decrypt(Vec::new(), &key, &iv).expect("sink::dec");
+	    stream.write_all(&output[..]).unwrap();
+	    stream.finish_verify(&tag).expect("sink::finish_verify");
+	    assert_eq!(&stream.into_inner()[..], INPUT.as_bytes());
+	}
+    }
+
+    /// A writer that accepts `room` more bytes, then fails with `BrokenPipe`.
+    struct BreakableWriter
+    {
+	inner: Vec<u8>,
+	room: usize,
+    }
+
+    impl Write for BreakableWriter
+    {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	    if self.room == 0 {
+		return Err(io::ErrorKind::BrokenPipe.into());
+	    }
+	    let n = std::cmp::min(buf.len(), self.room);
+	    self.inner.extend_from_slice(&buf[..n]);
+	    self.room -= n;
+	    Ok(n)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn write_errors()
+    {
+	let (key, iv) = cha::keygen();
+	let whole = enc_stream(INPUT.as_bytes(), &key, &iv).into_inner();
+	let (first, rest) = INPUT.as_bytes().split_at(20);
+
+	let mut stream = Sink::encrypt(BreakableWriter { inner: Vec::new(), room: 8 }, &key, &iv).expect("sink::enc");
+	// Only what the inner stream took is reported as written, the rest of the ciphertext is kept pending.
+	assert_eq!(stream.write(first).unwrap(), 8);
+	assert_eq!(stream.inner().inner.len(), 8);
+
+	// The pending data cannot be written, so retrying fails, and nothing is transformed again.
+	assert_eq!(stream.write(&first[8..]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+	assert_eq!(stream.flush().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+	assert_eq!(stream.finish_append().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+	stream.prune();
+
+	stream.inner_mut().room = usize::MAX;
+	stream.write_all(&first[8..]).unwrap();
+	stream.write_all(rest).unwrap();
+	stream.flush().unwrap();
+	assert_eq!(&stream.into_inner().inner[..], &whole[..]);
+
+	// A write the inner stream takes none of fails, and is written when retried
+	let mut stream = Sink::encrypt(BreakableWriter { inner: Vec::new(), room: 0 }, &key, &iv).expect("sink::enc");
+	assert_eq!(stream.write(first).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+	stream.inner_mut().room = usize::MAX;
+	stream.write_all(INPUT.as_bytes()).unwrap();
+	assert_eq!(&stream.inner().inner[..], &whole[..]);
+
+	// Pending data written by `flush()` is reported by the retry, without writing it again
+	let mut stream = Sink::encrypt(BreakableWriter { inner: Vec::new(), room: 8 }, &key, &iv).expect("sink::enc");
+	assert_eq!(stream.write(first).unwrap(), 8);
+	stream.inner_mut().room = usize::MAX;
+	stream.flush().unwrap();
+	assert_eq!(stream.inner().inner.len(), first.len());
+	assert_eq!(stream.write(&first[8..]).unwrap(), first.len() - 8);
+	assert_eq!(stream.inner().inner.len(), first.len());
+	stream.write_all(rest).unwrap();
+	assert_eq!(&stream.into_inner().inner[..], &whole[..]);
+    }
+
+    #[test]
+    fn tag()
+    {
+	let (key, iv) = cha::keygen();
+	let mut stream = enc_stream(INPUT.as_bytes(), &key, &iv);
+	let tag = stream.finish().expect("sink::finish");
+	let input = stream.into_inner();
+
+	eprintln!("Tag: {}", tag);
+	
+	let mut stream = Sink::decrypt(Vec::new(), &key, &iv).expect("sink::dec");
+	stream.write_all(&input[..]).unwrap();
+	stream.finish_verify(&tag).expect("sink::finish_verify");
+
+	assert_eq!(&stream.into_inner()[..], INPUT.as_bytes());
+    }
+
+    #[test]
+    fn tag_appended()
+    {
+	let (key, iv) = cha::keygen();
+	let mut stream = enc_stream(INPUT.as_bytes(), &key, &iv);
+	let tag = stream.finish_append().expect("sink::finish_append");
+	let input = stream.into_inner();
+
+	assert_eq!(input.len(), INPUT.len() + TAG_SIZE);
+	assert_eq!(&input[INPUT.len()..], tag.as_ref());
+    }
+
+    #[test]
+    fn tag_finished()
+    {
+	let (key, iv) = cha::keygen();
+	let mut stream = enc_stream(INPUT.as_bytes(), &key, &iv);
+	let tag = stream.finish_append().expect("sink::finish_append");
+	assert!(stream.is_finished());
+
+	// Finishing resets the keystream, so writing any more would reuse it
+	assert_eq!(stream.write(INPUT.as_bytes()).unwrap_err().kind(), io::ErrorKind::Other);
+	assert!(matches!(stream.finish(), Err(AuthError::Finished)));
+	assert!(stream.finish_append().is_err());
+	assert_eq!(stream.inner().len(), INPUT.len() + TAG_SIZE);
+
+	let mut stream = Sink::decrypt(Vec::new(), &key, &iv).expect("sink::dec");
+	stream.write_all(&enc_stream(INPUT.as_bytes(), &key, &iv).into_inner()[..]).unwrap();
+	stream.finish_verify(&tag).expect("sink::finish_verify");
+	assert!(matches!(stream.finish_verify(&tag), Err(AuthError::Finished)));
+	assert!(stream.write_vectored(&[io::IoSlice::new(INPUT.as_bytes())]).is_err());
+    }
+
+    #[test]
+    fn tag_mismatch()
+    {
+	let (key, iv) = cha::keygen();
+	let mut stream = enc_stream(INPUT.as_bytes(), &key, &iv);
+	let tag = stream.finish().expect("sink::finish");
+	let mut input = stream.into_inner();
+	input[3] ^= 0x80;
+
+	let mut stream = Sink::decrypt(Vec::new(), &key, &iv).expect("sink::dec");
+	stream.write_all(&input[..]).unwrap();
+	let err = stream.finish_verify(&tag).expect_err("tampered data was verified");
+	assert!(err.is_mismatch());
+
+	let mut stream = Sink::decrypt(Vec::new(), &key, &iv).expect("sink::dec");
+	stream.write_all(&input[..]).unwrap();
+	let err: io::Error = stream.finish_verify(&Tag::default()).unwrap_err().into();
+	assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn source_seek()
+    {
+	use std::io::{Seek, SeekFrom};
+	let (key, iv) = cha::keygen();
+	let input = INPUT.repeat(4);
+	let encrypted = enc_stream(input.as_bytes(), &key, &iv).into_inner();
+
+	let mut source: Source<_> = Source::decrypt(Cursor::new(&encrypted[..]), &key, &iv).expect("source::dec");
+	for &offset in &[0usize, 1, 63, 64, 65, 200, 129, 7, input.len() - 1, input.len()] {
+	    assert_eq!(source.seek(SeekFrom::Start(offset as u64)).unwrap(), offset as u64);
+	    let mut output = Vec::new();
+	    source.read_to_end(&mut output).expect("read");
+	    assert_eq!(&output[..], &input.as_bytes()[offset..]);
+	}
+
+	source.seek(SeekFrom::End(-10)).unwrap();
+	source.seek(SeekFrom::Current(-90)).unwrap();
+	let mut output = [0u8; 10];
+	source.read_exact(&mut output[..]).unwrap();
+	assert_eq!(&output[..], &input.as_bytes()[input.len() - 100..][..10]);
+
+	// Encrypting sources seek the same way
+	let mut source: Source<_> = Source::encrypt(Cursor::new(input.as_bytes()), &key, &iv).expect("source::enc");
+	source.seek(SeekFrom::Start(100)).unwrap();
+	let mut output = Vec::new();
+	source.read_to_end(&mut output).expect("read");
+	assert_eq!(&output[..], &encrypted[100..]);
+
+	assert_eq!(&decrypt_range(Cursor::new(&encrypted[..]), &key, &iv, 70, 20).unwrap()[..], &input.as_bytes()[70..90]);
+	assert_eq!(&decrypt_range(Cursor::new(&encrypted[..]), &key, &iv, input.len() as u64 - 5, 20).unwrap()[..], &input.as_bytes()[input.len() - 5..]);
+
+	let mut source: Source<_> = Source::decrypt_tagged(Cursor::new(&encrypted[..]), &key, &iv).expect("source::dec");
+	assert_eq!(source.seek(SeekFrom::Start(1)).unwrap_err().kind(), io::ErrorKind::Unsupported);
+
+	let mut source: Source<_> = Source::decrypt(Cursor::new(&encrypted[..]), &key, &iv).expect("source::dec");
+	source.seek(SeekFrom::Start(10)).unwrap();
+	assert_eq!(source.seek(SeekFrom::Start(cha::MAX_OFFSET)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+	assert_eq!(source.seek(SeekFrom::Current(cha::MAX_OFFSET as i64)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+	assert_eq!(source.stream_position().unwrap(), 10);
+    }
+
+    #[test]
+    fn source_buf_read()
+    {
+	use std::io::BufRead;
+	let (key, iv) = cha::keygen();
+	let input = INPUT.replace('!', "!\n");
+	let encrypted = enc_stream(input.as_bytes(), &key, &iv).into_inner();
+	let mut tagged = enc_stream(input.as_bytes(), &key, &iv);
+	tagged.finish_append().expect("sink::finish_append");
+	let tagged = tagged.into_inner();
+	let expected: Vec<&str> = input.lines().collect();
+
+	for &cap in &[1usize, 7, 64, source::DEFAULT_FILL_CAPACITY] {
+	    let source: Source<_, UseBufferInternal> = Source::decrypt(Cursor::new(&encrypted[..]), &key, &iv).expect("source::dec");
+	    let lines: Vec<String> = source.with_buffer_capacity(cap).lines().map(Result::unwrap).collect();
+	    assert_eq!(lines, expected);
+
+	    let source: Source<_, UseBufferInternal> = Source::decrypt_tagged(Cursor::new(&tagged[..]), &key, &iv).expect("source::dec");
+	    let lines: Vec<String> = source.with_buffer_capacity(cap).lines().map(Result::unwrap).collect();
+	    assert_eq!(lines, expected);
+	}
+
+	// `fill_buf()`/`consume()` and `read()` can be mixed
+	let mut source: Source<_, UseBufferInternal> = Source::decrypt(Cursor::new(&encrypted[..]), &key, &iv).expect("source::dec");
+	source.set_buffer_capacity(16);
+	assert_eq!(source.fill_buf().unwrap(), &input.as_bytes()[..16]);
+	source.consume(5);
+	let mut output = [0u8; 4];
+	source.read_exact(&mut output[..]).unwrap();
+	assert_eq!(&output[..], &input.as_bytes()[5..9]);
+	assert_eq!(source.fill_buf().unwrap(), &input.as_bytes()[9..16]);
+
+	let mut output = Vec::new();
+	source.read_to_end(&mut output).unwrap();
+	assert_eq!(&output[..], &input.as_bytes()[9..]);
+    }
+
+    #[test]
+    fn source_buf_read_seek()
+    {
+	use std::io::{BufRead, Seek, SeekFrom};
+	let (key, iv) = cha::keygen();
+	let encrypted = enc_stream(INPUT.as_bytes(), &key, &iv).into_inner();
+
+	let mut source: Source<_, UseBufferInternal> = Source::decrypt(Cursor::new(&encrypted[..]), &key, &iv).expect("source::dec");
+	source.set_buffer_capacity(32);
+	source.fill_buf().unwrap();
+	source.consume(10);
+	assert_eq!(source.stream_position().unwrap(), 10);
+
+	// Relative seeks are from the plaintext consumed, not the ciphertext read
+	assert_eq!(source.seek(SeekFrom::Current(5)).unwrap(), 15);
+	assert_eq!(source.fill_buf().unwrap(), &INPUT.as_bytes()[15..47]);
+	source.consume(2);
+	assert_eq!(source.seek(SeekFrom::Current(-17)).unwrap(), 0);
+
+	let mut output = Vec::new();
+	source.read_to_end(&mut output).unwrap();
+	assert_eq!(&output[..], INPUT.as_bytes());
+    }
+
+    /// A reader that counts the calls made to it.
+    struct Counting<R>(R, usize);
+
+    impl<R: Read> Read for Counting<R>
+    {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+	    self.1 += 1;
+	    self.0.read(buf)
+	}
+    }
+
+    #[test]
+    fn source_read_vectored()
+    {
+	use std::io::{BufRead, IoSliceMut};
+	let (key, iv) = cha::keygen();
+	let encrypted = enc_stream(INPUT.as_bytes(), &key, &iv).into_inner();
+	let mut tagged = enc_stream(INPUT.as_bytes(), &key, &iv);
+	tagged.finish_append().expect("sink::finish_append");
+	let tagged = tagged.into_inner();
+
+	fn read_all(mut source: impl Read) -> Vec<u8>
+	{
+	    let mut output = Vec::new();
+	    loop {
+		let (mut a, mut b, mut c) = ([0u8; 3], [0u8; 0], [0u8; 10]);
+		let n = source.read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b), IoSliceMut::new(&mut c)]).unwrap();
+		if n == 0 {
+		    break output;
+		}
+		output.extend(a.iter().chain(c.iter()).take(n));
+	    }
+	}
+
+	let mut source: Source<_, UseBufferInternal> = Source::decrypt(Counting(&encrypted[..], 0), &key, &iv).expect("source::dec");
+	let (mut a, mut b) = ([0u8; 5], [0u8; 7]);
+	assert_eq!(source.read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)]).unwrap(), 12);
+	assert_eq!(source.inner().1, 1);
+	assert_eq!(&a[..], &INPUT.as_bytes()[..5]);
+	assert_eq!(&b[..], &INPUT.as_bytes()[5..12]);
+	assert_eq!(read_all(source), &INPUT.as_bytes()[12..]);
+
+	assert_eq!(read_all(Source::<_, UseBufferExternal>::decrypt(&encrypted[..], &key, &iv).expect("source::dec")), INPUT.as_bytes());
+	assert_eq!(read_all(Source::<_, UseBufferInternal>::decrypt_tagged(&tagged[..], &key, &iv).expect("source::dec")), INPUT.as_bytes());
+	assert_eq!(read_all(Source::<_, UseBufferExternal>::decrypt_tagged(Trickle(&tagged[..], 5), &key, &iv).expect("source::dec")), INPUT.as_bytes());
+
+	// Plaintext left by `fill_buf()` is read first
+	let mut source: Source<_, UseBufferInternal> = Source::decrypt(&encrypted[..], &key, &iv).expect("source::dec");
+	source.set_buffer_capacity(8);
+	source.fill_buf().unwrap();
+	source.consume(2);
+	assert_eq!(read_all(source), INPUT.as_bytes()[2..]);
+    }
+
+    #[test]
+    fn source_pool()
+    {
+	use std::sync::Arc;
+	let (key, iv) = cha::keygen();
+	let mut tagged = enc_stream(INPUT.as_bytes(), &key, &iv);
+	tagged.finish_append().expect("sink::finish_append");
+	let tagged = Arc::new(tagged.into_inner());
+
+	let source: Source<_, UseBufferPool> = Source::decrypt(&tagged[..], &key, &iv).expect("source::dec");
+	assert!(Arc::ptr_eq(source.pool(), &pool::BufferPool::shared()));
+
+	let pool = Arc::new(pool::BufferPool::with_max_idle(2));
+	let threads: Vec<_> = (0..8).map(|i| {
+	    #[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
+	    let (pool, tagged, key, iv) = (pool.clone(), tagged.clone(), key.clone(), iv.clone());
+	    std::thread::spawn(move || {
+		let mut source = Source::<_, UseBufferPool>::decrypt_tagged(Trickle(&tagged[..], 3 + i), &key, &iv).expect("source::dec").with_pool(pool);
+		let mut output = Vec::new();
+		source.read_to_end(&mut output).expect("read");
+		assert_eq!(&output[..], INPUT.as_bytes());
+	    })
+	}).collect();
+	for thread in threads {
+	    thread.join().unwrap();
+	}
+	assert!(pool.idle() >= 1 && pool.idle() <= 2);
+	pool.with_buffer(64, |buf| assert!(buf.iter().all(|&b| b == 0)));
+
+	let mut source = Source::<_, UseBufferPool>::encrypt(INPUT.as_bytes(), &key, &iv).expect("source::enc").with_pool(pool.clone());
+	let mut output = Vec::new();
+	source.read_to_end(&mut output).expect("read");
+	assert_eq!(&output[..], &tagged[..INPUT.len()]);
+    }
+
+    #[test]
+    fn stack_max_bytes()
+    {
+	assert_eq!(UseBufferExternal::stack_max_bytes(), source::STACK_MAX_BYTES);
+
+	// Either side of the threshold, the buffer is zeroed and as large as asked for
+	for &max in &[0, 16, source::STACK_MAX_BYTES] {
+	    for &sz in &[1, 16, 17, source::STACK_MAX_BYTES + 1] {
+		assert!(source::alloca_max(sz, max, |buf| buf.len() == sz && buf.iter().all(|&b| b == 0)), "sz: {}, max: {}", sz, max);
+	    }
+	}
+
+	// A read too large for the stack
+	let (key, iv) = cha::keygen();
+	let input = INPUT.repeat(source::STACK_MAX_BYTES / INPUT.len() + 1);
+	let encrypted = enc_stream(input.as_bytes(), &key, &iv).into_inner();
+	let mut output = vec![0u8; input.len()];
+	Source::<_, UseBufferExternal>::decrypt(&encrypted[..], &key, &iv).expect("source::dec").read_exact(&mut output[..]).expect("read");
+	assert_eq!(&output[..], input.as_bytes());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn duplex()
+    {
+	use std::os::unix::net::UnixStream;
+	let (ours, theirs) = (cha::keygen(), cha::keygen());
+	let (a, b) = UnixStream::pair().unwrap();
+
+	let mut a = Stream::new(a, &ours.0, &ours.1, &theirs.0, &theirs.1).expect("Stream::new");
+	let mut b = Stream::new(b, &theirs.0, &theirs.1, &ours.0, &ours.1).expect("Stream::new");
+
+	a.write_all(INPUT.as_bytes()).unwrap();
+	a.flush().unwrap();
+	b.write_all(&INPUT.as_bytes()[..20]).unwrap();
+	b.flush().unwrap();
+
+	let mut output = vec![0u8; INPUT.len()];
+	b.read_exact(&mut output[..]).unwrap();
+	assert_eq!(&output[..], INPUT.as_bytes());
+	a.read_exact(&mut output[..20]).unwrap();
+	assert_eq!(&output[..20], &INPUT.as_bytes()[..20]);
+
+	// What is on the wire is what a `Sink` would write
+	let (raw, mut b) = UnixStream::pair().unwrap();
+	let mut a = Stream::new(raw, &ours.0, &ours.1, &theirs.0, &theirs.1).expect("Stream::new");
+	a.write_all(INPUT.as_bytes()).unwrap();
+	a.flush().unwrap();
+	drop(a);
+	let mut wire = Vec::new();
+	b.read_to_end(&mut wire).unwrap();
+	assert_eq!(wire, enc_stream(INPUT.as_bytes(), &ours.0, &ours.1).into_inner());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn duplex_split()
+    {
+	use std::os::unix::net::UnixStream;
+	let (ours, theirs) = (cha::keygen(), cha::keygen());
+	let (a, b) = UnixStream::pair().unwrap();
+
+	let mut a = Stream::new(a, &ours.0, &ours.1, &theirs.0, &theirs.1).expect("Stream::new");
+	a.write_all(&INPUT.as_bytes()[..5]).unwrap();
+	a.flush().unwrap();
+
+	// Each end echoes what it reads back to the other, from a separate thread.
+	let b = Stream::new(b, &theirs.0, &theirs.1, &ours.0, &ours.1).expect("Stream::new");
+	let (mut source, mut sink) = b.split_with(|b| (b.try_clone().unwrap(), b));
+	let echo = std::thread::spawn(move || {
+	    let mut buf = [0u8; 7];
+	    loop {
+		match source.read(&mut buf[..]).unwrap() {
+		    0 => break,
+		    n => sink.write_all(&buf[..n]).unwrap(),
+		}
+		sink.flush().unwrap();
+	    }
+	});
+
+	let (mut source, mut sink) = a.split_with(|a| (a.try_clone().unwrap(), a));
+	sink.write_all(&INPUT.as_bytes()[5..]).unwrap();
+	sink.flush().unwrap();
+	sink.inner().shutdown(std::net::Shutdown::Write).unwrap();
+
+	let mut output = Vec::new();
+	source.read_to_end(&mut output).unwrap();
+	echo.join().expect("echo thread panic");
+	assert_eq!(&output[..], INPUT.as_bytes());
+
+	// Streams that can be shared by reference split with `split()`
+	let (a, b) = UnixStream::pair().unwrap();
+	let a = Stream::new(&a, &ours.0, &ours.1, &theirs.0, &theirs.1).expect("Stream::new");
+	let (_, mut sink) = a.split();
+	sink.write_all(INPUT.as_bytes()).unwrap();
+	sink.flush().unwrap();
+	let mut b = Stream::new(&b, &theirs.0, &theirs.1, &ours.0, &ours.1).expect("Stream::new");
+	b.read_exact(&mut output[..]).unwrap();
+	assert_eq!(&output[..], INPUT.as_bytes());
+    }
+
     /// Checks if explicit clear is actually clearing.
 	#[cfg(feature="explicit_clear")] 
     #[test]
@@ -150,10 +889,10 @@ mod tests
 	let (buf, off, _s) = {
 	    let (key, iv) = cha::keygen();
 
-	    let input = enc_stream(INPUT.as_bytes(), key.clone(), iv.clone()).into_inner();
+	    let input = enc_stream(INPUT.as_bytes(), &key, &iv).into_inner();
 
 	    {
-		let mut stream = Sink::decrypt(&mut dec_buffer, key, iv).expect("sink::rem");
+		let mut stream = Sink::decrypt(&mut dec_buffer, &key, &iv).expect("sink::rem");
 
 		stream.write_all(&input[..]).unwrap();
 
@@ -0,0 +1,155 @@
+//! Shared scratch buffers for `UseBufferPool`
+use super::*;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+
+/// Default number of idle buffers a `BufferPool` keeps
+pub const DEFAULT_MAX_IDLE: usize = 64;
+
+/// A thread-safe pool of scratch buffers
+///
+/// Sources using `UseBufferPool` borrow a buffer from their pool for each read, and return it once the read is done, so buffers are only allocated for as many reads as happen at once.
+/// A buffer is cleared as soon as it is returned, so idle buffers never hold any data.
+/// ```
+/// # use chacha20stream::{Source, UseBufferPool, BufferPool};
+/// # use std::{io::Read, sync::Arc};
+/// # let (key, iv) = chacha20stream::keygen();
+/// let pool = Arc::new(BufferPool::with_max_idle(16));
+///
+/// let mut source: Source<_, UseBufferPool> = Source::encrypt(&b"Hello world!"[..], &key, &iv).unwrap().with_pool(pool.clone());
+/// source.read_to_end(&mut Vec::new()).unwrap();
+/// assert_eq!(pool.idle(), 1);
+/// ```
+#[derive(Debug)]
+pub struct BufferPool
+{
+    idle: Mutex<Vec<Vec<u8>>>, // every byte of each of these is 0
+    max_idle: usize,
+}
+
+/// A buffer borrowed from a pool, which is cleared and returned when dropped.
+struct Lease<'a>
+{
+    pool: &'a BufferPool,
+    buffer: Vec<u8>,
+    used: usize,
+}
+
+impl Drop for Lease<'_>
+{
+    fn drop(&mut self)
+    {
+	// Only the start of the buffer was handed out, the rest is still cleared.
+	bytes::prune(&mut self.buffer[..self.used]);
+	let mut idle = self.pool.lock();
+	if idle.len() < self.pool.max_idle {
+	    idle.push(std::mem::take(&mut self.buffer));
+	}
+    }
+}
+
+impl BufferPool
+{
+    /// Create a new empty pool that keeps up to `DEFAULT_MAX_IDLE` idle buffers
+    #[inline] pub fn new() -> Self
+    {
+	Self::with_max_idle(DEFAULT_MAX_IDLE)
+    }
+
+    /// Create a new empty pool that keeps up to `max_idle` idle buffers
+    ///
+    /// Buffers returned while the pool already has `max_idle` are freed instead.
+    #[inline] pub fn with_max_idle(max_idle: usize) -> Self
+    {
+	Self {
+	    idle: Mutex::new(Vec::new()),
+	    max_idle,
+	}
+    }
+
+    /// The process-wide pool that sources using `UseBufferPool` start with
+    pub fn shared() -> Arc<Self>
+    {
+	static SHARED: OnceLock<Arc<BufferPool>> = OnceLock::new();
+	SHARED.get_or_init(|| Arc::new(Self::new())).clone()
+    }
+
+    /// The most idle buffers this pool keeps
+    #[inline] pub fn max_idle(&self) -> usize
+    {
+	self.max_idle
+    }
+
+    /// The number of buffers currently idle in this pool
+    #[inline] pub fn idle(&self) -> usize
+    {
+	self.lock().len()
+    }
+
+    /// Call `cb` with a zeroed buffer of `sz` bytes borrowed from this pool.
+    ///
+    /// The buffer is cleared and returned once `cb` returns (or panics.)
+    pub fn with_buffer<T>(&self, sz: usize, cb: impl FnOnce(&mut [u8]) -> T) -> T
+    {
+	let buffer = self.lock().pop().unwrap_or_default();
+	let mut lease = Lease { pool: self, buffer, used: sz };
+	if lease.buffer.len() < sz {
+	    // Idle buffers are cleared, so growing one can't leave data behind in its old allocation.
+	    lease.buffer.resize(sz, 0);
+	}
+	cb(&mut lease.buffer[..sz])
+    }
+
+    #[inline] fn lock(&self) -> MutexGuard<'_, Vec<Vec<u8>>>
+    {
+	self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for BufferPool
+{
+    #[inline]
+    fn default() -> Self
+    {
+	Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn cleared_on_return()
+    {
+	let pool = BufferPool::with_max_idle(2);
+	pool.with_buffer(100, |buf| {
+	    assert_eq!(buf.len(), 100);
+	    buf.fill(0xff);
+	});
+	assert_eq!(pool.idle(), 1);
+
+	// The same buffer is reused, grown, and was cleared
+	pool.with_buffer(200, |buf| {
+	    assert!(buf.iter().all(|&b| b == 0));
+	    buf[..50].fill(0xff);
+	});
+	pool.with_buffer(10, |buf| assert!(buf.iter().all(|&b| b == 0)));
+	assert_eq!(pool.idle(), 1);
+
+	// Also when the borrower panics
+	let _ = std::panic::catch_unwind(|| pool.with_buffer(10, |buf| {
+	    buf.fill(0xff);
+	    panic!("borrower failed");
+	}));
+	pool.with_buffer(200, |buf| assert!(buf.iter().all(|&b| b == 0)));
+    }
+
+    #[test]
+    fn max_idle()
+    {
+	let pool = BufferPool::with_max_idle(2);
+	pool.with_buffer(1, |_| pool.with_buffer(1, |_| pool.with_buffer(1, |_| assert_eq!(pool.idle(), 0))));
+	assert_eq!(pool.idle(), 2);
+    }
+}
@@ -1,5 +1,8 @@
 //! Syncronous stream `Write` componant.
 use super::*;
+use std::ops::Range;
+use std::io::IoSlice;
+use bytes::PruneOnDrop;
 
 /// ChaCha Sink
 ///
@@ -31,6 +34,69 @@ use super::*;
 /// stream.flush().unwrap();
 /// ```
 ///
+/// # Authentication
+/// Once all data has been written, an encrypting sink can be finished with `finish()`, which returns the Poly1305 `Tag` for the ciphertext (or `finish_append()`, which also writes it to the inner stream.)
+/// A decrypting sink can then be finished with `finish_verify()`, which checks the data written to it against that tag.
+/// ```
+/// # use chacha20stream::Sink;
+/// # use std::io::Write;
+/// # let (key, iv) = chacha20stream::keygen();
+/// let mut stream = Sink::encrypt(Vec::new(), &key, &iv).expect("Failed to create encryptor");
+/// stream.write_all(b"Hello world!").unwrap();
+/// let tag = stream.finish().expect("Failed to finalise encryptor");
+///
+/// let mut encrypted = stream.into_inner();
+/// encrypted[0] ^= 1;
+///
+/// let mut stream = Sink::decrypt(Vec::new(), &key, &iv).expect("Failed to create decryptor");
+/// stream.write_all(&encrypted[..]).unwrap();
+/// assert!(stream.finish_verify(&tag).unwrap_err().is_mismatch());
+/// ```
+/// Finishing resets the cipher's keystream, so a finished sink cannot be used again: writes to it fail, as does finishing it a second time.
+///
+/// # Partial writes
+/// Plaintext is en/decrypted as soon as it is written, which advances the keystream. `write()` only reports the bytes whose ciphertext the inner stream accepted as written.
+/// If the inner stream takes less (a short write), or fails (with an error such as `Interrupted` or `WouldBlock`, which `write()` returns), the rest of the ciphertext is kept as pending data.
+/// The next `write()` must be given the rest of the same input, as `write_all()` does: it writes the pending ciphertext in place of that input, instead of transforming it again. No more input is transformed until all of it has been written.
+/// `flush()` also writes pending data to the inner stream. The input it was transformed from is then reported as written by the next `write()`.
+/// Input that has been transformed is part of the stream whether or not it has been reported yet, so it is also included in the tag computed by `finish()`.
+///
+/// # Vectored writes
+/// `write_vectored()` transforms all of its slices (up to `max_buffer()` bytes of them), in order, into a single ciphertext, and forwards it to the inner stream as one write instead of one per slice.
+///
+/// # Bounded buffer
+/// By default, a write transforms all of its input at once, so the buffer grows to the size of the largest write and stays that size.
+/// A sink created with `with_max_buffer()` transforms at most that many bytes per `write()` instead, and returns how many it accepted. `write_all()` keeps writing the rest, so the buffer never grows past the limit however large the input is.
+/// ```
+/// # use chacha20stream::Sink;
+/// # use std::io::Write;
+/// # let (key, iv) = chacha20stream::keygen();
+/// let mut stream = Sink::encrypt(Vec::new(), &key, &iv).unwrap().with_max_buffer(4096);
+/// let input = vec![0u8; 1024 * 1024];
+///
+/// assert_eq!(stream.write(&input[..]).unwrap(), 4096);
+/// stream.write_all(&input[4096..]).unwrap();
+/// ```
+/// With the `explicit_clear` feature, each chunk is also cleared from the buffer as soon as it has been written to the inner stream.
+///
+/// # Buffered writes
+/// A sink created with `buffered()` collects small writes as plaintext, and transforms and writes them to the inner stream together once the buffer is full, or on `flush()`.
+/// Writes at least as large as the buffer are not collected.
+/// The buffered plaintext is cleared as soon as it is transformed, and when the sink is dropped.
+/// ```
+/// # use chacha20stream::Sink;
+/// # use std::io::Write;
+/// # let (key, iv) = chacha20stream::keygen();
+/// let mut stream = Sink::encrypt(Vec::new(), &key, &iv).unwrap().buffered(4096);
+/// for byte in 0..100u8 {
+///     stream.write_all(&[byte]).unwrap();
+/// }
+/// assert!(stream.inner().is_empty());
+///
+/// stream.flush().unwrap();
+/// assert_eq!(stream.inner().len(), 100);
+/// ```
+///
 /// # Note
 /// When writing, a temporary buffer stored in the structure is used. This buffer is **not** cleared after a write, for efficiency reasons. This may leave sensitive information in the buffer after the write operation.
 /// The `flush()` implementation *does* clear this buffer.
@@ -38,8 +104,14 @@ use super::*;
 //#[derive(Debug)]
 pub struct Sink<W: ?Sized>
 {
-    crypter: Crypter, // for chacha, finalize does nothing it seems. we can also call it multiple times.
+    crypter: Crypter, // `finalize` must only be called once, at the end of the stream: it computes the Poly1305 tag and resets the keystream.
+    finished: bool, // `crypter` has been finalised
     pub(super) buffer: BufferVec, // used to buffer the operation
+    pending: Range<usize>, // transformed bytes in `buffer` not yet written to `stream`
+    unreported: usize, // bytes of input transformed by `write()` but not yet reported as written. The ciphertext of the last `pending.len()` of these has not been written yet.
+    max_buffer: Option<usize>, // most input transformed into `buffer` at a time, if limited
+    pub(super) plain: PruneOnDrop<Vec<u8>>, // plaintext accepted but not yet transformed. Never grows past its initial capacity, so it is never reallocated.
+    buffered: Option<usize>, // capacity of `plain`, if buffered
 
     stream: W,
 }
@@ -79,35 +151,239 @@ where W: Write
 	&mut self.stream
     }
 
-    /// Perform the cipher transform on this input to the inner buffer, returning the number of bytes updated.
-    fn transform(&mut self, buf: &[u8]) -> Result<usize, ErrorStack>
+    /// Transform the buffered plaintext onto the end of the pending data, and clear it.
+    fn seal_plain(&mut self) -> Result<(), ErrorStack>
+    {
+	if self.plain.is_empty() {
+	    return Ok(());
+	}
+	let start = self.pending.end;
+	let len = self.plain.len();
+	if start + len > self.buffer.len() {
+	    self.buffer.resize(start + len, 0);
+	}
+	let n = self.crypter.update(&self.plain[..], &mut self.buffer[start..])?;
+	self.pending.end += n;
+
+	bytes::prune(&mut self.plain[..]);
+	self.plain.clear();
+	Ok(())
+    }
+
+    /// Collect `bufs` in the plaintext buffer, if the sink is buffered and they are smaller than it, returning the number of bytes collected.
+    ///
+    /// If they do not fit alongside what is already buffered, that is transformed and written first.
+    /// Returns `None` if `bufs` should be transformed directly instead.
+    fn buffer_plain(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<Option<usize>>
+    {
+	let cap = match self.buffered {
+	    Some(cap) => cap,
+	    None => return Ok(None),
+	};
+	let len = bufs.iter().map(|buf| buf.len()).sum();
+	if self.plain.len() + len > cap {
+	    // The batch is written before `bufs` is accepted. If that fails, none of `bufs` is.
+	    self.seal_plain()?;
+	    self.drain()?;
+	}
+	if len >= cap {
+	    return Ok(None);
+	}
+	for buf in bufs {
+	    self.plain.extend_from_slice(buf);
+	}
+	Ok(Some(len))
+    }
+
+    /// The capacity of the plaintext buffer, if buffered
+    #[inline] pub fn buffered_capacity(&self) -> Option<usize>
+    {
+	self.buffered
+    }
+
+    /// Mark `n` pending bytes as written to the inner stream.
+    fn advance(&mut self, n: usize)
+    {
+	self.pending.start += n;
+	if self.pending.is_empty() {
+	    #[cfg(feature="explicit_clear")] if self.max_buffer.is_some() {
+		bytes::explicit_prune(&mut self.buffer[..self.pending.end]);
+	    }
+	    self.pending = 0..0;
+	}
+    }
+
+    /// Write any pending transformed bytes to the inner stream.
+    fn drain(&mut self) -> io::Result<()>
+    {
+	while !self.pending.is_empty() {
+	    match self.stream.write(&self.buffer[self.pending.clone()])? {
+		0 => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write pending data to inner stream")),
+		n => self.advance(n),
+	    }
+	}
+	Ok(())
+    }
+
+    /// Write up to `max` bytes of the ciphertext of unreported input to the inner stream with `write`, returning how many bytes of input to report as written.
+    fn write_unreported<F>(&mut self, max: usize, write: F) -> io::Result<usize>
+    where F: FnOnce(&mut W, &[u8]) -> io::Result<usize>
+    {
+	// The ciphertext of the first of these may have already been written by `flush()`, so they only need reporting.
+	let flushed = self.unreported - self.pending.len();
+	let n = if flushed > 0 {
+	    std::cmp::min(flushed, max)
+	} else {
+	    let end = self.pending.start + std::cmp::min(self.pending.len(), max);
+	    let n = write(&mut self.stream, &self.buffer[self.pending.start..end])?;
+	    self.advance(n);
+	    n
+	};
+	self.unreported -= n;
+	Ok(n)
+    }
+
+    /// Transform `bufs` and write them to the inner stream with `write`, returning how many bytes of them the inner stream accepted.
+    ///
+    /// If there is unreported input, `bufs` is the rest of it, and its pending ciphertext is written instead.
+    fn write_bufs<F>(&mut self, bufs: &[IoSlice<'_>], write: F) -> io::Result<usize>
+    where F: FnOnce(&mut W, &[u8]) -> io::Result<usize>
+    {
+	if self.finished {
+	    return Err(AuthError::Finished.into());
+	}
+	if self.unreported == 0 {
+	    if let Some(n) = self.buffer_plain(bufs)? {
+		return Ok(n);
+	    }
+	    // Nothing new is transformed until everything before it has been written.
+	    self.drain()?;
+
+	    let n = self.transform(bufs)?;
+	    self.pending = 0..n;
+	    self.unreported = n;
+	}
+	self.write_unreported(bufs.iter().map(|buf| buf.len()).sum(), write)
+    }
+
+    /// Perform the cipher transform on these inputs, one after the other, to the inner buffer, returning the number of bytes updated.
+    ///
+    /// No more than `max_buffer` bytes are transformed. There must be no pending bytes in the buffer.
+    fn transform(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize, ErrorStack>
     {
-	if buf.len() > self.buffer.len() {
-	    self.buffer.resize(buf.len(), 0);
+	debug_assert!(self.pending.is_empty(), "transform would overwrite pending data");
+	let len = bufs.iter().map(|buf| buf.len()).sum();
+	let len = self.max_buffer.map_or(len, |max| std::cmp::min(len, max));
+	if len > self.buffer.len() {
+	    self.buffer.resize(len, 0);
 	}
 	
-	let n = self.crypter.update(&buf[..], &mut self.buffer[..])?;
-	let _f = self.crypter.finalize(&mut self.buffer[..n])?; // I don't know if this is needed.
-	debug_assert_eq!(_f, 0);
+	let mut n = 0;
+	for buf in bufs {
+	    if n == len {
+		break;
+	    }
+	    let take = std::cmp::min(buf.len(), len - n);
+	    n += self.crypter.update(&buf[..take], &mut self.buffer[n..])?;
+	}
 
 	Ok(n)
     }
 
+    /// Limit how many bytes are transformed into the internal buffer at a time. This takes effect on the next write.
+    ///
+    /// If the buffer has already grown past `max`, it is cleared and shrunk to fit it (or to fit any pending data, if that is larger.)
+    ///
+    /// # Panics
+    /// If `max` is 0.
+    pub fn set_max_buffer(&mut self, max: usize)
+    {
+	assert!(max > 0, "max buffer size must not be 0");
+	self.max_buffer = Some(max);
+
+	let keep = std::cmp::max(max, self.pending.end);
+	if self.buffer.len() > keep {
+	    bytes::prune(&mut self.buffer[keep..]);
+	    self.buffer.truncate(keep);
+	    self.buffer.shrink_to_fit();
+	}
+    }
+
+    /// The most bytes transformed into the internal buffer at a time, if limited
+    #[inline] pub fn max_buffer(&self) -> Option<usize>
+    {
+	self.max_buffer
+    }
+
+    /// Has this sink been finished?
+    #[inline] pub fn is_finished(&self) -> bool
+    {
+	self.finished
+    }
+
+    /// Finalise an encrypting sink, returning the Poly1305 authentication tag of all data written to it.
+    ///
+    /// # Errors
+    /// Finalising resets the cipher's state, so after the sink is finished, this, `finish_append()`, `finish_verify()`, and any further writes return `AuthError::Finished`.
+    pub fn finish(&mut self) -> Result<Tag, AuthError>
+    {
+	if self.finished {
+	    return Err(AuthError::Finished);
+	}
+	self.seal_plain()?;
+	self.finished = true;
+	let mut tag = Tag::default();
+	self.crypter.finalize(&mut [])?;
+	self.crypter.get_tag(tag.as_mut())?;
+	Ok(tag)
+    }
+
+    /// Finalise an encrypting sink, and append the Poly1305 authentication tag of all data written to it to the inner stream.
+    ///
+    /// The tag is also returned.
+    ///
+    /// # Errors
+    /// If the sink has already been finished, `AuthError::Finished` is returned.
+    pub fn finish_append(&mut self) -> io::Result<Tag>
+    {
+	if self.finished {
+	    return Err(AuthError::Finished.into());
+	}
+	self.seal_plain()?;
+	self.drain()?;
+	let tag = self.finish()?;
+	self.stream.write_all(tag.as_ref())?;
+	Ok(tag)
+    }
+
+    /// Finalise a decrypting sink, verifying the data written to it against the Poly1305 authentication tag `tag`.
+    ///
+    /// # Errors
+    /// If the data written was not authenticated by `tag`, `AuthError::Mismatch` is returned.
+    /// If the sink has already been finished, `AuthError::Finished` is returned.
+    pub fn finish_verify(&mut self, tag: &Tag) -> Result<(), AuthError>
+    {
+	if self.finished {
+	    return Err(AuthError::Finished);
+	}
+	self.seal_plain()?;
+	self.finished = true;
+	self.crypter.set_tag(tag.as_ref())?;
+	self.crypter.finalize(&mut []).map_err(|_| AuthError::Mismatch)?;
+	Ok(())
+    }
+
     /// Clear the internal buffer while keeping it allocated for further use.
     ///
     /// This does not affect operations at all, all it does is 0 out the left-over temporary buffer from the last operation(s).
+    /// Pending data that has not been written to the inner stream yet is kept.
     #[inline] 
     pub fn prune(&mut self)
     {
-	#[cfg(feature="explicit_clear")]
-	{
-	    bytes::explicit_prune(&mut self.buffer[..]);
-	    return;
-	}
-	#[cfg(not(feature="explicit_clear"))] 
-	unsafe {
-	    std::ptr::write_bytes(self.buffer.as_mut_ptr(), 0, self.buffer.len());
-	}
+	let Range { start, end } = self.pending;
+	let (head, tail) = self.buffer.split_at_mut(end);
+	bytes::prune(&mut head[..start]);
+	bytes::prune(tail);
     }
 }
 
@@ -117,29 +393,60 @@ where W: Write
     /// Create a new Chacha Sink stream wrapper
     #[inline] fn new(stream: W, crypter: Crypter) -> Self
     {
-	Self{stream, crypter, buffer: BufferVec::new()}
+	Self{stream, crypter, finished: false, buffer: BufferVec::new(), pending: 0..0, unreported: 0, max_buffer: None, plain: PruneOnDrop(Vec::new()), buffered: None}
     }
 
     /// Create an encrypting Chacha Sink stream wrapper
-    pub fn encrypt(stream: W, key: Key, iv: IV) -> Result<Self, Error>
+    pub fn encrypt(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
     {
 	Ok(Self::new(stream, cha::encrypter(key, iv)?))
     }
     
     /// Create a decrypting Chacha Sink stream wrapper
-    pub fn decrypt(stream: W, key: Key, iv: IV) -> Result<Self, Error>
+    pub fn decrypt(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
     {
 	Ok(Self::new(stream, cha::decrypter(key, iv)?))
     }
+
+    /// Create an encrypting Chacha Sink stream wrapper that writes a container `Header` describing it to `stream` first.
+    ///
+    /// The header is bound to the ciphertext as additional authenticated data. Use `finish_append()` once all data is written, so the container can be read with `Source::open()`.
+    pub fn create(mut stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> io::Result<Self>
+    {
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
+	let header = Header::new(iv.as_ref().clone()).to_bytes();
+	stream.write_all(&header[..])?;
+	Ok(Self::encrypt_with_aad(stream, key, iv, header)?)
+    }
+
+    /// Create an encrypting Chacha Sink stream wrapper, binding the additional authenticated data `aad` to the ciphertext.
+    ///
+    /// `aad` is not encrypted or written to the stream, but is included in the tag returned by `finish()`.
+    pub fn encrypt_with_aad(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, Error>
+    {
+	Ok(Self::new(stream, cha::encrypter_with_aad(key, iv, aad)?))
+    }
+
+    /// Create a decrypting Chacha Sink stream wrapper, with the additional authenticated data `aad` bound to the ciphertext.
+    ///
+    /// `finish_verify()` will fail if `aad` differs from what the data was encrypted with.
+    pub fn decrypt_with_aad(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, Error>
+    {
+	Ok(Self::new(stream, cha::decrypter_with_aad(key, iv, aad)?))
+    }
     
 
     /// Consume into the inner stream
+    ///
+    /// Any pending data or buffered plaintext not yet written to the inner stream is lost. Call `flush()` first to write it.
     #[inline] pub fn into_inner(self) -> W
     {
 	self.stream
     }
 
     /// Consume into the inner stream and crypter
+    ///
+    /// Any pending data or buffered plaintext not yet written to the inner stream is lost. Call `flush()` first to write it.
     #[inline] pub fn into_parts(self) -> (W, Crypter)
     {
 	(self.stream, self.crypter)
@@ -153,23 +460,64 @@ where W: Write
 	Self::new(stream, crypter)
     }
 
+    /// Limit how many bytes are transformed into the internal buffer at a time.
+    ///
+    /// See `set_max_buffer()`.
+    ///
+    /// # Panics
+    /// If `max` is 0.
+    #[inline] pub fn with_max_buffer(mut self, max: usize) -> Self
+    {
+	self.set_max_buffer(max);
+	self
+    }
+
+    /// Collect writes smaller than `capacity` in a plaintext buffer, and transform and write them together when it is full, or on `flush()`.
+    ///
+    /// # Panics
+    /// If `capacity` is 0.
+    pub fn buffered(mut self, capacity: usize) -> Self
+    {
+	assert!(capacity > 0, "buffer capacity must not be 0");
+	// Move anything already buffered into a new allocation of the right size. The old one is cleared when dropped.
+	let mut plain = PruneOnDrop(Vec::with_capacity(std::cmp::max(capacity, self.plain.len())));
+	plain.extend_from_slice(&self.plain[..]);
+	self.plain = plain;
+	self.buffered = Some(capacity);
+	self
+    }
+
+    /// Replace the inner stream with `f(stream)`, keeping the crypter and any pending data.
+    pub(super) fn map_inner<T>(self, f: impl FnOnce(W) -> T) -> Sink<T>
+    {
+	Sink {
+	    stream: f(self.stream),
+	    crypter: self.crypter,
+	    finished: self.finished,
+	    buffer: self.buffer,
+	    pending: self.pending,
+	    unreported: self.unreported,
+	    max_buffer: self.max_buffer,
+	    plain: self.plain,
+	    buffered: self.buffered,
+	}
+    }
 }
 
 
 impl<W: ?Sized + Write> Write for Sink<W>
 {
-    #[inline] fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-	let n = self.transform(buf)?;
-
-	self.stream.write(&self.buffer[..n])
-	    
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	self.write_bufs(&[IoSlice::new(buf)], |stream, ciphertext| stream.write(ciphertext))
     }
-    #[inline] fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-	let n = self.transform(buf)?;
-
-	self.stream.write_all(&self.buffer[..n])
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+	// All of `bufs` is transformed into one contiguous ciphertext, which is forwarded in a single write.
+	self.write_bufs(bufs, |stream, ciphertext| stream.write_vectored(&[IoSlice::new(ciphertext)]))
     }
     #[inline] fn flush(&mut self) -> io::Result<()> {
+	self.seal_plain()?;
+	self.drain()?;
+	
 	#[cfg(feature="explicit_clear")] self.prune();
 	self.buffer.clear();
 	
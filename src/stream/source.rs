@@ -1,38 +1,85 @@
 //! Syncronous stream `Read` componant.
 use super::*;
+use std::io::{Seek, SeekFrom, BufRead, IoSliceMut};
+use std::ops::Range;
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+use super::pool::BufferPool;
 
-/// Max number of bytes to stackalloc
+/// Default max number of bytes to stackalloc
 ///
-/// Only used with `UseBufferExternal`.
+/// Only used with `UseBufferExternal`. See `UseBufferExternal::set_stack_max_bytes()`.
 pub const STACK_MAX_BYTES: usize = 4096;
 
+/// Max number of bytes to stackalloc, set by `UseBufferExternal::set_stack_max_bytes()`
+static STACK_MAX: AtomicUsize = AtomicUsize::new(STACK_MAX_BYTES);
+
+/// Default number of bytes decrypted at a time by `BufRead::fill_buf()`
+///
+/// Only used with `UseBufferInternal`.
+pub const DEFAULT_FILL_CAPACITY: usize = 8 * 1024;
+
 /// How buffers are used.
 pub trait BufferKind : private::Sealed
 {
     type InternalBuffer;
 
+    /// Is the internal buffer phantom? (i.e. the output buffer is reused instead.)
+    const PHANTOM: bool;
+
     fn create_buffer(cap: usize) -> Self::InternalBuffer;
 
-    fn buffer_len<R: ?Sized>(source: &Source<R, Self>) -> usize;
-    fn buffer_cap<R: ?Sized>(source: &Source<R, Self>) -> usize;
+    fn buffer_len(source: &Self::InternalBuffer) -> usize;
+    fn buffer_cap(source: &Self::InternalBuffer) -> usize;
 
     fn buffer_bytes_mut(source: &mut Self::InternalBuffer) -> &'_ mut [u8];
     fn buffer_bytes(source: &Self::InternalBuffer) -> &'_ [u8];
 
-    fn buffer_resize<R: ?Sized>(source: &mut Source<R, Self>, to: usize);
+    fn buffer_resize(source: &mut Self::InternalBuffer, to: usize);
+
+    /// Call `cb` with a scratch buffer of `sz` bytes, in place of the internal buffer.
+    ///
+    /// Only used if the internal buffer is phantom.
+    fn with_scratch<T>(source: &Self::InternalBuffer, sz: usize, cb: impl FnOnce(&mut [u8]) -> T) -> T;
 }
 
 /// Use struct-internal buffer for `Read`s
 #[derive(Debug)]
 pub struct UseBufferInternal;
 /// Reuse the output buffer for `Read`s
+///
+/// Raw bytes are read into a temporary buffer on the stack, or on the heap if it would be larger than `stack_max_bytes()`.
 #[derive(Debug)]
 pub struct UseBufferExternal;
+/// Borrow a buffer from a shared `BufferPool` for each `Read`
+///
+/// Sources start with `BufferPool::shared()`. Use `with_pool()` to give one a different pool.
+#[derive(Debug)]
+pub struct UseBufferPool;
+
+impl UseBufferExternal
+{
+    /// The largest temporary buffer allocated on the stack. Larger buffers are allocated on the heap.
+    ///
+    /// This is `STACK_MAX_BYTES` unless changed with `set_stack_max_bytes()`.
+    #[inline] pub fn stack_max_bytes() -> usize
+    {
+	STACK_MAX.load(Ordering::Relaxed)
+    }
+
+    /// Set the largest temporary buffer allocated on the stack, for every source in the process.
+    ///
+    /// 0 always allocates on the heap. Setting this too high risks overflowing the stack of any thread reading from a source.
+    #[inline] pub fn set_stack_max_bytes(max: usize)
+    {
+	STACK_MAX.store(max, Ordering::Relaxed);
+    }
+}
 
 impl private::Sealed for UseBufferInternal{}
 impl BufferKind for UseBufferInternal
 {
     type InternalBuffer = BufferVec;
+    const PHANTOM: bool = false;
 
     #[inline] fn create_buffer(cap: usize) -> Self::InternalBuffer {
 	if cap == 0 {
@@ -42,11 +89,11 @@ impl BufferKind for UseBufferInternal
 	}
     }
     
-    #[inline(always)] fn buffer_cap<R: ?Sized>(source: &Source<R, Self>) -> usize {
-	source.buffer.capacity()
+    #[inline(always)] fn buffer_cap(source: &Self::InternalBuffer) -> usize {
+	source.capacity()
     }
-    #[inline(always)] fn buffer_len<R: ?Sized>(source: &Source<R, Self>) -> usize {
-	source.buffer.len()
+    #[inline(always)] fn buffer_len(source: &Self::InternalBuffer) -> usize {
+	source.len()
     }
     
     #[inline(always)] fn buffer_bytes_mut(source: &mut Self::InternalBuffer) -> &'_ mut [u8]
@@ -58,29 +105,34 @@ impl BufferKind for UseBufferInternal
 	&source[..]
     }
 
-    #[inline(always)] fn buffer_resize<R: ?Sized>(source: &mut Source<R, Self>, to: usize)
+    #[inline(always)] fn buffer_resize(source: &mut Self::InternalBuffer, to: usize)
+    {
+	source.resize(to, 0);
+    }
+
+    #[cold]
+    #[inline(never)] fn with_scratch<T>(_: &Self::InternalBuffer, _: usize, _: impl FnOnce(&mut [u8]) -> T) -> T
     {
-	source.buffer.resize(to, 0);
+	panic!("Internal buffer is not phantom")
     }
 }
 impl private::Sealed for UseBufferExternal{}
 impl BufferKind for UseBufferExternal
 {
     type InternalBuffer = ();
+    const PHANTOM: bool = true;
 
     // -- always used --
     
-    #[inline(always)] fn create_buffer(_: usize) -> Self::InternalBuffer {
-	()
-    }
-    #[inline(always)] fn buffer_cap<R: ?Sized>(_: &Source<R, Self>) -> usize {
+    #[inline(always)] fn create_buffer(_: usize) -> Self::InternalBuffer {}
+    #[inline(always)] fn buffer_cap(_: &Self::InternalBuffer) -> usize {
 	0
     }
 
     // -- conditional --
     
     #[cold]
-    #[inline(never)] fn buffer_len<R: ?Sized>(_: &Source<R, Self>) -> usize {
+    #[inline(never)] fn buffer_len(_: &Self::InternalBuffer) -> usize {
 	panic!("Phantom buffer length cannot be checked")
     }
     #[cold]
@@ -94,10 +146,56 @@ impl BufferKind for UseBufferExternal
 	panic!("Cannot ref non-existent ibuf.")
     }
     #[cold]
-    #[inline(never)] fn buffer_resize<R: ?Sized>(_: &mut Source<R, Self>, _: usize)
+    #[inline(never)] fn buffer_resize(_: &mut Self::InternalBuffer, _: usize)
     {
 	panic!("Cannot resize non-existent ibuf.")
     }
+
+    #[inline(always)] fn with_scratch<T>(_: &Self::InternalBuffer, sz: usize, cb: impl FnOnce(&mut [u8]) -> T) -> T
+    {
+	try_alloca(sz, cb)
+    }
+}
+impl private::Sealed for UseBufferPool{}
+impl BufferKind for UseBufferPool
+{
+    type InternalBuffer = Arc<BufferPool>;
+    const PHANTOM: bool = true;
+
+    // -- always used --
+    
+    #[inline] fn create_buffer(_: usize) -> Self::InternalBuffer {
+	BufferPool::shared()
+    }
+    #[inline(always)] fn buffer_cap(_: &Self::InternalBuffer) -> usize {
+	0
+    }
+    #[inline(always)] fn with_scratch<T>(source: &Self::InternalBuffer, sz: usize, cb: impl FnOnce(&mut [u8]) -> T) -> T
+    {
+	source.with_buffer(sz, cb)
+    }
+
+    // -- conditional --
+    
+    #[cold]
+    #[inline(never)] fn buffer_len(_: &Self::InternalBuffer) -> usize {
+	panic!("Pooled buffer length cannot be checked")
+    }
+    #[cold]
+    #[inline(never)] fn buffer_bytes_mut(_: &mut Self::InternalBuffer) -> &'_ mut [u8]
+    {
+	panic!("Cannot mutref pooled ibuf.")
+    }
+    #[cold]
+    #[inline(never)] fn buffer_bytes(_: &Self::InternalBuffer) -> &'_ [u8]
+    {    
+	panic!("Cannot ref pooled ibuf.")
+    }
+    #[cold]
+    #[inline(never)] fn buffer_resize(_: &mut Self::InternalBuffer, _: usize)
+    {
+	panic!("Cannot resize pooled ibuf.")
+    }
 }
 
 #[cfg(not(feature="ad-hoc-buffer"))] 
@@ -105,15 +203,161 @@ pub type DefaultBuffer = UseBufferInternal;
 #[cfg(feature="ad-hoc-buffer")] 
 pub type DefaultBuffer = UseBufferExternal;
 
+/// How the Poly1305 tag is handled by a `Source`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TagMode
+{
+    /// The tag is neither produced nor checked.
+    Ignore,
+    /// Append the tag to the output once the inner stream reaches EOF.
+    ///
+    /// `tag` is `None` until it has been computed, `written` is the number of its bytes already output.
+    Append{tag: Option<Tag>, written: usize},
+    /// Hold back the last `TAG_SIZE` bytes read from the inner stream, and verify them as the tag once it reaches EOF.
+    ///
+    /// `len` is the number of valid bytes in `held`.
+    Verify{held: [u8; TAG_SIZE], len: usize, done: bool},
+}
+
+impl TagMode
+{
+    #[inline] pub(crate) fn append() -> Self
+    {
+	Self::Append{tag: None, written: 0}
+    }
+
+    #[inline] pub(crate) fn verify() -> Self
+    {
+	Self::Verify{held: [0u8; TAG_SIZE], len: 0, done: false}
+    }
+
+    /// Perform the cipher transform on the `raw` bytes read from the inner stream into `output`, returning the number of bytes output.
+    ///
+    /// An empty `raw` signals that the inner stream has reached EOF.
+    pub(crate) fn transform(&mut self, crypter: &mut Crypter, raw: &[u8], output: &mut [u8]) -> io::Result<usize>
+    {
+	match self {
+	    Self::Verify{ done: true, .. } => Ok(0),
+	    Self::Verify{ held, len, done } if raw.is_empty() => {
+		*done = true;
+		if *len < TAG_SIZE {
+		    // Truncated before the tag
+		    return Err(AuthError::Mismatch.into());
+		}
+		crypter.set_tag(&held[..]).map_err(AuthError::from)?;
+		crypter.finalize(&mut []).map_err(|_| AuthError::Mismatch)?;
+		Ok(0)
+	    },
+	    Self::Verify{ held, len, .. } => {
+		// Output everything but the last `TAG_SIZE` bytes of `held ++ raw`, which become the new `held`.
+		let emit = (*len + raw.len()).saturating_sub(TAG_SIZE);
+		let from_held = std::cmp::min(emit, *len);
+		let from_raw = emit - from_held;
+
+		let mut n = crypter.update(&held[..from_held], &mut output[..from_held])?;
+		n += crypter.update(&raw[..from_raw], &mut output[from_held..emit])?;
+
+		held.copy_within(from_held..*len, 0);
+		let kept = *len - from_held;
+		*len = kept + (raw.len() - from_raw);
+		held[kept..*len].copy_from_slice(&raw[from_raw..]);
+		Ok(n)
+	    },
+	    Self::Append{ tag, written } if raw.is_empty() => {
+		let tag = match tag {
+		    Some(tag) => tag,
+		    None => {
+			let mut new = Tag::default();
+			crypter.finalize(&mut [])?;
+			crypter.get_tag(new.as_mut())?;
+			tag.get_or_insert(new)
+		    },
+		};
+		let rest = &tag.as_ref()[*written..];
+		let n = std::cmp::min(rest.len(), output.len());
+		output[..n].copy_from_slice(&rest[..n]);
+		*written += n;
+		Ok(n)
+	    },
+	    _ => Ok(crypter.update(raw, &mut output[..raw.len()])?),
+	}
+    }
+}
+
 /// ChaCha source.
 /// En/decrypts information from the source reader.
 ///
 /// This is the `Read` implementing counterpart to `Sink`.
+///
+/// # Authentication
+/// A source created with `encrypt_tagged()` appends the Poly1305 `Tag` to its output after the last byte of ciphertext.
+/// A source created with `decrypt_tagged()` holds back the last `TAG_SIZE` bytes of the inner stream as the tag, and the `read` that reaches EOF fails with `io::ErrorKind::InvalidData` if it does not authenticate the data.
+/// ```
+/// # use chacha20stream::Source;
+/// # use std::io::{self, Read};
+/// # let (key, iv) = chacha20stream::keygen();
+/// let mut encrypted = Vec::new();
+/// let mut source: Source<_> = Source::encrypt_tagged(&b"Hello world!"[..], &key, &iv).unwrap();
+/// source.read_to_end(&mut encrypted).unwrap();
+///
+/// let mut decrypted = Vec::new();
+/// let mut source: Source<_> = Source::decrypt_tagged(&encrypted[..], &key, &iv).unwrap();
+/// source.read_to_end(&mut decrypted).expect("Message was tampered with");
+/// assert_eq!(&decrypted[..], b"Hello world!");
+///
+/// encrypted[0] ^= 1;
+/// let mut source: Source<_> = Source::decrypt_tagged(&encrypted[..], &key, &iv).unwrap();
+/// let err = source.read_to_end(&mut Vec::new()).unwrap_err();
+/// assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+/// ```
+/// # Seeking
+/// ChaCha20 is a counter-mode cipher, so a source created with `encrypt()` or `decrypt()` over a `Seek`able stream can jump to any offset without transforming the data before it.
+/// Offsets are into the ciphertext, which must start at the start of the inner stream.
+/// ```
+/// # use chacha20stream::{Sink, Source};
+/// # use std::io::{Cursor, Read, Write, Seek, SeekFrom};
+/// # let (key, iv) = chacha20stream::keygen();
+/// # let mut sink = Sink::encrypt(Vec::new(), &key, &iv).unwrap();
+/// # sink.write_all(b"Hello world!").unwrap();
+/// # let encrypted = sink.into_inner();
+/// let mut source: Source<_> = Source::decrypt(Cursor::new(encrypted), key, iv).unwrap();
+/// source.seek(SeekFrom::Start(6)).unwrap();
+///
+/// let mut output = String::new();
+/// source.read_to_string(&mut output).unwrap();
+/// assert_eq!(output, "world!");
+/// ```
+/// Sources that produce or verify a tag cannot seek, since the tag covers the whole stream.
+///
+/// # Buffered reading
+/// A source with `UseBufferInternal` implements `BufRead`, so it can be read line by line without wrapping it in a `BufReader`.
+/// Each `fill_buf()` that finds no plaintext left decrypts up to `buffer_capacity()` bytes into the internal buffer, and `consume()` clears the plaintext it releases from it.
+/// ```
+/// # use chacha20stream::{Sink, Source, UseBufferInternal};
+/// # use std::io::{BufRead, Write};
+/// # let (key, iv) = chacha20stream::keygen();
+/// # let mut sink = Sink::encrypt(Vec::new(), &key, &iv).unwrap();
+/// # sink.write_all(b"Hello\nworld!\n").unwrap();
+/// # let encrypted = sink.into_inner();
+/// let source: Source<_, UseBufferInternal> = Source::decrypt(&encrypted[..], key, iv).unwrap();
+/// let lines: Vec<String> = source.with_buffer_capacity(1024).lines().map(Result::unwrap).collect();
+/// assert_eq!(lines, ["Hello", "world!"]);
+/// ```
+///
+/// # Vectored reads
+/// `read_vectored()` reads from the inner stream once for all of its slices, and fills them in order.
+///
+/// # Note
+/// Plaintext released by a `decrypt_tagged()` source before EOF is reached has not yet been authenticated.
 //#[derive(Debug)]
 pub struct Source<R: ?Sized, Buffer: ?Sized + BufferKind = DefaultBuffer>
 {
-    crypter: Crypter,
+    tag: TagMode,
+    keys: Option<(Key, IV)>, // Kept to create a new crypter when seeking. `None` if the source was created from a crypter.
+    crypter: Crypter, // `finalize` must only be called once, at the end of the stream: it computes the Poly1305 tag and resets the keystream.
     pub(super) buffer: Buffer::InternalBuffer, // When `ad-hoc-buffer` is enabled, this isn't needed. We re-use the output buffer for the initial read of untransformed data from `stream` and the actual transformation of the read bytes.
+    filled: Range<usize>, // Plaintext in `buffer` from `fill_buf()` that has not been consumed yet. Always empty unless `Buffer` is `UseBufferInternal`.
+    fill_cap: usize, // How much `fill_buf()` decrypts at a time.
     
     stream: R
 }
@@ -124,7 +368,7 @@ impl<R: ?Sized+ fmt::Debug, K: ?Sized + BufferKind> fmt::Debug for Source<R, K>
     {
 	use std::any::type_name;
 	write!(f, "Source<Wraps: {}, BufferKind: {}>", type_name::<R>(), type_name::<K>())?;
-	match K::buffer_cap(self) {
+	match K::buffer_cap(&self.buffer) {
 	    0 => write!(f, "({:?}, (unbounded buffer cap))", &self.stream),
 	    cap => write!(f, "({:?}, ({} buffer cap))", &self.stream, cap), 
 	}
@@ -161,50 +405,47 @@ where R: Read
     /// Grow the inner buffer to fix this size, if needed.
     fn grow_to_fit(&mut self, sz: usize)
     {
-	if sz > K::buffer_len(self) {
-	    K::buffer_resize(self, sz);
+	if sz > K::buffer_len(&self.buffer) {
+	    K::buffer_resize(&mut self.buffer, sz);
 	}
     }
     
-    /// Perform the cipher transform on this `buffer` to the output buffer, returning the number of bytes updated.
-    fn transform_into(&mut self, buffer: &[u8], output: &mut [u8]) -> Result<usize, ErrorStack>
-    {
-	let n = self.crypter.update(buffer, &mut output[..])?;
-	let _f = self.crypter.finalize(&mut output[..n])?;
-	debug_assert_eq!(_f, 0);
-
-	Ok(n)
-    }
-    
-    /// Perform the cipher transform on the inner buffer bytes to the `output` buffer, returning the number of bytes updated.
+    /// Perform the cipher transform on the inner buffer bytes to the `output` buffer, returning the number of bytes output.
     ///
     /// # Panics
     /// If the inner buffer is phantom
-    fn transform(&mut self, bufsz: usize, output: &mut [u8]) -> Result<usize, ErrorStack>
+    fn transform(&mut self, bufsz: usize, output: &mut [u8]) -> io::Result<usize>
     {
-	let n = self.crypter.update(& K::buffer_bytes(&self.buffer)[..bufsz], &mut output[..])?;
-	let _f = self.crypter.finalize(&mut output[..n])?;
-	debug_assert_eq!(_f, 0);
-
-	Ok(n)
+	self.tag.transform(&mut self.crypter, &K::buffer_bytes(&self.buffer)[..bufsz], output)
     }
 
 
     /// Clear the internal buffer while keeping it allocated for further use.
     ///
     /// This does not affect operations at all, all it does is 0 out the left-over temporary buffer from the last operation(s).
+    /// Plaintext from `fill_buf()` that has not been consumed yet is kept.
+    /// With `UseBufferExternal` or `UseBufferPool` there is no internal buffer, and this does nothing.
     #[inline] 
     pub fn prune(&mut self)
     {
-	#[cfg(feature="explicit_clear")]
-	{
-	    bytes::explicit_prune(K::buffer_bytes_mut(&mut self.buffer));
+	if K::PHANTOM {
 	    return;
 	}
-	#[cfg(not(feature="explicit_clear"))] 
-	unsafe {
-	    std::ptr::write_bytes(K::buffer_bytes_mut(&mut self.buffer).as_mut_ptr(), 0, K::buffer_len(self));
-	}
+	let Range { start, end } = self.filled;
+	let (head, tail) = K::buffer_bytes_mut(&mut self.buffer).split_at_mut(end);
+	bytes::prune(&mut head[..start]);
+	bytes::prune(tail);
+    }
+
+    /// Copy up to `buf.len()` bytes of plaintext left by `fill_buf()` into `buf`, consuming them.
+    fn read_filled(&mut self, buf: &mut [u8]) -> usize
+    {
+	let filled = &mut K::buffer_bytes_mut(&mut self.buffer)[self.filled.clone()];
+	let n = std::cmp::min(filled.len(), buf.len());
+	buf[..n].copy_from_slice(&filled[..n]);
+	bytes::prune(&mut filled[..n]);
+	self.filled.start += n;
+	n
     }
 
 }
@@ -215,29 +456,81 @@ where R: Read
     /// Create a new Chacha Source stream wrapper from a reader
     #[inline] fn new(stream: R, crypter: Crypter) -> Self
     {
-	Self{stream, crypter, buffer: K::create_buffer(0)}
+	Self{stream, crypter, buffer: K::create_buffer(0), tag: TagMode::Ignore, keys: None, filled: 0..0, fill_cap: DEFAULT_FILL_CAPACITY}
     }
 
     /// Create an encrypting Chacha Source stream wrapper
-    pub fn encrypt(stream: R, key: Key, iv: IV) -> Result<Self, Error>
+    pub fn encrypt(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
     {
-	Ok(Self::new(stream, cha::encrypter(key, iv)?))
+	let (key, iv) = (key.as_ref(), iv.as_ref());
+	let mut this = Self::new(stream, cha::encrypter(key, iv)?);
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
+	let keys = (key.clone(), iv.clone());
+	this.keys = Some(keys);
+	Ok(this)
     }
     
     /// Create a decrypting Chacha Source stream wrapper
-    pub fn decrypt(stream: R, key: Key, iv: IV) -> Result<Self, Error>
+    pub fn decrypt(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
     {
-	Ok(Self::new(stream, cha::decrypter(key, iv)?))
+	let (key, iv) = (key.as_ref(), iv.as_ref());
+	let mut this = Self::new(stream, cha::decrypter(key, iv)?);
+	#[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
+	let keys = (key.clone(), iv.clone());
+	this.keys = Some(keys);
+	Ok(this)
+    }
+
+    /// Create an encrypting Chacha Source stream wrapper that appends the Poly1305 tag to its output when the inner stream reaches EOF
+    pub fn encrypt_tagged(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
+    {
+	let mut this = Self::encrypt(stream, key, iv)?;
+	this.tag = TagMode::append();
+	Ok(this)
+    }
+
+    /// Create a decrypting Chacha Source stream wrapper that verifies the Poly1305 tag at the end of the inner stream
+    ///
+    /// The last `TAG_SIZE` bytes of the inner stream are not decrypted, but checked as the tag when EOF is reached.
+    pub fn decrypt_tagged(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
+    {
+	let mut this = Self::decrypt(stream, key, iv)?;
+	this.tag = TagMode::verify();
+	Ok(this)
+    }
+
+    /// Create an encrypting Chacha Source stream wrapper, binding the additional authenticated data `aad` to the ciphertext.
+    ///
+    /// `aad` is not encrypted or output, but is included in the Poly1305 tag, which is appended to the output as with `encrypt_tagged()`.
+    pub fn encrypt_with_aad(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, Error>
+    {
+	let mut this = Self::new(stream, cha::encrypter_with_aad(key, iv, aad)?);
+	this.tag = TagMode::append();
+	Ok(this)
+    }
+
+    /// Create a decrypting Chacha Source stream wrapper, with the additional authenticated data `aad` bound to the ciphertext.
+    ///
+    /// The Poly1305 tag at the end of the inner stream is verified as with `decrypt_tagged()`, which fails if `aad` differs from what the data was encrypted with.
+    pub fn decrypt_with_aad(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, Error>
+    {
+	let mut this = Self::new(stream, cha::decrypter_with_aad(key, iv, aad)?);
+	this.tag = TagMode::verify();
+	Ok(this)
     }
     
 
     /// Consume into the inner stream
+    ///
+    /// Any plaintext from `fill_buf()` that has not been consumed yet is lost.
     #[inline] pub fn into_inner(self) -> R
     {
 	self.stream
     }
 
     /// Consume into the inner stream and crypter
+    ///
+    /// Any tag state from `encrypt_tagged()` or `decrypt_tagged()`, and any plaintext from `fill_buf()` that has not been consumed yet, is lost.
     #[inline] pub fn into_parts(self) -> (R, Crypter)
     {
 	(self.stream, self.crypter)
@@ -253,15 +546,34 @@ where R: Read
 }
 
 
+impl<R> Source<R>
+where R: Read
+{
+    /// Open a container written by `Sink::create()` or `frame::Sink::create()`.
+    ///
+    /// The container's `Header` is read from `stream`, and a decrypting reader for the rest of the stream is created from it.
+    ///
+    /// # Errors
+    /// If reading the header fails, or it is malformed (see `HeaderError`.)
+    #[inline] pub fn open(stream: R, key: impl AsRef<Key>) -> io::Result<header::Reader<R>>
+    {
+	header::Reader::open(stream, key)
+    }
+}
+
 impl<R> Source<R, UseBufferExternal>
 {
     /// Convert this instance to use internal buffer (instead of external.)
     pub fn with_internal_buffer(self) -> Source<R, UseBufferInternal>
     {
 	Source {
-	    buffer: UseBufferInternal::create_buffer(UseBufferExternal::buffer_cap(&self)),
+	    buffer: UseBufferInternal::create_buffer(UseBufferExternal::buffer_cap(&self.buffer)),
 	    crypter: self.crypter,
 	    stream: self.stream,
+	    tag: self.tag,
+	    keys: self.keys,
+	    filled: 0..0,
+	    fill_cap: self.fill_cap,
 	}
     }
 }
@@ -269,19 +581,189 @@ impl<R> Source<R, UseBufferExternal>
 impl<R> Source<R, UseBufferInternal>
 {
     /// Convert this instance to use external buffer (instead of internal.)
-    pub fn with_reused_buffer(self) -> Source<R, UseBufferExternal>
+    ///
+    /// Any plaintext from `fill_buf()` that has not been consumed yet is lost.
+    pub fn with_reused_buffer(mut self) -> Source<R, UseBufferExternal>
     {
+	bytes::prune(&mut self.buffer[self.filled.clone()]);
 	Source {
-	    buffer: UseBufferExternal::create_buffer(UseBufferInternal::buffer_cap(&self)),
+	    buffer: UseBufferExternal::create_buffer(UseBufferInternal::buffer_cap(&self.buffer)),
 	    crypter: self.crypter,
 	    stream: self.stream,
+	    tag: self.tag,
+	    keys: self.keys,
+	    filled: 0..0,
+	    fill_cap: self.fill_cap,
+	}
+    }
+}
+
+impl<R: ?Sized> Source<R, UseBufferPool>
+where R: Read
+{
+    /// Borrow buffers from `pool` instead of the one currently used.
+    #[inline] pub fn with_pool(mut self, pool: Arc<BufferPool>) -> Self
+    where R: Sized
+    {
+	self.buffer = pool;
+	self
+    }
+
+    /// The pool buffers are borrowed from
+    #[inline] pub fn pool(&self) -> &Arc<BufferPool>
+    {
+	&self.buffer
+    }
+}
+
+impl<R: ?Sized> Source<R, UseBufferInternal>
+where R: Read
+{
+    /// Set how many bytes `fill_buf()` decrypts at a time. The default is `DEFAULT_FILL_CAPACITY`.
+    ///
+    /// # Panics
+    /// If `capacity` is 0.
+    #[inline] pub fn with_buffer_capacity(mut self, capacity: usize) -> Self
+    where R: Sized
+    {
+	self.set_buffer_capacity(capacity);
+	self
+    }
+
+    /// Set how many bytes `fill_buf()` decrypts at a time. This takes effect on the next fill.
+    ///
+    /// # Panics
+    /// If `capacity` is 0.
+    #[inline] pub fn set_buffer_capacity(&mut self, capacity: usize)
+    {
+	assert!(capacity > 0, "fill capacity must not be 0");
+	self.fill_cap = capacity;
+    }
+
+    /// How many bytes `fill_buf()` decrypts at a time
+    #[inline] pub fn buffer_capacity(&self) -> usize
+    {
+	self.fill_cap
+    }
+}
+
+impl<R: ?Sized> BufRead for Source<R, UseBufferInternal>
+where R: Read
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+	if self.filled.start >= self.filled.end {
+	    let cap = self.fill_cap;
+	    self.grow_to_fit(cap * 2);
+	    // Raw bytes are read into the back half of the buffer, and decrypted into the front half.
+	    let (plain, raw) = self.buffer[..cap * 2].split_at_mut(cap);
+	    loop {
+		let read = self.stream.read(raw)?;
+		let n = self.tag.transform(&mut self.crypter, &raw[..read], plain)?;
+		#[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut raw[..read]);
+
+		// When verifying a tag, bytes read may all be held back. Keep reading until there is output or EOF.
+		if n > 0 || read == 0 {
+		    self.filled = 0..n;
+		    break;
+		}
+	    }
 	}
+	Ok(&self.buffer[self.filled.clone()])
+    }
+
+    fn consume(&mut self, amt: usize) {
+	let end = std::cmp::min(self.filled.start + amt, self.filled.end);
+	bytes::prune(&mut self.buffer[self.filled.start..end]);
+	self.filled.start = end;
+    }
+}
+
+impl<R: ?Sized, K: ?Sized + BufferKind> Seek for Source<R, K>
+where R: Read + Seek
+{
+    /// Seek to an offset in the ciphertext, repositioning the keystream to match.
+    ///
+    /// # Errors
+    /// If this source was created with a tag mode or from a crypter, if seeking the inner stream fails, or if the new position is not less than `cha::MAX_OFFSET`.
+    /// The position is left unchanged on error, except when seeking the inner stream fails.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+	let (key, iv) = match (&self.keys, &self.tag) {
+	    (Some(keys), TagMode::Ignore) => keys,
+	    _ => return Err(io::Error::new(io::ErrorKind::Unsupported, "source cannot seek: it was created with a tag mode or from a crypter")),
+	};
+	let old = match pos {
+	    SeekFrom::Start(_) => None,
+	    _ => Some(self.stream.stream_position()?),
+	};
+	// The inner stream is ahead of our position by any plaintext from `fill_buf()` that has not been consumed yet.
+	let pos = match pos {
+	    SeekFrom::Current(off) => SeekFrom::Current(off - (self.filled.end - self.filled.start) as i64),
+	    pos => pos,
+	};
+	let pos = match pos {
+	    SeekFrom::Start(pos) if pos >= cha::MAX_OFFSET => None,
+	    pos => Some(self.stream.seek(pos)?).filter(|&pos| pos < cha::MAX_OFFSET),
+	};
+	match pos {
+	    Some(pos) => {
+		self.crypter = cha::crypter_at(key, iv, pos)?;
+		if self.filled.start < self.filled.end {
+		    bytes::prune(&mut K::buffer_bytes_mut(&mut self.buffer)[self.filled.clone()]);
+		}
+		self.filled = 0..0;
+		Ok(pos)
+	    },
+	    None => {
+		if let Some(old) = old {
+		    self.stream.seek(SeekFrom::Start(old))?;
+		}
+		Err(io::Error::new(io::ErrorKind::InvalidInput, "position out of range for the chacha20 block counter"))
+	    },
+	}
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+	Ok(self.stream.stream_position()? - (self.filled.end - self.filled.start) as u64)
+    }
+}
+
+/// Decrypt `len` bytes of the ciphertext in `reader` from `offset`, without decrypting the data before it.
+///
+/// The ciphertext must start at the start of `reader`. Fewer than `len` bytes are returned if the ciphertext ends first.
+/// The data is not authenticated.
+/// ```
+/// # use chacha20stream::Sink;
+/// # use std::io::{Cursor, Write};
+/// # let (key, iv) = chacha20stream::keygen();
+/// # let mut sink = Sink::encrypt(Vec::new(), &key, &iv).unwrap();
+/// # sink.write_all(b"Hello world!").unwrap();
+/// # let encrypted = sink.into_inner();
+/// let output = chacha20stream::decrypt_range(Cursor::new(&encrypted[..]), key, iv, 6, 5).unwrap();
+/// assert_eq!(&output[..], b"world");
+/// ```
+pub fn decrypt_range<R: Read + Seek>(mut reader: R, key: impl AsRef<Key>, iv: impl AsRef<IV>, offset: u64, len: usize) -> io::Result<Vec<u8>>
+{
+    if offset >= cha::MAX_OFFSET {
+	return Err(io::Error::new(io::ErrorKind::InvalidInput, "offset out of range for chacha20 block counter"));
     }
+    reader.seek(SeekFrom::Start(offset))?;
+
+    // The ciphertext is read straight into the output and decrypted there.
+    let mut output = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut output)?;
+    cha::Transformer::at(key, iv, offset)?.apply_in_place(&mut output[..])?;
+    Ok(output)
 }
 
-fn try_alloca<T>(sz: usize, cb: impl for<'a> FnOnce(&'a mut [u8]) -> T) -> T
+pub(crate) fn try_alloca<T>(sz: usize, cb: impl for<'a> FnOnce(&'a mut [u8]) -> T) -> T
 {
-    if sz > STACK_MAX_BYTES {
+    alloca_max(sz, UseBufferExternal::stack_max_bytes(), cb)
+}
+
+/// Call `cb` with a zeroed buffer of `sz` bytes, allocated on the stack if it is no larger than `max`.
+pub(crate) fn alloca_max<T>(sz: usize, max: usize, cb: impl for<'a> FnOnce(&'a mut [u8]) -> T) -> T
+{
+    if sz > max {
 	let mut bytes = vec![0u8; sz];
 	cb(&mut bytes[..])
     } else {
@@ -293,23 +775,87 @@ impl<R: ?Sized, K: ?Sized + BufferKind> Read for Source<R, K>
 where R: Read
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-	if cfg!(feature="ad-hoc-buffer") {
-	    //XXX: FUck, we can't `crypter.update()` in place....
-	    
-	    try_alloca(buf.len(), move |temp| -> io::Result<usize> {
-		let read = self.stream.read(temp)?;
-		let b = self.transform_into(&temp[..read], &mut buf[..read])?;
-		#[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut temp[..b]);
-		Ok(b)
-	    })
+	if buf.is_empty() {
+	    return Ok(0);
+	}
+	if self.filled.start < self.filled.end {
+	    return Ok(self.read_filled(buf));
+	}
+	loop {
+	    let (read, b) = if K::PHANTOM {
+		// `Crypter::update()` needs separate input and output buffers, and held back tag bytes shift the output, so read into scratch space.
+		let (stream, tag, crypter) = (&mut self.stream, &mut self.tag, &mut self.crypter);
+		K::with_scratch(&self.buffer, buf.len(), |temp| -> io::Result<(usize, usize)> {
+		    let read = stream.read(temp)?;
+		    let b = tag.transform(crypter, &temp[..read], buf)?;
+		    #[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut temp[..read]);
+		    Ok((read, b))
+		})?
+	    }
+	    else {
+		self.grow_to_fit(buf.len()); 
+		let read = self.stream.read(&mut K::buffer_bytes_mut(&mut self.buffer)[..buf.len()])?;
+		let b = self.transform(read, buf)?;
+
+		#[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut K::buffer_bytes_mut(&mut self.buffer)[..read]);
+		(read, b)
+	    };
+	    // When verifying a tag, bytes read may all be held back. Keep reading until there is output or EOF.
+	    if b > 0 || read == 0 {
+		return Ok(b);
+	    }
+	}
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+	let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+	if len == 0 {
+	    return Ok(0);
+	}
+	if self.filled.start < self.filled.end {
+	    return Ok(bufs.iter_mut().map(|buf| self.read_filled(buf)).sum());
 	}
-	else {
-	    self.grow_to_fit(buf.len()); 
-	    let read = self.stream.read(&mut K::buffer_bytes_mut(&mut self.buffer)[..buf.len()])?;
-	    let b = self.transform(read, &mut buf[..read])?;
+	loop {
+	    let (read, b) = if K::PHANTOM {
+		let (stream, tag, crypter) = (&mut self.stream, &mut self.tag, &mut self.crypter);
+		K::with_scratch(&self.buffer, len * 2, |temp| read_scattered(stream, tag, crypter, temp, bufs))?
+	    } else {
+		self.grow_to_fit(len * 2);
+		let temp = &mut K::buffer_bytes_mut(&mut self.buffer)[..len * 2];
+		read_scattered(&mut self.stream, &mut self.tag, &mut self.crypter, temp, bufs)?
+	    };
+	    // When verifying a tag, bytes read may all be held back. Keep reading until there is output or EOF.
+	    if b > 0 || read == 0 {
+		return Ok(b);
+	    }
+	}
+    }
+}
 
-	    #[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut K::buffer_bytes_mut(&mut self.buffer)[..b]);
-	    Ok(b)
+/// Read up to half of `temp` from `stream` in one call, and transform it into the other half, then scatter it across `bufs`.
+///
+/// Returns the number of bytes read, and the number of bytes output.
+fn read_scattered<R: ?Sized + Read>(stream: &mut R, tag: &mut TagMode, crypter: &mut Crypter, temp: &mut [u8], bufs: &mut [IoSliceMut<'_>]) -> io::Result<(usize, usize)>
+{
+    let (raw, plain) = temp.split_at_mut(temp.len() / 2);
+    let read = stream.read(raw)?;
+    let b = tag.transform(crypter, &raw[..read], plain)?;
+    scatter(&plain[..b], bufs);
+
+    bytes::prune(&mut plain[..b]);
+    #[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut raw[..read]);
+    Ok((read, b))
+}
+
+/// Copy `from` across `bufs` in order. `bufs` must have room for all of it.
+fn scatter(mut from: &[u8], bufs: &mut [IoSliceMut<'_>])
+{
+    for buf in bufs {
+	if from.is_empty() {
+	    break;
 	}
+	let n = std::cmp::min(buf.len(), from.len());
+	buf[..n].copy_from_slice(&from[..n]);
+	from = &from[n..];
     }
 }
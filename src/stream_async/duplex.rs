@@ -0,0 +1,234 @@
+//! Asyncronous two-way stream wrapper.
+use super::*;
+#[cfg(any(feature="tokio1", feature="futures-io"))]
+use std::io::IoSlice;
+
+/// Async two-way ChaCha stream.
+/// Encrypts data written to it to the inner stream, and decrypts data read from the inner stream.
+///
+/// This is the async counterpart to the syncronous `Stream`, for sockets and other two-way streams. It implements `AsyncRead` and `AsyncWrite` for each of the enabled async runtime features that the inner stream does.
+///
+/// # Splitting
+/// `split()` and `split_with()` produce an `AsyncSource` and an `AsyncSink` that carry on with this stream's crypters.
+/// Tokio's `io::split()` can be passed straight to `split_with()`.
+//#[derive(Debug)]
+#[pin_project]
+pub struct Stream<S>
+{
+    #[pin] sink: Sink<S>, // Owns the inner stream, and handles writes.
+    crypter: Crypter, // Decrypts reads.
+    buffer: BufferVec, // Raw bytes read from the inner stream for the current `poll_read()`. Nothing is kept here over a yield.
+}
+
+impl<S: fmt::Debug> fmt::Debug for Stream<S>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+	write!(f, "Stream({:?}, ({} buffer cap))", self.sink.inner(), self.buffer.capacity())
+    }
+}
+
+impl<S> Stream<S>
+{
+    /// Create a new async two-way Chacha stream wrapper, encrypting writes with `write_key` and `write_iv`, and decrypting reads with `read_key` and `read_iv`.
+    pub fn new(stream: S, write_key: impl AsRef<Key>, write_iv: impl AsRef<IV>, read_key: impl AsRef<Key>, read_iv: impl AsRef<IV>) -> Result<Self, Error>
+    {
+	Ok(Self::from_parts(stream, cha::encrypter(write_key, write_iv)?, cha::decrypter(read_key, read_iv)?))
+    }
+
+    /// Create a stream from an inner stream, the crypter for writes, and the crypter for reads.
+    ///
+    /// The counterpart to `into_parts()`.
+    #[inline] pub fn from_parts(stream: S, encrypter: Crypter, decrypter: Crypter) -> Self
+    {
+	Self {
+	    sink: Sink::from_parts(stream, encrypter),
+	    crypter: decrypter,
+	    buffer: BufferVec::new(),
+	}
+    }
+
+    /// Consume into the inner stream
+    ///
+    /// Any pending written data not yet written to the inner stream is lost. Flush the stream first to write it.
+    #[inline] pub fn into_inner(self) -> S
+    {
+	self.sink.into_inner()
+    }
+
+    /// Consume into the inner stream, the crypter for writes, and the crypter for reads.
+    ///
+    /// Any pending written data not yet written to the inner stream is lost. Flush the stream first to write it.
+    #[inline] pub fn into_parts(self) -> (S, Crypter, Crypter)
+    {
+	let (stream, encrypter) = self.sink.into_parts();
+	(stream, encrypter, self.crypter)
+    }
+
+    /// Split into a `Source` that decrypts reads from the inner stream, and a `Sink` that encrypts writes to a clone of it.
+    ///
+    /// Any pending written data is kept by the `Sink`.
+    #[inline] pub fn split(self) -> (Source<S>, Sink<S>)
+    where S: Clone
+    {
+	self.split_with(|stream| (stream.clone(), stream))
+    }
+
+    /// Split into a `Source` that decrypts reads from, and a `Sink` that encrypts writes to, the read and write halves `f` splits the inner stream into.
+    ///
+    /// Any pending written data is kept by the `Sink`.
+    pub fn split_with<R, W, F>(self, f: F) -> (Source<R>, Sink<W>)
+    where F: FnOnce(S) -> (R, W)
+    {
+	let mut read = None;
+	let sink = self.sink.map_inner(|stream| {
+	    let (r, w) = f(stream);
+	    read = Some(r);
+	    w
+	});
+	(Source::from_parts(read.expect("split_with() callback was not called"), self.crypter), sink)
+    }
+
+    /// The crypter used to encrypt writes
+    #[inline] pub fn encrypter(&self) -> &Crypter
+    {
+	self.sink.crypter()
+    }
+
+    /// The crypter used to encrypt writes
+    #[inline] pub fn encrypter_mut(&mut self) -> &mut Crypter
+    {
+	self.sink.crypter_mut()
+    }
+
+    /// The crypter used to decrypt reads
+    #[inline] pub fn decrypter(&self) -> &Crypter
+    {
+	&self.crypter
+    }
+
+    /// The crypter used to decrypt reads
+    #[inline] pub fn decrypter_mut(&mut self) -> &mut Crypter
+    {
+	&mut self.crypter
+    }
+
+    /// The inner stream
+    #[inline] pub fn inner(&self) -> &S
+    {
+	self.sink.inner()
+    }
+
+    /// The inner stream
+    #[inline] pub fn inner_mut(&mut self) -> &mut S
+    {
+	self.sink.inner_mut()
+    }
+
+    /// Clear the internal buffers while keeping them allocated for further use.
+    ///
+    /// Pending written data that has not been written to the inner stream yet is kept.
+    #[inline]
+    pub fn prune(&mut self)
+    {
+	self.sink.prune();
+	bytes::prune(&mut self.buffer[..]);
+    }
+
+    /// Read raw bytes from the inner stream with `read`, decrypting them into `buf`.
+    fn poll_read_with<F>(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8], read: F) -> Poll<io::Result<usize>>
+    where F: FnOnce(Pin<&mut S>, &mut Context<'_>, &mut [u8]) -> Poll<io::Result<usize>>
+    {
+	let this = self.project();
+	if buf.len() > this.buffer.len() {
+	    this.buffer.resize(buf.len(), 0);
+	}
+	let raw = &mut this.buffer[..buf.len()];
+	let read = ready!(read(this.sink.inner_pinned(), cx, raw))?;
+	let n = this.crypter.update(&raw[..read], &mut buf[..read])?;
+
+	#[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut raw[..read]);
+	Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(feature="async")]
+impl<S: tokio::io::AsyncRead> tokio::io::AsyncRead for Stream<S>
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+	self.poll_read_with(cx, buf, S::poll_read)
+    }
+}
+
+#[cfg(feature="async")]
+impl<S: tokio::io::AsyncWrite> tokio::io::AsyncWrite for Stream<S>
+{
+    #[inline] fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+	self.project().sink.poll_write(cx, buf)
+    }
+    #[inline] fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.project().sink.poll_flush(cx)
+    }
+    #[inline] fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.project().sink.poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature="tokio1")]
+impl<S: tokio1::io::AsyncRead> tokio1::io::AsyncRead for Stream<S>
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio1::io::ReadBuf<'_>) -> Poll<io::Result<()>> {
+	let n = ready!(self.poll_read_with(cx, buf.initialize_unfilled(), |stream, cx, raw| {
+	    let mut raw = tokio1::io::ReadBuf::new(raw);
+	    ready!(stream.poll_read(cx, &mut raw))?;
+	    Poll::Ready(Ok(raw.filled().len()))
+	}))?;
+	buf.advance(n);
+	Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature="tokio1")]
+impl<S: tokio1::io::AsyncWrite> tokio1::io::AsyncWrite for Stream<S>
+{
+    #[inline] fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+	self.project().sink.poll_write(cx, buf)
+    }
+    #[inline] fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<Result<usize, io::Error>> {
+	self.project().sink.poll_write_vectored(cx, bufs)
+    }
+    #[inline] fn is_write_vectored(&self) -> bool {
+	self.sink.is_write_vectored()
+    }
+    #[inline] fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.project().sink.poll_flush(cx)
+    }
+    #[inline] fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.project().sink.poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature="futures-io")]
+impl<S: futures_io::AsyncRead> futures_io::AsyncRead for Stream<S>
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+	self.poll_read_with(cx, buf, S::poll_read)
+    }
+}
+
+#[cfg(feature="futures-io")]
+impl<S: futures_io::AsyncWrite> futures_io::AsyncWrite for Stream<S>
+{
+    #[inline] fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+	self.project().sink.poll_write(cx, buf)
+    }
+    #[inline] fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<Result<usize, io::Error>> {
+	self.project().sink.poll_write_vectored(cx, bufs)
+    }
+    #[inline] fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.project().sink.poll_flush(cx)
+    }
+    #[inline] fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.project().sink.poll_close(cx)
+    }
+}
@@ -2,10 +2,9 @@ use super::*;
 use key::*;
 
 use std::io;
-use tokio::io::AsyncWrite;
 use std::{
     pin::Pin,
-    task::{Context, Poll},
+    task::{Context, Poll, ready},
 };
 
 use std::fmt;
@@ -24,6 +23,7 @@ type BufferVec = smallvec::SmallVec<[u8; BUFFER_SIZE]>;
 type BufferVec = Vec<u8>;
 
 pub type Error = ErrorStack;
+use crate::error::AuthError;
 
 pub mod sink;
 pub use sink::Sink;
@@ -31,143 +31,742 @@ pub use sink::Sink;
 pub mod source;
 pub use source::Source;
 
-#[cfg(test)]
-mod test
+pub mod duplex;
+pub use duplex::Stream;
+
+/// Runtime-independent test readers and writers.
+///
+/// Each runtime's test module only implements its own `AsyncRead` and `AsyncWrite` on these.
+#[cfg(all(test, any(feature="async", feature="tokio1", feature="futures-io")))]
+mod fixtures
 {
-    use tokio::prelude::*;
+    use std::{
+	io,
+	task::{Context, Poll},
+    };
 
-    #[tokio::test]
-    async fn async_source_enc_dec()
+    /// A writer that accepts at most `max` bytes per poll, and is not ready every `pending_every`th poll.
+    pub struct ChoppyWriter
     {
-	use crate::ext::*;
-	const INPUT: &'static [u8] = b"Hello world!";
-	let (key, iv) = crate::cha::keygen();
+	pub inner: Vec<u8>,
+	max: usize,
+	pending_every: usize,
+	polls: usize,
+    }
 
-	println!("Input: {}", INPUT.hex());
-	let mut enc = super::Source::encrypt(&INPUT[..], key, iv).expect("Failed to create encryptor");
-	let mut enc_out = Vec::with_capacity(INPUT.len());
-	tokio::io::copy(&mut enc, &mut enc_out).await.expect("Failed to copy encrypted output");
+    impl ChoppyWriter
+    {
+	pub fn new(max: usize, pending_every: usize) -> Self
+	{
+	    Self { inner: Vec::new(), max, pending_every, polls: 0 }
+	}
 
-	println!("(enc) output: {}", enc_out.hex());
+	/// Take up to `max` bytes of `buf`, unless this poll is not ready.
+	pub fn poll_take(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>
+	{
+	    self.polls += 1;
+	    if self.polls == self.pending_every {
+		self.polls = 0;
+		cx.waker().wake_by_ref();
+		return Poll::Pending;
+	    }
+	    let n = std::cmp::min(buf.len(), self.max);
+	    self.inner.extend_from_slice(&buf[..n]);
+	    Poll::Ready(Ok(n))
+	}
+    }
 
-	let mut dec = super::Source::decrypt(&enc_out[..], key, iv).expect("Failed to create decryptor");
-	let mut dec_out = Vec::with_capacity(INPUT.len());
-	tokio::io::copy(&mut dec, &mut dec_out).await.expect("Failed to copy decrypted output");
+    /// A reader that returns at most `max` bytes per poll, and is not ready every `pending_every`th poll.
+    pub struct ChoppyReader<'a>
+    {
+	inner: &'a [u8],
+	max: usize,
+	pending_every: usize,
+	polls: usize,
+    }
 
-	println!("(dec) output: {}", dec_out.hex());
+    impl<'a> ChoppyReader<'a>
+    {
+	pub fn new(inner: &'a [u8], max: usize, pending_every: usize) -> Self
+	{
+	    Self { inner, max, pending_every, polls: 0 }
+	}
 
-	assert_eq!(&dec_out[..], INPUT);
+	/// Copy up to `max` bytes into `buf`, unless this poll is not ready, returning the number copied.
+	pub fn poll_give(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<usize>
+	{
+	    self.polls += 1;
+	    if self.polls == self.pending_every {
+		self.polls = 0;
+		cx.waker().wake_by_ref();
+		return Poll::Pending;
+	    }
+	    let n = std::cmp::min(std::cmp::min(buf.len(), self.max), self.inner.len());
+	    buf[..n].copy_from_slice(&self.inner[..n]);
+	    self.inner = &self.inner[n..];
+	    Poll::Ready(n)
+	}
     }
-    
-    #[tokio::test]
-    async fn sink_sync()
+
+    /// Encrypt `input` with the synchronous `Sink`, for comparison.
+    pub fn encrypt_sync(input: &[u8], key: &crate::Key, iv: &crate::IV) -> Vec<u8>
     {
-	let mut output = Vec::new();
-	let input = "Hello world!";
-	let (key, iv) = crate::cha::keygen();
-	
-	let encrypted = {
-	    let mut sink = super::Sink::encrypt(&mut output, key, iv).expect("Sink::encrypt");
-	    sink.write_all(input.as_bytes()).await.expect("Sink::write_all");
-	    sink.flush().await.expect("Sink::flush");
-	    sink.shutdown().await.expect("Sink::shutdown");
+	use std::io::Write;
+	let mut sink = crate::Sink::encrypt(Vec::new(), key, iv).expect("Sink::encrypt");
+	sink.write_all(input).unwrap();
+	sink.flush().unwrap();
+	sink.into_inner()
+    }
+}
 
-	    sink.into_inner().clone()
-	};
+/// The tests shared by the tokio 0.2 and tokio 1 implementations, using the runtime crate `$tokio` and its test attribute `$test`.
+///
+/// The invoking module provides the imports, and implements the runtime's `AsyncRead` and `AsyncWrite` for the `fixtures`.
+#[cfg(all(test, any(feature="async", feature="tokio1")))]
+macro_rules! tokio_tests
+{
+    ($tokio:ident, #[$test:meta]) => {
+	#[$test]
+	async fn async_source_enc_dec()
+	{
+	    use crate::ext::*;
+	    const INPUT: &[u8] = b"Hello world!";
+	    let (key, iv) = crate::cha::keygen();
+
+	    println!("Input: {}", INPUT.hex());
+	    let mut enc: super::Source<_> = super::Source::encrypt(INPUT, &key, &iv).expect("Failed to create encryptor");
+	    let mut enc_out = Vec::with_capacity(INPUT.len());
+	    $tokio::io::copy(&mut enc, &mut enc_out).await.expect("Failed to copy encrypted output");
+
+	    println!("(enc) output: {}", enc_out.hex());
+
+	    let mut dec: super::Source<_> = super::Source::decrypt(&enc_out[..], &key, &iv).expect("Failed to create decryptor");
+	    let mut dec_out = Vec::with_capacity(INPUT.len());
+	    $tokio::io::copy(&mut dec, &mut dec_out).await.expect("Failed to copy decrypted output");
+
+	    println!("(dec) output: {}", dec_out.hex());
+
+	    assert_eq!(&dec_out[..], INPUT);
+	}
 
-	output.clear();
-	let decrypted = {
-	    let mut sink = super::Sink::decrypt(&mut output, key, iv).expect("Sink::decrypt");
+	#[$test]
+	async fn async_aad()
+	{
+	    const INPUT: &[u8] = b"Hello world!";
+	    const AAD: &[u8] = b"routing header";
+	    let (key, iv) = crate::cha::keygen();
+
+	    let mut sink = super::Sink::encrypt_with_aad(Vec::new(), &key, &iv, AAD).expect("Sink::encrypt_with_aad");
+	    sink.write_all(INPUT).await.expect("Sink::write_all");
+	    // The written data is still pending, so the sink can't be finished until it is flushed
+	    assert!(matches!(sink.finish(), Err(crate::error::AuthError::Pending)));
+	    sink.flush().await.expect("Sink::flush");
+	    let tag = sink.finish().expect("Sink::finish");
+	    // Finishing resets the keystream, so a finished sink can't be written to or finished again
+	    assert!(sink.write_all(INPUT).await.is_err());
+	    assert!(matches!(sink.finish(), Err(crate::error::AuthError::Finished)));
+	    let encrypted = sink.into_inner();
+
+	    let mut sink = super::Sink::decrypt_with_aad(Vec::new(), &key, &iv, AAD).expect("Sink::decrypt_with_aad");
 	    sink.write_all(&encrypted[..]).await.expect("Sink::write_all");
-	    
+	    assert!(matches!(sink.finish_verify(&tag), Err(crate::error::AuthError::Pending)));
+	    sink.flush().await.expect("Sink::flush");
+	    sink.finish_verify(&tag).expect("Sink::finish_verify");
+	    assert_eq!(&sink.into_inner()[..], INPUT);
+
+	    let mut enc: super::Source<_> = super::Source::encrypt_with_aad(INPUT, &key, &iv, AAD).expect("Source::encrypt_with_aad");
+	    let mut enc_out = Vec::new();
+	    $tokio::io::copy(&mut enc, &mut enc_out).await.expect("Failed to copy encrypted output");
+	    assert_eq!(&enc_out[..INPUT.len()], &encrypted[..]);
+	    assert_eq!(&enc_out[INPUT.len()..], tag.as_ref());
+
+	    let mut dec: super::Source<_> = super::Source::decrypt_with_aad(&enc_out[..], &key, &iv, AAD).expect("Source::decrypt_with_aad");
+	    let mut dec_out = Vec::new();
+	    $tokio::io::copy(&mut dec, &mut dec_out).await.expect("Failed to copy decrypted output");
+	    assert_eq!(&dec_out[..], INPUT);
+
+	    let mut dec: super::Source<_> = super::Source::decrypt_with_aad(&enc_out[..], &key, &iv, b"").expect("Source::decrypt_with_aad");
+	    let err = $tokio::io::copy(&mut dec, &mut Vec::new()).await.expect_err("Wrong AAD was verified");
+	    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+	}
+
+	#[$test]
+	async fn sink_sync()
+	{
+	    let mut output = Vec::new();
+	    let input = "Hello world!";
+	    let (key, iv) = crate::cha::keygen();
+
+	    let encrypted = {
+		let mut sink = super::Sink::encrypt(&mut output, &key, &iv).expect("Sink::encrypt");
+		sink.write_all(input.as_bytes()).await.expect("Sink::write_all");
+		sink.flush().await.expect("Sink::flush");
+		sink.shutdown().await.expect("Sink::shutdown");
+
+		sink.into_inner().clone()
+	    };
+
+	    output.clear();
+	    let decrypted = {
+		let mut sink = super::Sink::decrypt(&mut output, &key, &iv).expect("Sink::decrypt");
+		sink.write_all(&encrypted[..]).await.expect("Sink::write_all");
+
+		sink.flush().await.expect("Sink::flush");
+		sink.shutdown().await.expect("Sink::shutdown");
+
+		sink.into_inner().clone()
+	    };
+	    assert_eq!(&decrypted[..], input.as_bytes());
+	}
+
+	#[$test]
+	async fn sink_mem()
+	{
+	    const BACKLOG: usize = 4;
+	    let (mut client, mut server) = $tokio::io::duplex(BACKLOG);
+	    let (key, iv) = crate::cha::keygen();
+
+	    let input = "Hello!";
+
+	    #[cfg_attr(not(feature="zeroize"), allow(clippy::clone_on_copy))]
+	    let (enckey, enciv) = (key.clone(), iv.clone());
+	    let enctask = $tokio::spawn(async move {
+		let mut sink = super::Sink::encrypt(&mut client, &enckey, &enciv).expect("Sink::encrypt");
+		sink.write_all(input.as_bytes()).await.expect("Sink::write_all");
+		sink.flush().await.expect("Sink::flush");
+		sink.shutdown().await.expect("Sink::shutdown");
+
+		drop(client);
+	    });
+
+	    let (mut declient, mut deserver) = $tokio::io::duplex(BACKLOG * 2);
+	    let dectask = $tokio::spawn(async move {
+		let mut sink = super::Sink::decrypt(&mut declient, &key, &iv).expect("Sink::encrypt");
+		$tokio::io::copy(&mut server, &mut sink).await.expect("Copy to sink failed");
+		sink.flush().await.expect("Sink::flush");
+		sink.shutdown().await.expect("Sink::shutdown");
+	    });
+
+	    let (de, en) = $tokio::join![dectask, enctask];
+
+	    de.expect("Dec task panic");
+	    en.expect("Enc task panic");
+
+	    let mut output = Vec::new();
+	    $tokio::io::copy(&mut deserver, &mut output).await.expect("Copy into vec");
+
+	    println!("In: {}, Out: {}", String::from_utf8_lossy(&output[..]), input);
+	    assert_eq!(&output[..], input.as_bytes());
+	}
+
+	#[$test]
+	async fn sink_short_writes()
+	{
+	    const INPUT: &[u8] = b"Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!";
+	    let (key, iv) = crate::cha::keygen();
+	    let expected = encrypt_sync(INPUT, &key, &iv);
+
+	    for &(max, pending_every) in &[(1, 2), (3, 3), (5, 2), (100, 2)] {
+		let mut sink = super::Sink::encrypt(ChoppyWriter::new(max, pending_every), &key, &iv).expect("Sink::encrypt");
+		for chunk in INPUT.chunks(7) {
+		    sink.write_all(chunk).await.expect("Sink::write_all");
+		}
+		sink.flush().await.expect("Sink::flush");
+		sink.shutdown().await.expect("Sink::shutdown");
+		assert_eq!(&sink.into_inner().inner[..], &expected[..], "max: {}, pending_every: {}", max, pending_every);
+	    }
+	}
+
+	#[$test]
+	async fn sink_cancel()
+	{
+	    const INPUT: &[u8] = b"Hello world!Hello world!Hello world!Hello world!";
+	    let (key, iv) = crate::cha::keygen();
+	    let (first, rest) = INPUT.split_at(20);
+
+	    let (client, mut server) = $tokio::io::duplex(8);
+	    let mut sink = super::Sink::encrypt(client, &key, &iv).expect("Sink::encrypt");
+
+	    // This is accepted, but only written to the backing stream by the next write or flush.
+	    sink.write_all(first).await.expect("Sink::write_all");
+
+	    // The backing stream only takes 8 bytes of the pending data until the other end reads, so this never accepts anything before it is dropped.
+	    let write = $tokio::time::timeout(std::time::Duration::from_millis(50), sink.write_all(rest)).await;
+	    assert!(write.is_err(), "write should not have completed");
+
+	    let reader = $tokio::spawn(async move {
+		let mut output = Vec::new();
+		server.read_to_end(&mut output).await.expect("read_to_end");
+		output
+	    });
+
+	    sink.write_all(rest).await.expect("Sink::write_all");
 	    sink.flush().await.expect("Sink::flush");
 	    sink.shutdown().await.expect("Sink::shutdown");
+	    drop(sink);
 
-	    sink.into_inner().clone()
-	};
-	assert_eq!(&decrypted[..], input.as_bytes());
+	    assert_eq!(&reader.await.expect("reader panic")[..], &encrypt_sync(INPUT, &key, &iv)[..]);
+	}
+
+	/// A writer that fails while `broken` is set.
+	struct BreakableWriter
+	{
+	    inner: Vec<u8>,
+	    broken: bool,
+	}
+
+	impl AsyncWrite for BreakableWriter
+	{
+	    fn poll_write(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		if self.broken {
+		    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+		}
+		self.inner.extend_from_slice(buf);
+		Poll::Ready(Ok(buf.len()))
+	    }
+	    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	    }
+	    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	    }
+	}
+
+	#[$test]
+	async fn sink_write_errors()
+	{
+	    let input: Vec<u8> = (0..super::sink::MAX_PENDING + 100).map(|i| (i % 251) as u8).collect();
+	    let (key, iv) = crate::cha::keygen();
+	    let mut sink = super::Sink::encrypt(BreakableWriter { inner: Vec::new(), broken: true }, &key, &iv).expect("Sink::encrypt");
+
+	    // Only `MAX_PENDING` bytes are accepted at once, and nothing is written yet.
+	    assert_eq!(sink.write(&input[..]).await.expect("Sink::write"), super::sink::MAX_PENDING);
+
+	    // The pending data cannot be written, so the error is returned and nothing more is accepted.
+	    let err = sink.write(&input[super::sink::MAX_PENDING..]).await.expect_err("write to broken stream");
+	    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+	    sink.flush().await.expect_err("flush to broken stream");
+
+	    sink.inner_mut().broken = false;
+	    sink.write_all(&input[super::sink::MAX_PENDING..]).await.expect("Sink::write_all");
+	    sink.flush().await.expect("Sink::flush");
+	    assert_eq!(&sink.into_inner().inner[..], &encrypt_sync(&input[..], &key, &iv)[..]);
+	}
+
+	/// Read `source` to the end, with a different buffer size for each read.
+	async fn read_varying<R: AsyncRead + Unpin>(mut source: R) -> io::Result<Vec<u8>>
+	{
+	    const SIZES: &[usize] = &[1, 7, 64, 3, 200, 16, 5000];
+	    let mut output = Vec::new();
+	    let mut buf = vec![0u8; 5000];
+	    for &size in SIZES.iter().cycle() {
+		match source.read(&mut buf[..size]).await? {
+		    0 => break,
+		    n => output.extend_from_slice(&buf[..n]),
+		}
+	    }
+	    Ok(output)
+	}
+
+	#[$test]
+	async fn source_varying_reads()
+	{
+	    use super::source::{UseBufferInternal, UseBufferExternal};
+	    let input: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+	    let (key, iv) = crate::cha::keygen();
+	    let expected = encrypt_sync(&input[..], &key, &iv);
+
+	    for &(max, pending_every) in &[(1, 2), (13, 3), (100, 2), (10000, 2)] {
+		let choppy = |inner| ChoppyReader::new(inner, max, pending_every);
+
+		let source = super::Source::<_, UseBufferInternal>::encrypt(choppy(&input[..]), &key, &iv).expect("Source::encrypt");
+		assert_eq!(read_varying(source).await.expect("internal encrypt"), expected, "max: {}, pending_every: {}", max, pending_every);
+
+		let source = super::Source::<_, UseBufferExternal>::decrypt(choppy(&expected[..]), &key, &iv).expect("Source::decrypt");
+		assert_eq!(read_varying(source).await.expect("external decrypt"), input, "max: {}, pending_every: {}", max, pending_every);
+
+		// Converting between buffer kinds keeps the keystream position
+		let mut source = super::Source::<_, UseBufferExternal>::decrypt(choppy(&expected[..]), &key, &iv).expect("Source::decrypt");
+		let mut output = vec![0u8; 100];
+		source.read_exact(&mut output[..]).await.expect("read_exact");
+		output.extend(read_varying(source.with_internal_buffer()).await.expect("converted decrypt"));
+		assert_eq!(output, input);
+	    }
+
+	    let mut tagged: super::Source<_> = super::Source::encrypt_tagged(&input[..], &key, &iv).expect("Source::encrypt_tagged");
+	    let mut encrypted = Vec::new();
+	    tagged.read_to_end(&mut encrypted).await.expect("read_to_end");
+	    for &(max, pending_every) in &[(1, 2), (17, 3), (10000, 2)] {
+		let source = super::Source::<_, UseBufferInternal>::decrypt_tagged(ChoppyReader::new(&encrypted[..], max, pending_every), &key, &iv).expect("Source::decrypt_tagged");
+		assert_eq!(read_varying(source).await.expect("internal verify"), input);
+		let source = super::Source::<_, UseBufferExternal>::decrypt_tagged(ChoppyReader::new(&encrypted[..], max, pending_every), &key, &iv).expect("Source::decrypt_tagged");
+		assert_eq!(read_varying(source).await.expect("external verify"), input);
+	    }
+	}
+
+	#[$test]
+	async fn duplex()
+	{
+	    const INPUT: &[u8] = b"Hello world!Hello world!Hello world!Hello world!";
+	    let (ours, theirs) = (crate::cha::keygen(), crate::cha::keygen());
+	    // Big enough for the whole message both ways, as `a` does not read until it has written all of it.
+	    let (a, b) = $tokio::io::duplex(64);
+
+	    let mut a = super::Stream::new(a, &ours.0, &ours.1, &theirs.0, &theirs.1).expect("Stream::new");
+	    let b = super::Stream::new(b, &theirs.0, &theirs.1, &ours.0, &ours.1).expect("Stream::new");
+
+	    // The other end echoes what it reads back, with its halves in separate tasks.
+	    let (mut source, mut sink) = b.split_with($tokio::io::split);
+	    let echo = $tokio::spawn(async move {
+		let mut buf = [0u8; 7];
+		loop {
+		    match source.read(&mut buf[..]).await.expect("Source::read") {
+			0 => break,
+			n => sink.write_all(&buf[..n]).await.expect("Sink::write_all"),
+		    }
+		    sink.flush().await.expect("Sink::flush");
+		}
+		sink.shutdown().await.expect("Sink::shutdown");
+	    });
+
+	    let mut output = vec![0u8; INPUT.len()];
+	    for chunk in INPUT.chunks(10) {
+		a.write_all(chunk).await.expect("Stream::write_all");
+	    }
+	    a.flush().await.expect("Stream::flush");
+	    a.read_exact(&mut output[..]).await.expect("Stream::read_exact");
+	    assert_eq!(&output[..], INPUT);
+
+	    a.shutdown().await.expect("Stream::shutdown");
+	    echo.await.expect("echo task panic");
+	    assert_eq!(a.read(&mut output[..]).await.expect("Stream::read"), 0);
+	}
+
+	#[$test]
+	async fn sink_files()
+	{
+	    let mut output = $tokio::fs::File::from_std(tempfile::tempfile().unwrap());
+
+	    let input = "Hello world!".repeat(64);
+	    let (key, iv) = crate::cha::keygen();
+
+	    {
+		let mut sink = super::Sink::encrypt(&mut output, &key, &iv).expect("Sink::encrypt");
+		sink.write_all(input.as_bytes()).await.expect("Sink::write_all");
+		sink.flush().await.expect("Sink::flush");
+		sink.shutdown().await.expect("Sink::shutdown");
+	    }
+
+	    let mut encrypted = output;
+	    encrypted.seek(io::SeekFrom::Start(0)).await.unwrap();
+
+	    let mut output = $tokio::fs::File::from_std(tempfile::tempfile().unwrap());
+	    {
+		let mut sink = super::Sink::decrypt(&mut output, &key, &iv).expect("Sink::decrypt");
+		$tokio::io::copy(&mut encrypted, &mut sink).await.expect("Copy to sink");
+
+		sink.flush().await.expect("Sink::flush");
+		sink.shutdown().await.expect("Sink::shutdown");
+	    }
+	    let mut decrypted = output;
+
+	    let (r1, r2) = $tokio::join![encrypted.sync_data(),
+					 decrypted.sync_data()];
+	    r1.expect("enc sync");
+	    r2.expect("dec sync");
+
+	    let decrypted = {
+		decrypted.seek(io::SeekFrom::Start(0)).await.unwrap();
+
+		let mut output = vec![0u8; input.len()];
+		decrypted.read_exact(&mut output[..]).await.expect("Read decrypted");
+
+		output
+	    };
+
+	    assert_eq!(&decrypted[..], input.as_bytes());
+	}
+    };
+}
+
+#[cfg(all(test, feature="async"))]
+#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+mod test
+{
+    use super::fixtures::*;
+    use tokio::prelude::*;
+    use std::{
+	io,
+	pin::Pin,
+	task::{Context, Poll},
+    };
+
+    tokio_tests!(tokio, #[tokio::test]);
+
+    impl AsyncRead for ChoppyReader<'_>
+    {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+	    self.get_mut().poll_give(cx, buf).map(Ok)
+	}
+    }
+
+    impl AsyncWrite for ChoppyWriter
+    {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+	    self.get_mut().poll_take(cx, buf)
+	}
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+	    Poll::Ready(Ok(()))
+	}
+	fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+	    Poll::Ready(Ok(()))
+	}
+    }
+}
+
+#[cfg(all(test, feature="tokio1"))]
+#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+mod test_tokio1
+{
+    use super::fixtures::*;
+    use tokio1::io::{
+	AsyncRead, AsyncWrite, ReadBuf,
+	AsyncReadExt, AsyncWriteExt, AsyncSeekExt,
+    };
+    use std::{
+	io::{self, IoSlice},
+	pin::Pin,
+	task::{Context, Poll, ready},
+    };
+
+    tokio_tests!(tokio1, #[tokio1::test(crate = "tokio1")]);
+
+    impl AsyncRead for ChoppyReader<'_>
+    {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+	    let n = ready!(self.get_mut().poll_give(cx, buf.initialize_unfilled()));
+	    buf.advance(n);
+	    Poll::Ready(Ok(()))
+	}
+    }
+
+    impl AsyncWrite for ChoppyWriter
+    {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+	    self.get_mut().poll_take(cx, buf)
+	}
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+	    Poll::Ready(Ok(()))
+	}
+	fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+	    Poll::Ready(Ok(()))
+	}
     }
-    #[tokio::test]
-    async fn sink_mem()
+
+    #[tokio1::test(crate = "tokio1")]
+    async fn sink_vectored()
     {
-	const BACKLOG: usize = 4;
-	let (mut client, mut server) = tokio::io::duplex(BACKLOG);
+	const INPUT: &[u8] = b"Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!";
 	let (key, iv) = crate::cha::keygen();
+	let expected = encrypt_sync(INPUT, &key, &iv);
 
-	let input = "Hello!";
+	let (a, rest) = INPUT.split_at(5);
+	let (b, c) = rest.split_at(30);
+	let mut sink = super::Sink::encrypt(ChoppyWriter::new(7, 2), &key, &iv).expect("Sink::encrypt");
+	assert!(sink.is_write_vectored());
 
-	let enctask = tokio::spawn(async move {
-	    let mut sink = super::Sink::encrypt(&mut client, key, iv).expect("Sink::encrypt");
-	    sink.write_all(input.as_bytes()).await.expect("Sink::write_all");
-	    sink.flush().await.expect("Sink::flush");
-	    sink.shutdown().await.expect("Sink::shutdown");
+	let bufs = [IoSlice::new(a), IoSlice::new(&[]), IoSlice::new(b), IoSlice::new(c)];
+	assert_eq!(sink.write_vectored(&bufs[..]).await.expect("Sink::write_vectored"), INPUT.len());
+	sink.flush().await.expect("Sink::flush");
+	assert_eq!(&sink.into_inner().inner[..], &expected[..]);
+    }
+}
 
-	    drop(client);
+#[cfg(all(test, feature="futures-io"))]
+#[cfg_attr(not(feature="zeroize"), allow(clippy::needless_borrows_for_generic_args))]
+mod test_futures_io
+{
+    use super::fixtures::*;
+    use futures::{
+	executor::block_on,
+	io::{
+	    AsyncRead, AsyncWrite,
+	    AsyncReadExt, AsyncWriteExt,
+	},
+    };
+    use std::{
+	io::{self, IoSlice},
+	pin::Pin,
+	task::{Context, Poll},
+    };
+
+    impl AsyncRead for ChoppyReader<'_>
+    {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+	    self.get_mut().poll_give(cx, buf).map(Ok)
+	}
+    }
+
+    impl AsyncWrite for ChoppyWriter
+    {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+	    self.get_mut().poll_take(cx, buf)
+	}
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+	    Poll::Ready(Ok(()))
+	}
+	fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+	    Poll::Ready(Ok(()))
+	}
+    }
+
+    #[test]
+    fn source_enc_dec()
+    {
+	const INPUT: &[u8] = b"Hello world!";
+	let (key, iv) = crate::cha::keygen();
+
+	block_on(async {
+	    let mut enc: super::Source<_> = super::Source::encrypt(INPUT, &key, &iv).expect("Failed to create encryptor");
+	    let mut enc_out = Vec::new();
+	    enc.read_to_end(&mut enc_out).await.expect("Failed to read encrypted output");
+	    assert_eq!(enc_out, encrypt_sync(INPUT, &key, &iv));
+
+	    let mut dec: super::Source<_> = super::Source::decrypt(&enc_out[..], &key, &iv).expect("Failed to create decryptor");
+	    let mut dec_out = Vec::new();
+	    dec.read_to_end(&mut dec_out).await.expect("Failed to read decrypted output");
+	    assert_eq!(&dec_out[..], INPUT);
 	});
+    }
+
+    #[test]
+    fn sink_short_writes()
+    {
+	const INPUT: &[u8] = b"Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!";
+	let (key, iv) = crate::cha::keygen();
+	let expected = encrypt_sync(INPUT, &key, &iv);
 
-	let (mut declient, mut deserver) = tokio::io::duplex(BACKLOG * 2);
-	let dectask = tokio::spawn(async move {
-	    
-	    let mut sink = super::Sink::decrypt(&mut declient, key, iv).expect("Sink::encrypt");
-	    tokio::io::copy(&mut server, &mut sink).await.expect("Copy to sink failed");
+	block_on(async {
+	    for &(max, pending_every) in &[(1, 2), (3, 3), (5, 2), (100, 2)] {
+		let mut sink = super::Sink::encrypt(ChoppyWriter::new(max, pending_every), &key, &iv).expect("Sink::encrypt");
+		for chunk in INPUT.chunks(7) {
+		    sink.write_all(chunk).await.expect("Sink::write_all");
+		}
+		sink.close().await.expect("Sink::close");
+		assert_eq!(&sink.into_inner().inner[..], &expected[..], "max: {}, pending_every: {}", max, pending_every);
+	    }
+
+	    let (a, rest) = INPUT.split_at(5);
+	    let (b, c) = rest.split_at(30);
+	    let mut sink = super::Sink::encrypt(ChoppyWriter::new(7, 2), &key, &iv).expect("Sink::encrypt");
+	    let bufs = [IoSlice::new(a), IoSlice::new(&[]), IoSlice::new(b), IoSlice::new(c)];
+	    assert_eq!(sink.write_vectored(&bufs[..]).await.expect("Sink::write_vectored"), INPUT.len());
 	    sink.flush().await.expect("Sink::flush");
-	    sink.shutdown().await.expect("Sink::shutdown");
+	    assert_eq!(&sink.into_inner().inner[..], &expected[..]);
 	});
+    }
+
+    #[test]
+    fn source_choppy_reads()
+    {
+	use super::source::{UseBufferInternal, UseBufferExternal};
+	let input: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+	let (key, iv) = crate::cha::keygen();
+	let expected = encrypt_sync(&input[..], &key, &iv);
+
+	block_on(async {
+	    for &(max, pending_every) in &[(1, 2), (13, 3), (10000, 2)] {
+		let choppy = |inner| ChoppyReader::new(inner, max, pending_every);
+
+		let mut output = Vec::new();
+		let mut source = super::Source::<_, UseBufferInternal>::encrypt(choppy(&input[..]), &key, &iv).expect("Source::encrypt");
+		source.read_to_end(&mut output).await.expect("internal encrypt");
+		assert_eq!(output, expected, "max: {}, pending_every: {}", max, pending_every);
+
+		output.clear();
+		let mut source = super::Source::<_, UseBufferExternal>::decrypt(choppy(&expected[..]), &key, &iv).expect("Source::decrypt");
+		source.read_to_end(&mut output).await.expect("external decrypt");
+		assert_eq!(output, input, "max: {}, pending_every: {}", max, pending_every);
+	    }
 
-	let (de, en) = tokio::join![dectask, enctask];
-	
-	de.expect("Dec task panic");
-	en.expect("Enc task panic");
-	
-	let mut output = Vec::new();
-	tokio::io::copy(&mut deserver, &mut output).await.expect("Copy into vec");
+	    let mut encrypted = Vec::new();
+	    let mut tagged: super::Source<_> = super::Source::encrypt_tagged(&input[..], &key, &iv).expect("Source::encrypt_tagged");
+	    tagged.read_to_end(&mut encrypted).await.expect("read_to_end");
 
-	println!("In: {}, Out: {}", String::from_utf8_lossy(&output[..]), input);
-	assert_eq!(&output[..], input.as_bytes());
+	    let mut output = Vec::new();
+	    let mut source: super::Source<_> = super::Source::decrypt_tagged(ChoppyReader::new(&encrypted[..], 17, 3), &key, &iv).expect("Source::decrypt_tagged");
+	    source.read_to_end(&mut output).await.expect("verify");
+	    assert_eq!(output, input);
+
+	    encrypted[0] ^= 1;
+	    let mut source: super::Source<_> = super::Source::decrypt_tagged(&encrypted[..], &key, &iv).expect("Source::decrypt_tagged");
+	    let err = source.read_to_end(&mut Vec::new()).await.expect_err("tampered data was verified");
+	    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	});
     }
-    #[tokio::test]
-    async fn sink_files()
+
+    /// Reads from `input`, and writes to `output`.
+    struct Loopback<'a>
     {
-	let mut output = tokio::fs::File::from_std(tempfile::tempfile().unwrap());
-	
-	let input = "Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!Hello world!";
-	let (key, iv) = crate::cha::keygen();
-	
-	{
-	    let mut sink = super::Sink::encrypt(&mut output, key, iv).expect("Sink::encrypt");
-	    sink.write_all(input.as_bytes()).await.expect("Sink::write_all");
-	    sink.flush().await.expect("Sink::flush");
-	    sink.shutdown().await.expect("Sink::shutdown");
+	input: ChoppyReader<'a>,
+	output: ChoppyWriter,
+    }
+
+    impl AsyncRead for Loopback<'_>
+    {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+	    Pin::new(&mut self.input).poll_read(cx, buf)
 	}
+    }
 
-	let mut encrypted = output;
-	encrypted.seek(tokio::io::SeekFrom::Start(0)).await.unwrap();
-	
-	let mut output = tokio::fs::File::from_std(tempfile::tempfile().unwrap());
-	{
-	    let mut sink = super::Sink::decrypt(&mut output, key, iv).expect("Sink::decrypt");
-	    tokio::io::copy(&mut encrypted, &mut sink).await.expect("Copy to sinl");
-	    
-	    sink.flush().await.expect("Sink::flush");
-	    sink.shutdown().await.expect("Sink::shutdown");
+    impl AsyncWrite for Loopback<'_>
+    {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+	    Pin::new(&mut self.output).poll_write(cx, buf)
+	}
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+	    Pin::new(&mut self.output).poll_flush(cx)
 	}
-	let mut decrypted = output;
+	fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+	    Pin::new(&mut self.output).poll_close(cx)
+	}
+    }
+
+    #[test]
+    fn duplex()
+    {
+	const INPUT: &[u8] = b"Hello world!Hello world!Hello world!Hello world!";
+	let (ours, theirs) = (crate::cha::keygen(), crate::cha::keygen());
+	let incoming = encrypt_sync(INPUT, &theirs.0, &theirs.1);
+	let loopback = || Loopback {
+	    input: ChoppyReader::new(&incoming[..], 5, 3),
+	    output: ChoppyWriter::new(5, 3),
+	};
 
-	let (r1, r2) = tokio::join![encrypted.sync_data(),
-				    decrypted.sync_data()];
-	r1.expect("enc sync");
-	r2.expect("dec sync");
+	block_on(async {
+	    let mut stream = super::Stream::new(loopback(), &ours.0, &ours.1, &theirs.0, &theirs.1).expect("Stream::new");
+	    let mut output = Vec::new();
+	    stream.read_to_end(&mut output).await.expect("Stream::read_to_end");
+	    assert_eq!(&output[..], INPUT);
 
-	let decrypted = {
-	    decrypted.seek(tokio::io::SeekFrom::Start(0)).await.unwrap();
+	    stream.write_all(INPUT).await.expect("Stream::write_all");
+	    stream.close().await.expect("Stream::close");
+	    assert_eq!(stream.into_inner().output.inner, encrypt_sync(INPUT, &ours.0, &ours.1));
 
-	    let mut output = vec![0u8; input.len()];
-	    decrypted.read_exact(&mut output[..]).await.expect("Read decrypted");
+	    // Splitting carries on with the same keystreams
+	    let mut stream = super::Stream::new(loopback(), &ours.0, &ours.1, &theirs.0, &theirs.1).expect("Stream::new");
+	    let mut output = vec![0u8; 10];
+	    stream.read_exact(&mut output[..]).await.expect("Stream::read_exact");
+	    stream.write_all(&INPUT[..10]).await.expect("Stream::write_all");
 
-	    output
-	};
-	
-	assert_eq!(&decrypted[..], input.as_bytes());
+	    let (mut source, mut sink) = stream.split_with(|stream| stream.split());
+	    source.read_to_end(&mut output).await.expect("Source::read_to_end");
+	    assert_eq!(&output[..], INPUT);
+	    sink.write_all(&INPUT[10..]).await.expect("Sink::write_all");
+	    sink.close().await.expect("Sink::close");
+
+	    let loopback = source.into_inner().reunite(sink.into_inner()).expect("reunite");
+	    assert_eq!(loopback.output.inner, encrypt_sync(INPUT, &ours.0, &ours.1));
+	});
     }
 }
-
@@ -1,5 +1,9 @@
 //! Asyncronous `AsyncWrite` wrapper.
 use super::*;
+use std::{
+    ops::Range,
+    io::IoSlice,
+};
 
 /// Async ChaCha Sink
 ///
@@ -7,7 +11,9 @@ use super::*;
 /// To create an encrypting wrapper stream:
 /// ```
 /// # use chacha20stream::AsyncSink;
-/// # use tokio::prelude::*;
+/// # #[cfg(feature="async")] use tokio::prelude::*;
+/// # #[cfg(all(feature="tokio1", not(feature="async")))] use tokio1::io::AsyncWriteExt;
+/// # #[cfg(not(any(feature="async", feature="tokio1")))] use futures::io::AsyncWriteExt;
 /// # let (key, iv) = chacha20stream::keygen();
 /// # let mut backing_stream = Vec::new();
 /// # async move {
@@ -23,7 +29,9 @@ use super::*;
 /// To create a decrypting wrapper stream:
 /// ```
 /// # use chacha20stream::AsyncSink;
-/// # use tokio::prelude::*;
+/// # #[cfg(feature="async")] use tokio::prelude::*;
+/// # #[cfg(all(feature="tokio1", not(feature="async")))] use tokio1::io::AsyncWriteExt;
+/// # #[cfg(not(any(feature="async", feature="tokio1")))] use futures::io::AsyncWriteExt;
 /// # let (key, iv) = chacha20stream::keygen();
 /// # let mut backing_stream = Vec::new();
 /// # async move {
@@ -35,6 +43,9 @@ use super::*;
 /// # };
 /// ```
 ///
+/// # Partial writes
+/// Like a buffered writer, `poll_write()` en/decrypts up to `MAX_PENDING` bytes of its input into the internal buffer and reports them as accepted. That pending data is written to the backing stream by the next `poll_write()`, `poll_flush()`, or `poll_shutdown()`, before anything else.
+/// `poll_write()` returns the backing stream's `Poll::Pending` or error without accepting any input while pending data cannot be written, so dropping a `write_all()` future part-way through never loses or duplicates data: only the input reported as accepted has been transformed.
 ///
 /// # Note
 /// When writing, a temporary buffer stored in the structure is used. This buffer is **not** cleared after a write, for efficiency reasons. This may leave sensitive information in the buffer after the write operation.
@@ -46,9 +57,11 @@ pub struct Sink<W>
 {
     #[pin] stream: W,
     
-    crypter: Crypter, // for chacha, finalize does nothing it seems. we can also call it multiple times.
+    crypter: Crypter, // `finalize` must only be called once, at the end of the stream: it computes the Poly1305 tag and resets the keystream.
+    finished: bool, // `crypter` has been finalised
 
     buffer: BufferVec, // used to buffer the operation
+    pending: Range<usize>, // transformed bytes in `buffer` not yet written to `stream`
 }
 
 impl<W: fmt::Debug> fmt::Debug for Sink<W>
@@ -59,51 +72,92 @@ impl<W: fmt::Debug> fmt::Debug for Sink<W>
     }
 }
 
-/// Perform the cipher transform on this input to the inner buffer, returning the number of bytes updated.
-fn transform(crypter: &mut Crypter, buffer: &mut BufferVec, buf: &[u8]) -> Result<(), ErrorStack>
+/// The most input transformed by one call to `poll_write()`, and so the most data kept pending.
+pub const MAX_PENDING: usize = 64 * 1024;
+
+/// Write the `pending` bytes of `buffer` to `stream` with `write`.
+fn poll_drain<W, F>(mut stream: Pin<&mut W>, cx: &mut Context<'_>, buffer: &BufferVec, pending: &mut Range<usize>, mut write: F) -> Poll<io::Result<()>>
+where F: FnMut(Pin<&mut W>, &mut Context<'_>, &[u8]) -> Poll<io::Result<usize>>
 {
-    //if buf.len() > self.buffer.len() {
-    buffer.resize(buf.len(), 0);
-    //}
-    
-    let n = crypter.update(&buf[..], &mut buffer[..])?;
-    let _f = crypter.finalize(&mut buffer[..n])?; // I don't know if this is needed.
-    debug_assert_eq!(_f, 0);
+    while pending.start < pending.end {
+	match ready!(write(stream.as_mut(), cx, &buffer[pending.clone()]))? {
+	    0 => return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write pending data to inner stream"))),
+	    n => pending.start += n,
+	}
+    }
+    *pending = 0..0;
+    Poll::Ready(Ok(()))
+}
+
+/// Perform the cipher transform on the first `max` bytes of these input buffers, one after the other, to the inner buffer.
+///
+/// Returns the number of input bytes transformed.
+fn transform(crypter: &mut Crypter, buffer: &mut BufferVec, bufs: &[IoSlice<'_>], max: usize) -> Result<usize, ErrorStack>
+{
+    let len = std::cmp::min(bufs.iter().map(|buf| buf.len()).sum(), max);
+    buffer.resize(len, 0);
+
+    let (mut read, mut n) = (0, 0);
+    for buf in bufs {
+	let take = std::cmp::min(buf.len(), len - read);
+	n += crypter.update(&buf[..take], &mut buffer[n..])?;
+	read += take;
+    }
 
     buffer.resize(n, 0);
-    Ok(())
+    Ok(read)
 }
 
 
-impl<W: AsyncWrite> Sink<W>
+impl<W> Sink<W>
 {
     
     /// Create a new async Chacha Sink stream wrapper
     #[inline] fn new(stream: W, crypter: Crypter) -> Self
     {
-	Self{stream, crypter, buffer: BufferVec::new()}
+	Self{stream, crypter, finished: false, buffer: BufferVec::new(), pending: 0..0}
     }
 
     /// Create an encrypting Chacha Sink stream wrapper
-    pub fn encrypt(stream: W, key: Key, iv: IV) -> Result<Self, Error>
+    pub fn encrypt(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
     {
 	Ok(Self::new(stream, cha::encrypter(key, iv)?))
     }
     
     /// Create a decrypting Chacha Sink stream wrapper
-    pub fn decrypt(stream: W, key: Key, iv: IV) -> Result<Self, Error>
+    pub fn decrypt(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
     {
 	Ok(Self::new(stream, cha::decrypter(key, iv)?))
     }
+
+    /// Create an encrypting Chacha Sink stream wrapper, binding the additional authenticated data `aad` to the ciphertext.
+    ///
+    /// `aad` is not encrypted or written to the stream, but is included in the tag returned by `finish()`.
+    pub fn encrypt_with_aad(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, Error>
+    {
+	Ok(Self::new(stream, cha::encrypter_with_aad(key, iv, aad)?))
+    }
+
+    /// Create a decrypting Chacha Sink stream wrapper, with the additional authenticated data `aad` bound to the ciphertext.
+    ///
+    /// `finish_verify()` will fail if `aad` differs from what the data was encrypted with.
+    pub fn decrypt_with_aad(stream: W, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, Error>
+    {
+	Ok(Self::new(stream, cha::decrypter_with_aad(key, iv, aad)?))
+    }
     
 
     /// Consume into the inner stream
+    ///
+    /// Any pending data not yet written to the inner stream is lost. Flush the sink first to write it.
     #[inline] pub fn into_inner(self) -> W
     {
 	self.stream
     }
 
     /// Consume into the inner stream and crypter
+    ///
+    /// Any pending data not yet written to the inner stream is lost. Flush the sink first to write it.
     #[inline] pub fn into_parts(self) -> (W, Crypter)
     {
 	(self.stream, self.crypter)
@@ -116,6 +170,24 @@ impl<W: AsyncWrite> Sink<W>
     {
 	Self::new(stream, crypter)
     }
+
+    /// Replace the inner stream with `f(stream)`, keeping the crypter and any pending data.
+    pub(super) fn map_inner<T>(self, f: impl FnOnce(W) -> T) -> Sink<T>
+    {
+	Sink {
+	    stream: f(self.stream),
+	    crypter: self.crypter,
+	    finished: self.finished,
+	    buffer: self.buffer,
+	    pending: self.pending,
+	}
+    }
+
+    /// The pinned inner stream
+    #[inline] pub(super) fn inner_pinned(self: Pin<&mut Self>) -> Pin<&mut W>
+    {
+	self.project().stream
+    }
     
     /// The crypter of this instance
     #[inline] pub fn crypter(&self) -> &Crypter
@@ -141,44 +213,96 @@ impl<W: AsyncWrite> Sink<W>
 	&mut self.stream
     }
     
+    /// Has this sink been finished?
+    #[inline] pub fn is_finished(&self) -> bool
+    {
+	self.finished
+    }
+
+    /// Finalise an encrypting sink, returning the Poly1305 authentication tag of all data written to it.
+    ///
+    /// The sink must be flushed before it is finished.
+    ///
+    /// # Errors
+    /// If there is pending data not yet written to the inner stream, `AuthError::Pending` is returned and the sink is not finished.
+    /// Finalising resets the cipher's state, so after the sink is finished, this, `finish_verify()`, and any further writes return `AuthError::Finished`.
+    pub fn finish(&mut self) -> Result<Tag, AuthError>
+    {
+	if !self.pending.is_empty() {
+	    return Err(AuthError::Pending);
+	}
+	if std::mem::replace(&mut self.finished, true) {
+	    return Err(AuthError::Finished);
+	}
+	let mut tag = Tag::default();
+	self.crypter.finalize(&mut [])?;
+	self.crypter.get_tag(tag.as_mut())?;
+	Ok(tag)
+    }
+
+    /// Finalise a decrypting sink, verifying the data written to it against the Poly1305 authentication tag `tag`.
+    ///
+    /// The sink must be flushed before it is finished.
+    ///
+    /// # Errors
+    /// If the data written was not authenticated by `tag`, `AuthError::Mismatch` is returned.
+    /// If there is pending data not yet written to the inner stream, `AuthError::Pending` is returned and the sink is not finished.
+    /// If the sink has already been finished, `AuthError::Finished` is returned.
+    pub fn finish_verify(&mut self, tag: &Tag) -> Result<(), AuthError>
+    {
+	if !self.pending.is_empty() {
+	    return Err(AuthError::Pending);
+	}
+	if std::mem::replace(&mut self.finished, true) {
+	    return Err(AuthError::Finished);
+	}
+	self.crypter.set_tag(tag.as_ref())?;
+	self.crypter.finalize(&mut []).map_err(|_| AuthError::Mismatch)?;
+	Ok(())
+    }
+
     /// Clear the internal buffer while keeping it allocated for further use.
     ///
     /// This does not affect operations at all, all it does is 0 out the left-over temporary buffer from the last operation(s).
+    /// Pending data that has not been written to the inner stream yet is kept.
     #[inline] 
     pub fn prune(&mut self)
     {
-	#[cfg(feature="explicit_clear")]
-	{
-	    bytes::explicit_prune(&mut self.buffer[..]);
-	    return;
-	}
-	#[cfg(not(feature="explicit_clear"))] 
-	unsafe {
-	    std::ptr::write_bytes(self.buffer.as_mut_ptr(), 0, self.buffer.len());
-	}
+	let Range { start, end } = self.pending;
+	let (head, tail) = self.buffer.split_at_mut(end);
+	bytes::prune(&mut head[..start]);
+	bytes::prune(tail);
     }
 }
 
-//When implementing `poll`, we check if buffer is empty on poll, and if it isn't, poll backing stream to write it. Then, clear buffer after `Poll::Ready` on backing stream's write.
-impl<W: AsyncWrite> AsyncWrite for Sink<W>
+impl<W> Sink<W>
 {
-    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+    /// Write any pending data to the inner stream with `write`, then transform up to `MAX_PENDING` bytes of `bufs` into pending data.
+    ///
+    /// Input is only transformed once there is no pending data left, and all of what is transformed is reported as accepted, so a `Poll::Pending` or error never leaves it half-consumed.
+    pub(super) fn poll_write_with<F>(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>], write: F) -> Poll<io::Result<usize>>
+    where F: FnMut(Pin<&mut W>, &mut Context<'_>, &[u8]) -> Poll<io::Result<usize>>
+    {
 	let this = self.project();
-	if this.buffer.is_empty() {
-	    transform(this.crypter, this.buffer, buf)?;
+	if *this.finished {
+	    return Poll::Ready(Err(AuthError::Finished.into()));
 	}
-	let poll = this.stream.poll_write(cx, &this.buffer[..]);
-	if poll.is_ready() {
-	    #[cfg(feature="explicit_clear")]
-	    bytes::explicit_prune(&mut this.buffer[..]);
-	    this.buffer.clear();
-	} 
-	poll
+	ready!(poll_drain(this.stream, cx, this.buffer, this.pending, write))?;
+
+	let read = transform(this.crypter, this.buffer, bufs, MAX_PENDING)?;
+	*this.pending = 0..this.buffer.len();
+	Poll::Ready(Ok(read))
     }
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-	let this = self.project();
 
-	let poll = this.stream.poll_flush(cx);
+    /// Write any pending data to the inner stream with `write`, then flush or shut it down with `then`, clearing the buffer once it is done.
+    pub(super) fn poll_flush_with<F, T>(self: Pin<&mut Self>, cx: &mut Context<'_>, write: F, then: T) -> Poll<io::Result<()>>
+    where F: FnMut(Pin<&mut W>, &mut Context<'_>, &[u8]) -> Poll<io::Result<usize>>,
+	  T: FnOnce(Pin<&mut W>, &mut Context<'_>) -> Poll<io::Result<()>>
+    {
+	let mut this = self.project();
+	ready!(poll_drain(this.stream.as_mut(), cx, this.buffer, this.pending, write))?;
+
+	let poll = then(this.stream, cx);
 	if poll.is_ready() {
 	    #[cfg(feature="explicit_clear")]
 	    bytes::explicit_prune(&mut this.buffer[..]);
@@ -186,15 +310,57 @@ impl<W: AsyncWrite> AsyncWrite for Sink<W>
 	}
 	poll
     }
+}
+
+#[cfg(feature="async")]
+impl<W: tokio::io::AsyncWrite> tokio::io::AsyncWrite for Sink<W>
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+	self.poll_write_with(cx, &[IoSlice::new(buf)], W::poll_write)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.poll_flush_with(cx, W::poll_write, W::poll_flush)
+    }
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-	let this = self.project();
+	self.poll_flush_with(cx, W::poll_write, W::poll_shutdown)
+    }
+}
 
-	let poll = this.stream.poll_shutdown(cx);
-	if poll.is_ready() {
-	    #[cfg(feature="explicit_clear")]
-	    bytes::explicit_prune(&mut this.buffer[..]);
-	    this.buffer.clear();
-	}
-	poll
+#[cfg(feature="tokio1")]
+impl<W: tokio1::io::AsyncWrite> tokio1::io::AsyncWrite for Sink<W>
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+	self.poll_write_with(cx, &[IoSlice::new(buf)], W::poll_write)
+    }
+    /// Up to `MAX_PENDING` bytes of `bufs` are transformed into one buffer of ciphertext, which is written to the inner stream as with `poll_write()`.
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<Result<usize, io::Error>> {
+	self.poll_write_with(cx, bufs, W::poll_write)
+    }
+    #[inline] fn is_write_vectored(&self) -> bool {
+	true
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.poll_flush_with(cx, W::poll_write, W::poll_flush)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.poll_flush_with(cx, W::poll_write, W::poll_shutdown)
+    }
+}
+
+#[cfg(feature="futures-io")]
+impl<W: futures_io::AsyncWrite> futures_io::AsyncWrite for Sink<W>
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+	self.poll_write_with(cx, &[IoSlice::new(buf)], W::poll_write)
+    }
+    /// Up to `MAX_PENDING` bytes of `bufs` are transformed into one buffer of ciphertext, which is written to the inner stream as with `poll_write()`.
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<Result<usize, io::Error>> {
+	self.poll_write_with(cx, bufs, W::poll_write)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.poll_flush_with(cx, W::poll_write, W::poll_flush)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+	self.poll_flush_with(cx, W::poll_write, W::poll_close)
     }
 }
@@ -1,65 +1,120 @@
 //! Asyncronous `AsyncRead` wrapper.
 use super::*;
-use tokio::io::AsyncRead;
+use crate::stream::source::TagMode;
+use crate::stream::pool::BufferPool;
+use std::sync::Arc;
+#[cfg(feature="tokio1")]
+use tokio1::io::ReadBuf;
+pub use crate::stream::source::{
+    BufferKind,
+    UseBufferInternal,
+    UseBufferExternal,
+    UseBufferPool,
+    DefaultBuffer,
+};
 
 /// Asyncronous ChaCha source.
 /// En/decrypts information from the source async reader.
 ///
 /// This is the `Read` implementing counterpart to `AsyncSink`.
+///
+/// # Authentication
+/// As with the syncronous `Source`, a source created with `encrypt_tagged()` appends the Poly1305 tag to its output, and one created with `decrypt_tagged()` verifies the last `TAG_SIZE` bytes of the inner stream as the tag once it reaches EOF.
+///
+/// # Buffering
+/// The `BufferKind` is chosen the same way as for the syncronous `Source`.
+/// Raw bytes are only read from the inner stream when it is ready, so nothing is kept in the buffer between polls, and each `poll_read()` may be passed a buffer of any size.
 //#[derive(Debug)]
 #[pin_project]
-pub struct Source<R>
+pub struct Source<R, Buffer: BufferKind = DefaultBuffer>
 {
     #[pin] stream: R,
+
+    tag: TagMode,
     
-    crypter: Crypter, // for chacha, finalize does nothing it seems. we can also call it multiple times.
+    crypter: Crypter, // `finalize` must only be called once, at the end of the stream: it computes the Poly1305 tag and resets the keystream.
 
-    buffer: BufferVec, // used to buffer the operation (ad-hoc-buffer wouldn't work for async operations as the buffer may need to be saved over yields.)
+    buffer: Buffer::InternalBuffer, // Raw bytes read from `stream` for the current `poll_read()`. Nothing is kept here over a yield, so with `UseBufferExternal` or `UseBufferPool` a stack or pooled buffer is used instead.
 }
 
-impl<R: fmt::Debug> fmt::Debug for Source<R>
+impl<R: fmt::Debug, K: BufferKind> fmt::Debug for Source<R, K>
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
     {
-	write!(f, "Source({:?}, ({} buffer cap))", self.stream, self.buffer.capacity())
+	use std::any::type_name;
+	write!(f, "Source<Wraps: {}, BufferKind: {}>", type_name::<R>(), type_name::<K>())?;
+	match K::buffer_cap(&self.buffer) {
+	    0 => write!(f, "({:?}, (unbounded buffer cap))", &self.stream),
+	    cap => write!(f, "({:?}, ({} buffer cap))", &self.stream, cap), 
+	}
     }
 }
 
-/// Perform the cipher transform on the inner buffer, writing to the output buffer, returning the number of bytes updated.
-fn transform(crypter: &mut Crypter, buf: &[u8], buffer: &mut [u8]) -> Result<usize, ErrorStack>
+/// Perform the cipher transform on the inner buffer, writing to the output buffer, returning the number of bytes output.
+#[inline] fn transform(crypter: &mut Crypter, tag: &mut TagMode, buf: &[u8], buffer: &mut [u8]) -> io::Result<usize>
 {
-    //if buf.len() > self.buffer.len() {
-    //buf.resize(buffer.len(), 0);
-    //}
-    
-    let n = crypter.update(&buf[..], &mut buffer[..])?;
-    let _f = crypter.finalize(&mut buffer[..n])?; // I don't know if this is needed.
-    debug_assert_eq!(_f, 0);
-    
-    Ok(n)
+    tag.transform(crypter, buf, buffer)
 }
 
 
-impl<R: AsyncRead> Source<R>
+impl<R, K: BufferKind> Source<R, K>
 {
     
     /// Create a new async Chacha Source stream wrapper
     #[inline] fn new(stream: R, crypter: Crypter) -> Self
     {
-	Self{stream, crypter, buffer: BufferVec::new()}
+	Self{stream, crypter, buffer: K::create_buffer(0), tag: TagMode::Ignore}
     }
 
     /// Create an encrypting Chacha Source stream wrapper
-    pub fn encrypt(stream: R, key: Key, iv: IV) -> Result<Self, Error>
+    pub fn encrypt(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
     {
 	Ok(Self::new(stream, cha::encrypter(key, iv)?))
     }
     
     /// Create a decrypting Chacha Source stream wrapper
-    pub fn decrypt(stream: R, key: Key, iv: IV) -> Result<Self, Error>
+    pub fn decrypt(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
     {
 	Ok(Self::new(stream, cha::decrypter(key, iv)?))
     }
+
+    /// Create an encrypting Chacha Source stream wrapper that appends the Poly1305 tag to its output when the inner stream reaches EOF
+    pub fn encrypt_tagged(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
+    {
+	let mut this = Self::encrypt(stream, key, iv)?;
+	this.tag = TagMode::append();
+	Ok(this)
+    }
+
+    /// Create a decrypting Chacha Source stream wrapper that verifies the Poly1305 tag at the end of the inner stream
+    ///
+    /// The last `TAG_SIZE` bytes of the inner stream are not decrypted, but checked as the tag when EOF is reached.
+    pub fn decrypt_tagged(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>) -> Result<Self, Error>
+    {
+	let mut this = Self::decrypt(stream, key, iv)?;
+	this.tag = TagMode::verify();
+	Ok(this)
+    }
+
+    /// Create an encrypting Chacha Source stream wrapper, binding the additional authenticated data `aad` to the ciphertext.
+    ///
+    /// `aad` is not encrypted or output, but is included in the Poly1305 tag, which is appended to the output as with `encrypt_tagged()`.
+    pub fn encrypt_with_aad(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, Error>
+    {
+	let mut this = Self::new(stream, cha::encrypter_with_aad(key, iv, aad)?);
+	this.tag = TagMode::append();
+	Ok(this)
+    }
+
+    /// Create a decrypting Chacha Source stream wrapper, with the additional authenticated data `aad` bound to the ciphertext.
+    ///
+    /// The Poly1305 tag at the end of the inner stream is verified as with `decrypt_tagged()`, which fails if `aad` differs from what the data was encrypted with.
+    pub fn decrypt_with_aad(stream: R, key: impl AsRef<Key>, iv: impl AsRef<IV>, aad: impl AsRef<[u8]>) -> Result<Self, Error>
+    {
+	let mut this = Self::new(stream, cha::decrypter_with_aad(key, iv, aad)?);
+	this.tag = TagMode::verify();
+	Ok(this)
+    }
     
 
     /// Consume into the inner stream
@@ -69,6 +124,8 @@ impl<R: AsyncRead> Source<R>
     }
 
     /// Consume into the inner stream and crypter
+    ///
+    /// Any tag state from `encrypt_tagged()` or `decrypt_tagged()` is lost.
     #[inline] pub fn into_parts(self) -> (R, Crypter)
     {
 	(self.stream, self.crypter)
@@ -110,49 +167,128 @@ impl<R: AsyncRead> Source<R>
     /// Clear the internal buffer while keeping it allocated for further use.
     ///
     /// This does not affect operations at all, all it does is 0 out the left-over temporary buffer from the last operation(s).
+    /// With `UseBufferExternal` or `UseBufferPool` there is no internal buffer, and this does nothing.
     #[inline] 
     pub fn prune(&mut self)
     {
-	#[cfg(feature="explicit_clear")]
-	{
-	    bytes::explicit_prune(&mut self.buffer[..]);
+	if K::PHANTOM {
 	    return;
 	}
-	#[cfg(not(feature="explicit_clear"))] 
-	unsafe {
-	    std::ptr::write_bytes(self.buffer.as_mut_ptr(), 0, self.buffer.len());
+	bytes::prune(K::buffer_bytes_mut(&mut self.buffer));
+    }
+}
+
+impl<R> Source<R, UseBufferExternal>
+{
+    /// Convert this instance to use internal buffer (instead of external.)
+    pub fn with_internal_buffer(self) -> Source<R, UseBufferInternal>
+    {
+	Source {
+	    buffer: UseBufferInternal::create_buffer(UseBufferExternal::buffer_cap(&self.buffer)),
+	    crypter: self.crypter,
+	    stream: self.stream,
+	    tag: self.tag,
 	}
     }
 }
 
-//When implementing `poll`, we check if buffer is empty on poll, and if it isn't, poll backing stream to write it. Then, clear buffer after `Poll::Ready` on backing stream's write.
-impl<R: AsyncRead> AsyncRead for Source<R>
+impl<R> Source<R, UseBufferInternal>
 {
-    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
-	let this = self.project();
+    /// Convert this instance to use external buffer (instead of internal.)
+    pub fn with_reused_buffer(self) -> Source<R, UseBufferExternal>
+    {
+	Source {
+	    buffer: UseBufferExternal::create_buffer(UseBufferInternal::buffer_cap(&self.buffer)),
+	    crypter: self.crypter,
+	    stream: self.stream,
+	    tag: self.tag,
+	}
+    }
+}
+
+impl<R> Source<R, UseBufferPool>
+{
+    /// Borrow buffers from `pool` instead of the one currently used.
+    #[inline] pub fn with_pool(mut self, pool: Arc<BufferPool>) -> Self
+    {
+	self.buffer = pool;
+	self
+    }
 
-	if this.buffer.is_empty() {
-	    this.buffer.resize(buf.len(), 0);
+    /// The pool buffers are borrowed from
+    #[inline] pub fn pool(&self) -> &Arc<BufferPool>
+    {
+	&self.buffer
+    }
+}
+
+impl<R, K: BufferKind> Source<R, K>
+{
+    /// Read raw bytes from the inner stream with `read`, transforming them into `buf`.
+    ///
+    /// Raw bytes are only taken from the inner stream when it is ready, so on `Pending` there is nothing to keep, and the next poll can start over with a different `buf`.
+    pub(super) fn poll_read_with<F>(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8], mut read: F) -> Poll<io::Result<usize>>
+    where F: FnMut(Pin<&mut R>, &mut Context<'_>, &mut [u8]) -> Poll<io::Result<usize>>
+    {
+	let mut this = self.project();
+	if buf.is_empty() {
+	    return Poll::Ready(Ok(0));
 	}
-	debug_assert_eq!(buf.len(), this.buffer.len());
-	
-	let poll = this.stream.poll_read(cx, &mut this.buffer[..]);
-	match poll {
-	    Poll::Ready(Ok(read)) => {
-		// Data read, perform transform.
-		
-		let n = transform(this.crypter, &this.buffer[..read], &mut buf[..read])?;
-		debug_assert_eq!(n, read);
-		
-		// Reset buffer size to 0, so we know the next call will be on a new buffer, and we can resize it to the correct size again
-		if cfg!(feature="explicit_clear") {
-		    bytes::explicit_prune(&mut this.buffer[..]);
-		} // XXX: Should we blank the buffer here? Or is just a `.clear()` alright?
-		this.buffer.clear();
-		Poll::Ready(Ok(n))
-	    },
-	    other => other
+
+	loop {
+	    let (raw, n) = if K::PHANTOM {
+		let (stream, crypter, tag) = (&mut this.stream, &mut *this.crypter, &mut *this.tag);
+		ready!(K::with_scratch(&*this.buffer, buf.len(), |temp| -> Poll<io::Result<(usize, usize)>> {
+		    let raw = ready!(read(stream.as_mut(), cx, temp))?;
+		    let n = transform(crypter, tag, &temp[..raw], buf)?;
+		    #[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut temp[..raw]);
+		    Poll::Ready(Ok((raw, n)))
+		}))?
+	    } else {
+		if buf.len() > K::buffer_len(this.buffer) {
+		    K::buffer_resize(this.buffer, buf.len());
+		}
+		let temp = &mut K::buffer_bytes_mut(this.buffer)[..buf.len()];
+		let raw = ready!(read(this.stream.as_mut(), cx, temp))?;
+		let n = transform(this.crypter, this.tag, &temp[..raw], buf)?;
+		#[cfg(feature="explicit_clear")] bytes::explicit_prune(&mut temp[..raw]);
+		(raw, n)
+	    };
+
+	    // When verifying a tag, bytes read may all be held back. Keep reading until there is output or EOF.
+	    if n > 0 || raw == 0 {
+		return Poll::Ready(Ok(n));
+	    }
 	}
     }
 }
 
+#[cfg(feature="async")]
+impl<R: tokio::io::AsyncRead, K: BufferKind> tokio::io::AsyncRead for Source<R, K>
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+	self.poll_read_with(cx, buf, R::poll_read)
+    }
+}
+
+#[cfg(feature="tokio1")]
+impl<R: tokio1::io::AsyncRead, K: BufferKind> tokio1::io::AsyncRead for Source<R, K>
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+	let n = ready!(self.poll_read_with(cx, buf.initialize_unfilled(), |stream, cx, temp| {
+	    let mut temp = ReadBuf::new(temp);
+	    ready!(stream.poll_read(cx, &mut temp))?;
+	    Poll::Ready(Ok(temp.filled().len()))
+	}))?;
+	buf.advance(n);
+	Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature="futures-io")]
+impl<R: futures_io::AsyncRead, K: BufferKind> futures_io::AsyncRead for Source<R, K>
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+	self.poll_read_with(cx, buf, R::poll_read)
+    }
+}